@@ -22,11 +22,6 @@ impl AABB {
         AABB { min, max }
     }
 
-    /* This should only be used as a initial value for union */
-    pub fn zero() -> AABB {
-        AABB { min: Vec3::zero(), max: Vec3::zero() }
-    }
-
     pub fn hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
         let invd = ray.dir.recip();
         let mut t0 = (self.min - ray.orig) * invd;
@@ -40,31 +35,28 @@ impl AABB {
                 std::mem::swap(&mut t0[a], &mut t1[a]);
             }
             t0_min = if t0[a] > t0_min { t0[a] } else { t0_min };
-            t1_max = if t1[a] > t1_max { t1[a] } else { t1_max };
+            t1_max = if t1[a] < t1_max { t1[a] } else { t1_max };
             if t1_max <= t0_min {
                 return false;
             }
         }
-        return true;
+        true
+    }
+
+    /* Smallest box containing both */
+    pub fn merge(&self, other: &AABB) -> AABB {
+        let min = Vec3::new(self.min.x.min(other.min.x),
+                            self.min.y.min(other.min.y),
+                            self.min.z.min(other.min.z));
+        let max = Vec3::new(self.max.x.max(other.max.x),
+                            self.max.y.max(other.max.y),
+                            self.max.z.max(other.max.z));
+        AABB::new(min, max)
     }
 
     pub fn union(hittables: &[& dyn Hittable]) -> Option<AABB> {
-        fn do_union(a: AABB, b: AABB) -> AABB {
-            let min = Vec3::new(a.min.x.min(b.min.x),
-                                a.min.y.min(b.min.y),
-                                a.min.z.min(b.min.z));
-            let max = Vec3::new(a.max.x.max(b.max.x),
-                                a.max.y.max(b.max.y),
-                                a.max.z.max(b.max.z));
-            AABB::new(min, max)
-        };
-        let items = hittables.into_iter();
-        let mut res = items.filter_map(|hittable| hittable.get_aabb()).peekable();
-        if res.peek().is_none() {
-            return None;
-        }
-        let aabb = res.fold(AABB::zero(), do_union);
-        return Some(aabb);
+        let items = hittables.iter();
+        items.filter_map(|hittable| hittable.get_aabb()).reduce(|a, b| a.merge(&b))
     }
 
     pub fn get_longest_axis(&self) -> Axis {
@@ -102,18 +94,20 @@ mod tests {
         use crate::Sphere;
         use crate::material::Lambertian;
         use crate::Vec3;
-        use crate::hittable::Hittable;
         use crate::aabb::AABB;
 
         let mat = Lambertian::new(Vec3::new(0.2, 0.3, 0.7));
 
         let sp0 = Sphere::new(Vec3::new(0.0, 0.0, 0.0), 1.0, &mat);
         let sp1 = Sphere::new(Vec3::new(1.0, 0.0, 0.0), 1.0, &mat);
-        let aabb0 = sp0.get_aabb().unwrap();
-        let aabb1 = sp1.get_aabb().unwrap();
-        let aabb = AABB::union(&aabb0, &aabb1);
+        let aabb = AABB::union(&[&sp0, &sp1]).unwrap();
 
         assert_eq!(aabb.max, Vec3::new(2.0, 1.0, 1.0));
         assert_eq!(aabb.min, Vec3::new(-1.0, -1.0, -1.0));
+
+        /* Boxes away from the origin don't grow to reach it */
+        let sp2 = Sphere::new(Vec3::new(5.0, 5.0, 5.0), 1.0, &mat);
+        let aabb = AABB::union(&[&sp2]).unwrap();
+        assert_eq!(aabb.min, Vec3::new(4.0, 4.0, 4.0));
     }
 }
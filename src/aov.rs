@@ -0,0 +1,234 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::checkpoint::{CheckpointError, Decoder, Encoder};
+use crate::film::PixelBounds;
+
+/* Arbitrary output variable, an auxiliary image rendered along with the radiance */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AovKind {
+    /* Reflectance at the first hit */
+    Albedo,
+    /* Shading normal at the first hit, facing the camera */
+    Normal,
+    /* Distance from the camera to the first hit */
+    Depth,
+    /* World space position of the first hit */
+    Position,
+    MaterialId,
+    ObjectId,
+    /* Light reaching the camera after at most one scattering event, and the rest of it */
+    Direct,
+    Indirect,
+}
+
+impl FromStr for AovKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "albedo" => Ok(AovKind::Albedo),
+            "normal" => Ok(AovKind::Normal),
+            "depth" => Ok(AovKind::Depth),
+            "position" => Ok(AovKind::Position),
+            "material" => Ok(AovKind::MaterialId),
+            "object" => Ok(AovKind::ObjectId),
+            "direct" => Ok(AovKind::Direct),
+            "indirect" => Ok(AovKind::Indirect),
+            _ => Err(format!("Unknown AOV: {}", s)),
+        }
+    }
+}
+
+impl AovKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AovKind::Albedo => "albedo",
+            AovKind::Normal => "normal",
+            AovKind::Depth => "depth",
+            AovKind::Position => "position",
+            AovKind::MaterialId => "material",
+            AovKind::ObjectId => "object",
+            AovKind::Direct => "direct",
+            AovKind::Indirect => "indirect",
+        }
+    }
+
+    /* Names of the channels within the layer of a multi-channel image */
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            AovKind::Albedo | AovKind::Direct | AovKind::Indirect => &["R", "G", "B"],
+            AovKind::Normal | AovKind::Position => &["X", "Y", "Z"],
+            AovKind::Depth => &["Z"],
+            AovKind::MaterialId | AovKind::ObjectId => &["id"],
+        }
+    }
+}
+
+/* What a camera path found at its first hit, zero where it left the scene right away */
+#[derive(Copy, Clone, Debug)]
+pub struct AovSample {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub position: Vec3,
+    pub depth: f32,
+    pub material: u32,
+    pub object: u32,
+    /* Part of the radiance of the path that is direct light */
+    pub direct: Vec3,
+}
+
+impl Default for AovSample {
+    fn default() -> AovSample {
+        AovSample {
+            albedo: Vec3::zero(),
+            normal: Vec3::zero(),
+            position: Vec3::zero(),
+            depth: 0.0,
+            material: 0,
+            object: 0,
+            direct: Vec3::zero(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct AovPixel {
+    albedo: Vec3,
+    normal: Vec3,
+    direct: Vec3,
+    indirect: Vec3,
+    n: u32,
+    /* Sample closest to the pixel center and its squared distance from it */
+    nearest: AovSample,
+    nearest_dist: f32,
+}
+
+/*
+ * Accumulation buffer of the AOVs of the crop window. Samples only count
+ * towards the pixel they were taken for, without the reconstruction filter
+ * of the film, so the direct and indirect parts add up to the radiance
+ * as the box filter reconstructs it.
+ */
+pub struct AovFilm {
+    pub kinds: Vec<AovKind>,
+    crop: PixelBounds,
+    pixels: Vec<AovPixel>,
+}
+
+impl AovFilm {
+    pub fn new(kinds: Vec<AovKind>, crop: PixelBounds) -> AovFilm {
+        let empty = AovPixel {
+            albedo: Vec3::zero(),
+            normal: Vec3::zero(),
+            direct: Vec3::zero(),
+            indirect: Vec3::zero(),
+            n: 0,
+            nearest: AovSample::default(),
+            nearest_dist: f32::INFINITY,
+        };
+        /* Nothing is stored without any AOVs to write */
+        let n = if kinds.is_empty() { 0 } else { crop.w() * crop.h() };
+        AovFilm { kinds, crop, pixels: vec![empty; n] }
+    }
+
+    /* Adds the sample taken at the offset jitter within pixel x, y, ignored outside of the crop */
+    pub fn add_sample(&mut self, x: usize, y: usize, jitter: (f32, f32), radiance: Vec3,
+                      sample: &AovSample) {
+        if self.kinds.is_empty() || !self.crop.contains(x, y) {
+            return;
+        }
+        let pixel = &mut self.pixels[(x - self.crop.x0) + (y - self.crop.y0) * self.crop.w()];
+        pixel.albedo += sample.albedo;
+        pixel.normal += sample.normal;
+        pixel.direct += sample.direct;
+        pixel.indirect += radiance - sample.direct;
+        pixel.n += 1;
+
+        let dist = (jitter.0 - 0.5) * (jitter.0 - 0.5) + (jitter.1 - 0.5) * (jitter.1 - 0.5);
+        if dist < pixel.nearest_dist {
+            pixel.nearest = *sample;
+            pixel.nearest_dist = dist;
+        }
+    }
+
+    pub fn encode(&self, out: &mut Encoder) {
+        out.u64(self.pixels.len() as u64);
+        for p in &self.pixels {
+            for v in [p.albedo, p.normal, p.direct, p.indirect, p.nearest.albedo, p.nearest.normal,
+                      p.nearest.position, p.nearest.direct] {
+                out.vec3(v);
+            }
+            out.u32(p.n);
+            out.f32(p.nearest.depth);
+            out.u32(p.nearest.material);
+            out.u32(p.nearest.object);
+            out.f32(p.nearest_dist);
+        }
+    }
+
+    /* Restores the accumulated samples, AOVs must be recorded now as they were before */
+    pub fn decode(&mut self, input: &mut Decoder) -> Result<(), CheckpointError> {
+        input.expect("number of AOV pixels", self.pixels.len() as u64)?;
+        for p in self.pixels.iter_mut() {
+            for v in [&mut p.albedo, &mut p.normal, &mut p.direct, &mut p.indirect, &mut p.nearest.albedo,
+                      &mut p.nearest.normal, &mut p.nearest.position, &mut p.nearest.direct] {
+                *v = input.vec3()?;
+            }
+            p.n = input.u32()?;
+            p.nearest.depth = input.f32()?;
+            p.nearest.material = input.u32()?;
+            p.nearest.object = input.u32()?;
+            p.nearest_dist = input.f32()?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Image of one AOV, a plane of values per channel. Colors and normals
+     * are averaged over the samples of a pixel. Depths, positions and IDs
+     * blended across an edge would belong to neither side, so those keep
+     * the sample nearest to the pixel center instead.
+     */
+    pub fn planes(&self, kind: AovKind) -> Vec<Vec<f32>> {
+        let values: Vec<Vec3> = self.pixels.iter().map(|p| {
+            let mean = |sum: Vec3| if p.n > 0 { sum / p.n as f32 } else { Vec3::zero() };
+            match kind {
+                AovKind::Albedo => mean(p.albedo),
+                AovKind::Normal => mean(p.normal),
+                AovKind::Direct => mean(p.direct),
+                AovKind::Indirect => mean(p.indirect),
+                AovKind::Position => p.nearest.position,
+                AovKind::Depth => Vec3::one() * p.nearest.depth,
+                AovKind::MaterialId => Vec3::one() * p.nearest.material as f32,
+                AovKind::ObjectId => Vec3::one() * p.nearest.object as f32,
+            }
+        }).collect();
+        (0..kind.channels().len()).map(|c| values.iter().map(|v| v[c]).collect()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aov_film() {
+        let crop = PixelBounds { x0: 1, x1: 3, y0: 0, y1: 1 };
+        let mut film = AovFilm::new(vec![AovKind::Albedo, AovKind::ObjectId], crop);
+        let mut sample = AovSample { albedo: Vec3::one(), object: 4, direct: Vec3::one() * 0.25,
+                                     ..AovSample::default() };
+        film.add_sample(1, 0, (0.45, 0.55), Vec3::one(), &sample);
+        sample.albedo = Vec3::zero();
+        sample.object = 7;
+        film.add_sample(1, 0, (0.9, 0.1), Vec3::one() * 0.5, &sample);
+        film.add_sample(0, 0, (0.5, 0.5), Vec3::one(), &sample);
+
+        /* Colors are averaged, IDs come from the sample nearest to the center */
+        assert_eq!(film.planes(AovKind::Albedo)[0], vec![0.5, 0.0]);
+        assert_eq!(film.planes(AovKind::ObjectId), vec![vec![4.0, 0.0]]);
+        let direct = film.planes(AovKind::Direct)[1][0];
+        let indirect = film.planes(AovKind::Indirect)[1][0];
+        assert!((direct + indirect - 0.75).abs() < 1e-6);
+        assert_eq!("material".parse::<AovKind>(), Ok(AovKind::MaterialId));
+    }
+}
@@ -0,0 +1,183 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::distribution::Distribution2D;
+use crate::image::Image;
+use crate::warp::sample_unit_disk;
+
+/*
+ * Shape of the opening of a lens, which is what out of focus highlights
+ * take the shape of. Points are in the unit disk, scaled by the lens.
+ */
+pub trait Aperture {
+    /* Point on the opening, distributed by how much light passes there */
+    fn sample(&self, u: (f32, f32)) -> Vec3;
+
+    /* Fraction of light let through at a point, for lenses tracing through their stop */
+    fn transmission(&self, p: Vec3) -> f32;
+}
+
+/*
+ * Aperture as given on the command line:
+ *   circle
+ *   polygon:blades[:rotation]
+ *   image:mask.pfm
+ * The rotation is in degrees, the mask is a .pfm or .hdr image whose
+ * brightness is the transmission over the square around the unit disk.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApertureShape {
+    Circle,
+    Polygon { blades: u32, rotation: f32 },
+    Image { path: String },
+}
+
+impl FromStr for ApertureShape {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        match fields.as_slice() {
+            ["circle"] => Ok(ApertureShape::Circle),
+            ["polygon", blades, rotation @ ..] if rotation.len() <= 1 => {
+                let blades = match blades.parse::<u32>() {
+                    Ok(blades) if blades >= 3 => blades,
+                    _ => return Err(format!("Invalid blade count: {}", blades)),
+                };
+                let rotation = match rotation.first() {
+                    Some(r) => r.parse().map_err(|_| format!("Invalid rotation: {}", r))?,
+                    None => 0.0,
+                };
+                Ok(ApertureShape::Polygon { blades, rotation })
+            }
+            ["image", path @ ..] if !path.is_empty() => Ok(ApertureShape::Image { path: path.join(":") }),
+            _ => Err(format!("Unknown aperture: {}", s)),
+        }
+    }
+}
+
+pub fn new_aperture(shape: &ApertureShape) -> Result<Box<dyn Aperture>, String> {
+    match shape {
+        ApertureShape::Circle => Ok(Box::new(CircleAperture)),
+        ApertureShape::Polygon { blades, rotation } =>
+            Ok(Box::new(PolygonAperture::new(*blades, rotation.to_radians()))),
+        ApertureShape::Image { path } => match Image::load(path) {
+            Ok(image) => ImageAperture::new(image).map(|a| Box::new(a) as Box<dyn Aperture>)
+                                                 .ok_or(format!("Aperture mask {} is black", path)),
+            Err(why) => Err(format!("Could not load {}: {}", path, why)),
+        },
+    }
+}
+
+pub struct CircleAperture;
+
+impl Aperture for CircleAperture {
+    fn sample(&self, u: (f32, f32)) -> Vec3 {
+        sample_unit_disk(u)
+    }
+
+    fn transmission(&self, p: Vec3) -> f32 {
+        if p.len2() <= 1.0 { 1.0 } else { 0.0 }
+    }
+}
+
+/* Regular polygon inscribed in the unit circle, as formed by straight diaphragm blades */
+pub struct PolygonAperture {
+    blades: u32,
+    rotation: f32,
+}
+
+impl PolygonAperture {
+    pub fn new(blades: u32, rotation: f32) -> PolygonAperture {
+        PolygonAperture { blades: blades.max(3), rotation }
+    }
+
+    fn corner(&self, i: u32) -> Vec3 {
+        let phi = self.rotation + 2.0 * PI * i as f32 / self.blades as f32;
+        Vec3::new(phi.cos(), phi.sin(), 0.0)
+    }
+}
+
+impl Aperture for PolygonAperture {
+    /* Picks one of the triangles fanning out of the center and a uniform point in it */
+    fn sample(&self, u: (f32, f32)) -> Vec3 {
+        let x = u.0 * self.blades as f32;
+        let i = (x as u32).min(self.blades - 1);
+        let u0 = x - i as f32;
+
+        let s = u0.sqrt();
+        let (b1, b2) = (s * (1.0 - u.1), s * u.1);
+        self.corner(i) * b1 + self.corner(i + 1) * b2
+    }
+
+    fn transmission(&self, p: Vec3) -> f32 {
+        /* Inside if within the apothem along the direction of the nearest edge's normal */
+        let sector = 2.0 * PI / self.blades as f32;
+        let phi = (p.y.atan2(p.x) - self.rotation).rem_euclid(sector);
+        let apothem = (sector / 2.0).cos();
+        let along = p.len() * (phi - sector / 2.0).cos();
+        if along <= apothem { 1.0 } else { 0.0 }
+    }
+}
+
+/* Aperture mask image stretched over [-1, 1]^2, its brightness being the transmission */
+pub struct ImageAperture {
+    image: Image,
+    distribution: Distribution2D,
+    max: f32,
+}
+
+impl ImageAperture {
+    /* None if the image lets no light through */
+    pub fn new(image: Image) -> Option<ImageAperture> {
+        let values: Vec<f32> = image.pixels.iter().map(|p| p.luminance().max(0.0)).collect();
+        let max = values.iter().cloned().fold(0.0, f32::max);
+        if max <= 0.0 {
+            return None;
+        }
+        let distribution = Distribution2D::new(&values, image.w, image.h);
+        Some(ImageAperture { image, distribution, max })
+    }
+}
+
+impl Aperture for ImageAperture {
+    fn sample(&self, u: (f32, f32)) -> Vec3 {
+        let ((s, t), _) = self.distribution.sample_continuous(u);
+        /* Row 0 is the top of the image */
+        Vec3::new(2.0 * s - 1.0, 1.0 - 2.0 * t, 0.0)
+    }
+
+    fn transmission(&self, p: Vec3) -> f32 {
+        let s = (p.x + 1.0) / 2.0;
+        let t = (1.0 - p.y) / 2.0;
+        if !(0.0..1.0).contains(&s) || !(0.0..1.0).contains(&t) {
+            return 0.0;
+        }
+        let x = (s * self.image.w as f32) as usize;
+        let y = (t * self.image.h as f32) as usize;
+        self.image.get(x, y).luminance().max(0.0) / self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_polygon_aperture() {
+        let hexagon = PolygonAperture::new(6, 0.0);
+        let n = 32;
+        for i in 0..n {
+            for j in 0..n {
+                let u = ((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                assert_eq!(hexagon.transmission(hexagon.sample(u)), 1.0);
+            }
+        }
+        /* A corner reaches the unit circle, the middle of an edge doesn't */
+        assert_eq!(hexagon.transmission(Vec3::new(0.99, 0.0, 0.0)), 1.0);
+        assert_eq!(hexagon.transmission(Vec3::new(0.0, 0.95, 0.0)), 0.0);
+        assert_eq!("polygon:6:15".parse::<ApertureShape>(),
+                   Ok(ApertureShape::Polygon { blades: 6, rotation: 15.0 }));
+        assert!("polygon:2".parse::<ApertureShape>().is_err());
+    }
+}
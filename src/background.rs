@@ -0,0 +1,211 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::distribution::Distribution2D;
+use crate::image::Image;
+
+/* A sampled direction towards the background */
+pub struct BackgroundSample {
+    pub dir: Vec3,
+    pub radiance: Vec3,
+    /* Solid angle density */
+    pub pdf: f32,
+}
+
+/* Radiance arriving from infinitely far away along directions that miss the scene */
+pub trait Background {
+    fn eval(&self, dir: Vec3) -> Vec3;
+
+    /* Importance samples a direction, None if the background can't be sampled */
+    fn sample(&self, _u: (f32, f32)) -> Option<BackgroundSample> {
+        None
+    }
+
+    fn pdf(&self, _dir: Vec3) -> f32 {
+        0.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BackgroundKind {
+    Constant,
+    Gradient,
+    EnvMap,
+    Sky,
+}
+
+impl FromStr for BackgroundKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "constant" => Ok(BackgroundKind::Constant),
+            "gradient" => Ok(BackgroundKind::Gradient),
+            "envmap" => Ok(BackgroundKind::EnvMap),
+            "sky" => Ok(BackgroundKind::Sky),
+            _ => Err(format!("Unknown background: {}", s)),
+        }
+    }
+}
+
+pub struct ConstantBackground {
+    color: Vec3,
+}
+
+impl ConstantBackground {
+    pub fn new(color: Vec3) -> ConstantBackground {
+        ConstantBackground { color }
+    }
+}
+
+impl Background for ConstantBackground {
+    fn eval(&self, _dir: Vec3) -> Vec3 {
+        self.color
+    }
+}
+
+/* Vertical blend between two colors, the original fake sky */
+pub struct GradientBackground {
+    bottom: Vec3,
+    top: Vec3,
+}
+
+impl GradientBackground {
+    pub fn new(bottom: Vec3, top: Vec3) -> GradientBackground {
+        GradientBackground { bottom, top }
+    }
+
+    pub fn sky() -> GradientBackground {
+        GradientBackground::new(Vec3::one(), Vec3::new(0.5, 0.7, 1.0))
+    }
+}
+
+impl Background for GradientBackground {
+    fn eval(&self, dir: Vec3) -> Vec3 {
+        let unit_dir = dir.normalized();
+        let t = 0.5 * (unit_dir.y + 1.0);
+        self.bottom * (1.0 - t) + self.top * t
+    }
+}
+
+/*
+ * Equirectangular (latitude-longitude) environment map with +y up. The
+ * texels are importance sampled proportionally to their luminance.
+ */
+pub struct EnvironmentMap {
+    image: Image,
+    /* Rotation around the y axis in radians */
+    rotation: f32,
+    intensity: f32,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn new(image: Image, rotation: f32, intensity: f32) -> EnvironmentMap {
+        let (w, h) = (image.w, image.h);
+        let mut func = vec![0.0; w * h];
+        for y in 0..h {
+            /* Rows near the poles cover less solid angle */
+            let sin_theta = (PI * (y as f32 + 0.5) / h as f32).sin();
+            for x in 0..w {
+                func[x + y * w] = image.get(x, y).luminance().max(0.0) * sin_theta;
+            }
+        }
+        let distribution = Distribution2D::new(&func, w, h);
+        EnvironmentMap { image, rotation, intensity, distribution }
+    }
+
+    fn to_local(&self, dir: Vec3) -> Vec3 {
+        let (s, c) = (-self.rotation).sin_cos();
+        Vec3::new(c * dir.x + s * dir.z, dir.y, -s * dir.x + c * dir.z)
+    }
+
+    fn to_world(&self, dir: Vec3) -> Vec3 {
+        let (s, c) = self.rotation.sin_cos();
+        Vec3::new(c * dir.x + s * dir.z, dir.y, -s * dir.x + c * dir.z)
+    }
+
+    /* Direction to [0, 1)^2 map coordinates */
+    fn dir_to_uv(dir: Vec3) -> (f32, f32) {
+        let d = dir.normalized();
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        let mut phi = d.x.atan2(-d.z);
+        if phi < 0.0 {
+            phi += 2.0 * PI;
+        }
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    fn uv_to_dir(uv: (f32, f32)) -> Vec3 {
+        let phi = uv.0 * 2.0 * PI;
+        let theta = uv.1 * PI;
+        let sin_theta = theta.sin();
+        Vec3::new(sin_theta * phi.sin(), theta.cos(), -sin_theta * phi.cos())
+    }
+
+    fn lookup(&self, uv: (f32, f32)) -> Vec3 {
+        let x = ((uv.0 * self.image.w as f32) as usize).min(self.image.w - 1);
+        let y = ((uv.1 * self.image.h as f32) as usize).min(self.image.h - 1);
+        self.image.get(x, y) * self.intensity
+    }
+}
+
+impl Background for EnvironmentMap {
+    fn eval(&self, dir: Vec3) -> Vec3 {
+        self.lookup(Self::dir_to_uv(self.to_local(dir)))
+    }
+
+    fn sample(&self, u: (f32, f32)) -> Option<BackgroundSample> {
+        let (uv, map_pdf) = self.distribution.sample_continuous(u);
+        if map_pdf == 0.0 {
+            return None;
+        }
+
+        let sin_theta = (uv.1 * PI).sin();
+        if sin_theta == 0.0 {
+            return None;
+        }
+
+        /* Change of variables from the unit square to solid angle */
+        let pdf = map_pdf / (2.0 * PI * PI * sin_theta);
+        let dir = self.to_world(Self::uv_to_dir(uv));
+        Some(BackgroundSample { dir, radiance: self.lookup(uv), pdf })
+    }
+
+    fn pdf(&self, dir: Vec3) -> f32 {
+        let uv = Self::dir_to_uv(self.to_local(dir));
+        let sin_theta = (uv.1 * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envmap_sample_pdf() {
+        let (w, h) = (16, 8);
+        let mut pixels = vec![Vec3::one() * 0.1; w * h];
+        pixels[5 + 3 * w] = Vec3::one() * 100.0;
+        let env = EnvironmentMap::new(Image { w, h, pixels }, 0.7, 1.0);
+
+        let mut hits = 0;
+        for i in 0..64 {
+            for j in 0..64 {
+                let u = ((i as f32 + 0.5) / 64.0, (j as f32 + 0.5) / 64.0);
+                let s = env.sample(u).unwrap();
+                assert!((env.pdf(s.dir) - s.pdf).abs() / s.pdf < 1e-2);
+                assert_eq!(env.eval(s.dir), s.radiance);
+                if s.radiance.x > 1.0 {
+                    hits += 1;
+                }
+            }
+        }
+        /* The bright texel holds the vast majority of the power */
+        assert!(hits > 64 * 64 / 2);
+    }
+}
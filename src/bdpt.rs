@@ -0,0 +1,608 @@
+use std::cell::RefCell;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::aov::AovSample;
+use crate::camera::Camera;
+use crate::distribution::Distribution1D;
+use crate::film::Splat;
+use crate::hittable::{Hittable, HitRecord};
+use crate::integrator::{power_heuristic, record_first_hit, sample_background, BounceCounts, DepthPolicy,
+                        Integrator, SHADOW_EPSILON, T_MAX, T_MIN};
+use crate::sampler::{Sampler, BOUNCE_BSDF_DIM, BOUNCE_LIGHT_DIM};
+use crate::scene::Scene;
+use crate::stats::{count, Counter};
+
+#[derive(Copy, Clone)]
+enum VertexKind<'a> {
+    Camera,
+    /* Index of the light in the scene */
+    Light(usize),
+    Surface(HitRecord<'a>),
+}
+
+/*
+ * Vertex of a camera or light subpath. The densities are by area, of
+ * sampling the vertex from the one before it on its subpath and from the
+ * one after it when going the other way, zero at singular scattering.
+ */
+#[derive(Copy, Clone)]
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    p: Vec3,
+    /* Shading normal, zero for points like the pinhole and punctual lights */
+    n: Vec3,
+    /* Unit direction towards the vertex before this one on its subpath */
+    wo: Vec3,
+    /* Throughput of the subpath up to and including this vertex */
+    beta: Vec3,
+    /* Scattering at the vertex was singular, so no connection can be made to it */
+    delta: bool,
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn new(kind: VertexKind<'a>, p: Vec3, n: Vec3, beta: Vec3) -> Vertex<'a> {
+        Vertex { kind, p, n, wo: Vec3::zero(), beta, delta: false, pdf_fwd: 0.0, pdf_rev: 0.0 }
+    }
+
+    /* BSDF towards next times the cosine there, zero for anything but surfaces */
+    fn f(&self, next: &Vertex, from_light: bool) -> Vec3 {
+        match &self.kind {
+            VertexKind::Surface(rec) => {
+                let wi = (next.p - self.p).normalized();
+                let f = rec.mat.eval(self.wo, wi, rec);
+                if from_light { f * shading_correction(self.wo, wi, rec) } else { f }
+            }
+            _ => Vec3::zero(),
+        }
+    }
+}
+
+/* Where a camera subpath left the scene */
+struct Escape {
+    dir: Vec3,
+    beta: Vec3,
+    /* Solid angle density of the direction, zero after singular scattering */
+    pdf: f32,
+}
+
+/*
+ * Factor keeping transport from the lights symmetric where the shading
+ * normal differs from the surface's, as BSDFs only see the former.
+ */
+fn shading_correction(wo: Vec3, wi: Vec3, rec: &HitRecord) -> f32 {
+    let denom = wo.dot(rec.ng).abs() * wi.dot(rec.n).abs();
+    if denom == 0.0 {
+        return 0.0;
+    }
+    wo.dot(rec.n).abs() * wi.dot(rec.ng).abs() / denom
+}
+
+/* Solid angle density at from converted to area density at to */
+fn convert_density(pdf: f32, from: &Vertex, to: &Vertex) -> f32 {
+    let d = to.p - from.p;
+    let dist2 = d.len2();
+    if dist2 == 0.0 {
+        return 0.0;
+    }
+    let cos = if to.n == Vec3::zero() { 1.0 } else { to.n.dot(d).abs() / dist2.sqrt() };
+    pdf * cos / dist2
+}
+
+/*
+ * Path made of the first s vertices of the light subpath and the first t
+ * of the camera subpath, connected at the time of the camera ray.
+ */
+#[derive(Copy, Clone)]
+struct Connection<'p, 's> {
+    light: &'p [Vertex<'s>],
+    camera: &'p [Vertex<'s>],
+    s: usize,
+    t: usize,
+    time: f32,
+}
+
+/* Zero densities belong to singular events, which cancel out of the ratios of densities */
+fn remap0(pdf: f32) -> f32 {
+    if pdf != 0.0 { pdf } else { 1.0 }
+}
+
+/*
+ * Bidirectional path tracing: for every camera sample a subpath is traced
+ * from the camera and one from a light, and every pair of their vertices
+ * is connected. Each such strategy is weighted by the balance heuristic
+ * over all strategies that could have produced the same path. Connections
+ * of light subpaths to the camera land on other pixels and are splatted.
+ *
+ * Lights are picked by power for both starting light subpaths and direct
+ * lighting, and lights at infinity can't start subpaths. The background
+ * is found by camera subpaths and sampled from their vertices, weighted
+ * against each other like in trace_ray. Media are left out.
+ */
+pub struct BdptIntegrator<'a> {
+    policy: DepthPolicy,
+    camera: &'a dyn Camera,
+    /* Chooses the light a light subpath starts at, None without any lights */
+    lights: Option<Distribution1D>,
+    film_size: (usize, usize),
+    splats: RefCell<Vec<Splat>>,
+}
+
+impl<'a> BdptIntegrator<'a> {
+    pub fn new(policy: DepthPolicy, camera: &'a dyn Camera, scene: &Scene,
+               film_size: (usize, usize)) -> BdptIntegrator<'a> {
+        let scene_radius = match scene.world.get_aabb() {
+            Some(aabb) => (aabb.max - aabb.min).len() / 2.0,
+            None => 0.0,
+        };
+        let powers: Vec<f32> = scene.lights.iter().map(|light| light.power(scene_radius)).collect();
+        let lights = if powers.is_empty() { None } else { Some(Distribution1D::new(&powers)) };
+        BdptIntegrator { policy, camera, lights, film_size, splats: RefCell::new(Vec::new()) }
+    }
+
+    /* Light whose surface a camera subpath has hit */
+    fn find_light(scene: &Scene, v: &Vertex) -> Option<usize> {
+        scene.lights.iter().position(|light| light.on_surface(v.p))
+    }
+
+    /* Area density of sampling next from v, which was reached from prev */
+    fn pdf(&self, scene: &Scene, v: &Vertex, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let pdf_dir = match &v.kind {
+            VertexKind::Camera => self.camera.pdf_dir(next.p - v.p),
+            VertexKind::Light(index) => return self.pdf_light(scene, v, *index, next),
+            VertexKind::Surface(rec) => match prev {
+                Some(prev) => rec.mat.pdf((prev.p - v.p).normalized(), (next.p - v.p).normalized(), rec),
+                None => 0.0,
+            },
+        };
+        convert_density(pdf_dir, v, next)
+    }
+
+    /* Area density of light emitted at v by light index arriving at next */
+    fn pdf_light(&self, scene: &Scene, v: &Vertex, index: usize, next: &Vertex) -> f32 {
+        let (_, pdf_dir) = scene.lights[index].pdf_le(v.p, v.n, next.p - v.p);
+        convert_density(pdf_dir, v, next)
+    }
+
+    /* Density of a light subpath starting at v on light index */
+    fn pdf_light_origin(&self, scene: &Scene, v: &Vertex, index: usize, next: &Vertex) -> f32 {
+        let pmf = match &self.lights {
+            Some(lights) => lights.pmf(index),
+            None => return 0.0,
+        };
+        let (pdf_pos, _) = scene.lights[index].pdf_le(v.p, v.n, next.p - v.p);
+        pmf * pdf_pos
+    }
+
+    /* Whether nothing lies between a and b */
+    fn visible(scene: &Scene, a: &Vertex, b: &Vertex, time: f32, sampler: &mut dyn Sampler) -> bool {
+        let d = b.p - a.p;
+        let dist = d.len();
+        let dir = d / dist;
+        let offset = if a.n.dot(dir) >= 0.0 { a.n } else { -a.n };
+        let shadow = Ray::new(a.p + offset * SHADOW_EPSILON, dir, time);
+        count(Counter::ShadowRays, 1);
+        scene.world.hit(&shadow, T_MIN, dist * (1.0 - 1e-3), sampler.rng()).is_none()
+    }
+
+    /*
+     * Extends path from its last vertex along ray, whose direction was
+     * sampled with solid angle density pdf, up to the depth limit. Bounces
+     * of light subpaths take the sampler dimensions after those of the
+     * camera. Returns where the path left the scene, if it did.
+     */
+    fn random_walk<'s>(&self, scene: &'s Scene, mut ray: Ray, mut beta: Vec3, mut pdf: f32, sampler: &mut dyn Sampler,
+                       path: &mut Vec<Vertex<'s>>) -> Option<Escape> {
+        let mut bounces = BounceCounts::default();
+        let from_light = matches!(path[0].kind, VertexKind::Light(_));
+        /* Camera subpaths also end on the emitter they hit */
+        let max_vertices = self.policy.max_depth as usize + if from_light { 1 } else { 2 };
+        let first_block = if from_light { self.policy.max_depth + 3 } else { 0 };
+        while path.len() < max_vertices {
+            let depth = path.len() as u32 - 1;
+            sampler.start_bounce(first_block + depth);
+            let rr_u = sampler.get_1d();
+
+            count(if !from_light && depth == 0 { Counter::CameraRays } else { Counter::ScatterRays }, 1);
+            let rec = match scene.world.hit(&ray, T_MIN, T_MAX, sampler.rng()) {
+                Some(rec) => rec,
+                None => return Some(Escape { dir: ray.dir, beta, pdf }),
+            };
+            count(Counter::PathVertices, 1);
+
+            let mut v = Vertex::new(VertexKind::Surface(rec), rec.p, rec.n, beta);
+            v.wo = -ray.dir.normalized();
+            let prev = path.len() - 1;
+            v.pdf_fwd = convert_density(pdf, &path[prev], &v);
+            path.push(v);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            sampler.set_bounce_dimension(first_block + depth, BOUNCE_BSDF_DIM);
+            let scatter = rec.mat.scatter(&ray, &rec, sampler)?;
+            if !bounces.add(scatter.lobe, &self.policy) {
+                break;
+            }
+            let wi = scatter.ray.dir.normalized();
+            let pdf_rev = if scatter.pdf > 0.0 {
+                rec.mat.pdf(wi, v.wo, &rec)
+            } else {
+                path[prev + 1].delta = true;
+                0.0
+            };
+            beta *= scatter.attenuation;
+            if from_light {
+                beta *= shading_correction(v.wo, wi, &rec);
+            }
+
+            if depth + 1 >= self.policy.rr_depth {
+                let survive = beta.max_component().min(0.95);
+                if rr_u >= survive {
+                    break;
+                }
+                beta = beta / survive;
+            }
+
+            path[prev].pdf_rev = convert_density(pdf_rev, &v, &path[prev]);
+            pdf = scatter.pdf;
+            ray = scatter.ray;
+        }
+        None
+    }
+
+    /* Traces a subpath from a light picked by power, leaving path empty if none can be started */
+    fn light_subpath<'s>(&self, scene: &'s Scene, time: f32, sampler: &mut dyn Sampler, path: &mut Vec<Vertex<'s>>) {
+        let lights = match &self.lights {
+            Some(lights) => lights,
+            None => return,
+        };
+        sampler.set_bounce_dimension(self.policy.max_depth + 2, 0);
+        let (index, pmf) = lights.sample_discrete(sampler.get_1d());
+        let u_pos = sampler.get_2d();
+        let u_dir = sampler.get_2d();
+        let light = &scene.lights[index];
+        let emission = match light.sample_le(u_pos, u_dir, time) {
+            Some(emission) if emission.pdf_pos > 0.0 && emission.pdf_dir > 0.0 => emission,
+            _ => return,
+        };
+        if emission.radiance == Vec3::zero() {
+            return;
+        }
+
+        let mut v = Vertex::new(VertexKind::Light(index), emission.ray.orig, emission.n, emission.radiance);
+        v.pdf_fwd = pmf * emission.pdf_pos;
+        path.push(v);
+        let cos = if emission.n == Vec3::zero() { 1.0 } else { emission.n.dot(emission.ray.dir).abs() };
+        let beta = emission.radiance * (cos / (pmf * emission.pdf_pos * emission.pdf_dir));
+        self.random_walk(scene, emission.ray, beta, emission.pdf_dir, sampler, path);
+
+        /* The cutoff of punctual lights applies over the first segment only */
+        if path.len() > 1 {
+            let cutoff = light.cutoff((path[1].p - path[0].p).len2());
+            for v in &mut path[1..] {
+                v.beta *= cutoff;
+            }
+        }
+    }
+
+    /*
+     * Balance heuristic weight of a connection, with the light or camera
+     * vertex replaced by the one sampled for the connection. The
+     * densities of the vertices around the connection are updated for the
+     * path as connected, then the weight follows from the ratios of the
+     * densities of all other ways to split the path.
+     */
+    fn mis_weight(&self, scene: &Scene, c: &Connection, sampled: Option<Vertex>) -> f32 {
+        let Connection { s, t, .. } = *c;
+        if s + t == 2 {
+            return 1.0;
+        }
+        let mut light = c.light[..s].to_vec();
+        let mut camera = c.camera[..t].to_vec();
+        match sampled {
+            Some(v) if s == 1 => light[0] = v,
+            Some(v) if t == 1 => camera[0] = v,
+            _ => {}
+        }
+
+        /* Emitters hit by the camera subpath but not known as lights are only found that way */
+        let hit_light = if s == 0 {
+            match Self::find_light(scene, &camera[t - 1]) {
+                Some(index) => Some(index),
+                None => return 1.0,
+            }
+        } else {
+            None
+        };
+
+        camera[t - 1].delta = false;
+        if s > 0 {
+            light[s - 1].delta = false;
+        }
+        camera[t - 1].pdf_rev = match hit_light {
+            Some(index) => self.pdf_light_origin(scene, &camera[t - 1], index, &camera[t - 2]),
+            None => self.pdf(scene, &light[s - 1], if s > 1 { Some(&light[s - 2]) } else { None }, &camera[t - 1]),
+        };
+        if t > 1 {
+            camera[t - 2].pdf_rev = match hit_light {
+                Some(index) => self.pdf_light(scene, &camera[t - 1], index, &camera[t - 2]),
+                None => self.pdf(scene, &camera[t - 1], Some(&light[s - 1]), &camera[t - 2]),
+            };
+        }
+        if s > 0 {
+            light[s - 1].pdf_rev = self.pdf(scene, &camera[t - 1], if t > 1 { Some(&camera[t - 2]) } else { None },
+                                            &light[s - 1]);
+        }
+        if s > 1 {
+            light[s - 2].pdf_rev = self.pdf(scene, &light[s - 1], Some(&camera[t - 1]), &light[s - 2]);
+        }
+
+        let mut sum = 0.0;
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap0(camera[i].pdf_rev) / remap0(camera[i].pdf_fwd);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum += ratio;
+            }
+        }
+        ratio = 1.0;
+        for i in (0..s).rev() {
+            ratio *= remap0(light[i].pdf_rev) / remap0(light[i].pdf_fwd);
+            let delta_before = match light[0].kind {
+                VertexKind::Light(index) if i == 0 => scene.lights[index].is_punctual(),
+                _ if i == 0 => true,
+                _ => light[i - 1].delta,
+            };
+            if !light[i].delta && !delta_before {
+                sum += ratio;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+
+    /* Light of a connection with t of at least two */
+    fn connect(&self, scene: &Scene, c: &Connection, sampler: &mut dyn Sampler) -> Vec3 {
+        let Connection { s, t, time, .. } = *c;
+        let pt = &c.camera[t - 1];
+        let rec = match &pt.kind {
+            VertexKind::Surface(rec) => rec,
+            _ => return Vec3::zero(),
+        };
+        let mut sampled = None;
+        let radiance = if s == 0 {
+            pt.beta * rec.mat.emitted(rec)
+        } else if s == 1 {
+            /* Direct lighting, with the light vertex sampled towards pt */
+            let lights = match &self.lights {
+                Some(lights) => lights,
+                None => return Vec3::zero(),
+            };
+            let (index, pmf) = lights.sample_discrete(sampler.get_1d());
+            let u = sampler.get_2d();
+            let sample = match scene.lights[index].sample_li(pt.p, u) {
+                Some(sample) if sample.pdf > 0.0 && sample.dist.is_finite() => sample,
+                _ => return Vec3::zero(),
+            };
+            let weight = sample.radiance / (sample.pdf * pmf);
+            let mut v = Vertex::new(VertexKind::Light(index), pt.p + sample.wi * sample.dist, sample.n, weight);
+            v.pdf_fwd = self.pdf_light_origin(scene, &v, index, pt);
+            let radiance = pt.beta * pt.f(&v, false) * weight;
+            if radiance == Vec3::zero() || !Self::visible(scene, pt, &v, time, sampler) {
+                return Vec3::zero();
+            }
+            sampled = Some(v);
+            radiance
+        } else {
+            let qs = &c.light[s - 1];
+            if qs.delta || pt.delta {
+                return Vec3::zero();
+            }
+            let radiance = qs.beta * qs.f(pt, true) * pt.f(qs, false) * pt.beta / (pt.p - qs.p).len2();
+            if radiance == Vec3::zero() || !Self::visible(scene, qs, pt, time, sampler) {
+                return Vec3::zero();
+            }
+            radiance
+        };
+        if radiance == Vec3::zero() {
+            return Vec3::zero();
+        }
+        radiance * self.mis_weight(scene, c, sampled)
+    }
+
+    /* Light tracing: connects the light subpath to a point on the lens, for t of one */
+    fn connect_camera(&self, scene: &Scene, c: &Connection, sampler: &mut dyn Sampler) -> Option<Splat> {
+        let qs = &c.light[c.s - 1];
+        if qs.delta {
+            return None;
+        }
+        let u = (sampler.rng().sample_01(), sampler.rng().sample_01());
+        let sample = self.camera.sample_wi(qs.p, u)?;
+        let mut v = Vertex::new(VertexKind::Camera, sample.p, Vec3::zero(), Vec3::one() * sample.weight);
+        v.wo = Vec3::zero();
+        let value = qs.beta * qs.f(&v, true) * sample.weight;
+        if value == Vec3::zero() || !Self::visible(scene, qs, &v, c.time, sampler) {
+            return None;
+        }
+        let weight = self.mis_weight(scene, c, Some(v));
+        let (w, h) = self.film_size;
+        Some(Splat { x: sample.s * w as f32, y: (1.0 - sample.t) * h as f32, value: value * weight })
+    }
+}
+
+impl Integrator for BdptIntegrator<'_> {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler, aov: Option<&mut AovSample>) -> Vec3 {
+        let mut camera = Vec::new();
+        let mut v = Vertex::new(VertexKind::Camera, ray.orig, Vec3::zero(), Vec3::one());
+        /* Cameras that can't be connected to rule out the strategies doing so */
+        let pdf = self.camera.pdf_dir(ray.dir);
+        v.delta = pdf == 0.0;
+        camera.push(v);
+        let escape = self.random_walk(scene, *ray, Vec3::one(), pdf, sampler, &mut camera);
+        let mut light = Vec::new();
+        self.light_subpath(scene, ray.time, sampler, &mut light);
+
+        let mut radiance = Vec3::zero();
+        let mut direct = Vec3::zero();
+        if let Some(escape) = escape {
+            let weight = if escape.pdf > 0.0 && camera.len() > 1 {
+                power_heuristic(escape.pdf, scene.background.pdf(escape.dir))
+            } else {
+                1.0
+            };
+            let background = escape.beta * scene.background.eval(escape.dir) * weight;
+            radiance += background;
+            if camera.len() <= 2 {
+                direct += background;
+            }
+        }
+
+        let mut splats = self.splats.borrow_mut();
+        for t in 1..=camera.len() {
+            if let VertexKind::Surface(rec) = &camera[t - 1].kind {
+                let pt = &camera[t - 1];
+                sampler.set_bounce_dimension(t as u32 - 2, BOUNCE_LIGHT_DIM);
+                let incoming = Ray::new(pt.p + pt.wo, -pt.wo, ray.time);
+                let lit = pt.beta * sample_background(scene, &incoming, rec, sampler);
+                radiance += lit;
+                if t == 2 {
+                    direct += lit;
+                }
+            }
+            for s in 0..=light.len() {
+                let depth = s + t;
+                if (s == 1 && t == 1) || depth < 2 || depth - 2 > self.policy.max_depth as usize {
+                    continue;
+                }
+                let c = Connection { light: &light, camera: &camera, s, t, time: ray.time };
+                if t == 1 {
+                    if s > 1 {
+                        splats.extend(self.connect_camera(scene, &c, sampler));
+                    }
+                    continue;
+                }
+                let connected = self.connect(scene, &c, sampler);
+                radiance += connected;
+                if depth - 2 <= 1 {
+                    direct += connected;
+                }
+            }
+        }
+
+        if let Some(aov) = aov {
+            if let Some(VertexKind::Surface(rec)) = camera.get(1).map(|v| v.kind) {
+                record_first_hit(aov, scene, ray, &rec);
+            }
+            aov.direct = direct;
+        }
+        radiance
+    }
+
+    fn take_splats(&self) -> Vec<Splat> {
+        std::mem::take(&mut *self.splats.borrow_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aperture::CircleAperture;
+    use crate::camera::{Frame, OrthographicCamera, PerspectiveCamera, Shutter};
+    use crate::film::{CropWindow, Film};
+    use crate::filter::{new_filter, FilterKind};
+    use crate::integrator::trace_ray;
+    use crate::light::{Light, SphereLight};
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::sampler::IndependentSampler;
+    use crate::scene::test_scene;
+    use crate::sphere::Sphere;
+
+    /*
+     * Without a camera to connect to, the strategies weighted in li alone
+     * cover all light, so on ground lit by a sphere light it estimates the
+     * same radiance as the path tracer.
+     */
+    #[test]
+    fn test_bdpt_matches_path_tracing() {
+        let ground_mat = Lambertian::new(Vec3::one() * 0.5);
+        let light_mat = DiffuseLight::new(Vec3::one() * 4.0, false);
+        let ground = Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &ground_mat);
+        let bulb = Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.25, &light_mat);
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(SphereLight::new(Vec3::new(0.0, 1.0, 0.0), 0.25,
+                                                                         Vec3::one() * 4.0))];
+        let scene = test_scene(vec![&ground, &bulb], lights, Vec3::zero(), vec![&ground_mat, &light_mat]);
+        let frame = Frame::look_at(Vec3::new(0.5, 2.0, 0.0), Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let camera = OrthographicCamera::new(frame, 1.0, 1.0, Shutter { open: 0.0, close: 0.0 });
+        let policy = DepthPolicy::default();
+        let bdpt = BdptIntegrator::new(policy, &camera, &scene, (1, 1));
+        let ray = Ray::new(Vec3::new(0.5, 2.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+
+        let mut sampler = IndependentSampler::new(5);
+        let n = 4000;
+        let (mut bidirectional, mut unidirectional) = (0.0, 0.0);
+        for i in 0..n {
+            sampler.start_pixel_sample(0, i);
+            bidirectional += bdpt.li(&ray, &scene, &mut sampler, None).x;
+            assert!(bdpt.take_splats().is_empty());
+            sampler.start_pixel_sample(1, i);
+            unidirectional += trace_ray(&ray, &scene, &mut sampler, &policy, None).x;
+        }
+        let (bidirectional, unidirectional) = (bidirectional / n as f32, unidirectional / n as f32);
+        assert!((bidirectional - unidirectional).abs() < 0.05 * unidirectional,
+                "{} vs {}", bidirectional, unidirectional);
+    }
+
+    /*
+     * Through a pinhole the light subpaths connect to the camera as well,
+     * landing as splats on other pixels. The image with the splats added
+     * matches the path traced one.
+     */
+    #[test]
+    fn test_bdpt_splats_match_path_tracing() {
+        let ground_mat = Lambertian::new(Vec3::one() * 0.5);
+        let light_mat = DiffuseLight::new(Vec3::one() * 4.0, false);
+        let ground = Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &ground_mat);
+        let bulb = Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.25, &light_mat);
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(SphereLight::new(Vec3::new(0.0, 1.0, 0.0), 0.25,
+                                                                         Vec3::one() * 4.0))];
+        let scene = test_scene(vec![&ground, &bulb], lights, Vec3::zero(), vec![&ground_mat, &light_mat]);
+        let frame = Frame::look_at(Vec3::new(1.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let shutter = Shutter { open: 0.0, close: 0.0 };
+        let camera = PerspectiveCamera::new(frame, 1.0, 40.0, 0.0, 2.0, Box::new(CircleAperture), shutter);
+        let policy = DepthPolicy::default();
+        let (w, h) = (4, 4);
+        let bdpt = BdptIntegrator::new(policy, &camera, &scene, (w, h));
+
+        /* Only the pixels of the crop window are sampled, still all the light paths land anywhere */
+        for crop in [CropWindow::default(), "0.25,0.75,0,0.5".parse().unwrap()] {
+            let mut sampler = IndependentSampler::new(5);
+            let new_film = || Film::new(w, h, crop, new_filter(FilterKind::Box, None));
+            let (mut bidirectional, mut unidirectional) = (new_film(), new_film());
+            let bounds = bidirectional.sample_bounds();
+            let mut splatted = 0;
+            for y in bounds.y0..bounds.y1 {
+                for x in bounds.x0..bounds.x1 {
+                    for i in 0..500 {
+                        sampler.start_pixel_sample(x + y * w, i);
+                        let jitter = sampler.get_2d();
+                        let u = (x as f32 + jitter.0) / w as f32;
+                        let v = 1.0 - (y as f32 + jitter.1) / h as f32;
+                        let ray = camera.get_ray(u, v, &mut sampler).unwrap();
+                        bidirectional.add_sample(x, y, jitter, bdpt.li(&ray, &scene, &mut sampler, None));
+                        let splats = bdpt.take_splats();
+                        splatted += splats.iter().filter(|splat| splat.value != Vec3::zero()).count();
+                        bidirectional.add_splats(&splats);
+                        unidirectional.add_sample(x, y, jitter, trace_ray(&ray, &scene, &mut sampler, &policy, None));
+                    }
+                }
+            }
+            assert!(splatted > 0);
+            let mean = |film: &Film| film.image().iter().map(|p| p.x).sum::<f32>() / film.image().len() as f32;
+            let (bidirectional, unidirectional) = (mean(&bidirectional), mean(&unidirectional));
+            assert!((bidirectional - unidirectional).abs() < 0.05 * unidirectional,
+                    "{:?}: {} vs {}", bounds, bidirectional, unidirectional);
+        }
+    }
+}
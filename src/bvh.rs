@@ -5,6 +5,7 @@ use crate::Ray;
 use crate::hittable::{Hittable, HitRecord, HittableList};
 use crate::rng::RNG;
 use crate::aabb::AABB;
+use crate::stats::{count, Counter};
 
 pub enum BVH<'a> {
     Node {
@@ -55,33 +56,53 @@ impl BVH<'_> {
             let a = Box::new(BVH::Leaf { hittables: a_hittables });
             let b = Box::new(BVH::Leaf { hittables: b_hittables });
             if cmp(a_aabb, b_aabb) == Ordering::Less {
-                return BVH::Node {
+                BVH::Node {
                     left: a,
                     right: b,
-                    aabb: aabb,
-                };
+                    aabb,
+                }
             } else {
-                return BVH::Node {
+                BVH::Node {
                     left: b,
                     right: a,
-                    aabb: aabb,
-                };
+                    aabb,
+                }
             }
         } else {
             objs.sort_by(|a, b| cmp(a.get_aabb().unwrap(),
                                     b.get_aabb().unwrap()));
 
-            return BVH::Node {
+            BVH::Node {
                 left: Box::new(BVH::new(objs[..partition].to_vec())),
                 right: Box::new(BVH::new(objs[partition..].to_vec())),
-                aabb: aabb,
-            };
+                aabb,
+            }
+        }
+    }
+}
+
+impl BVH<'_> {
+    pub fn node_count(&self) -> usize {
+        match self {
+            BVH::Node { left, right, .. } => 1 + left.node_count() + right.node_count(),
+            BVH::Leaf { .. } => 1,
+        }
+    }
+
+    /* Bytes taken by the nodes and the primitive lists of the leaves */
+    pub fn memory(&self) -> usize {
+        let own = std::mem::size_of::<BVH>();
+        match self {
+            BVH::Node { left, right, .. } => own + left.memory() + right.memory(),
+            BVH::Leaf { hittables } =>
+                own + hittables.hittables.capacity() * std::mem::size_of::<&dyn Hittable>(),
         }
     }
 }
 
 impl Hittable for BVH<'_> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord<'_>> {
+        count(Counter::BvhNodes, 1);
         match self {
             BVH::Node { left, right, aabb } =>
             {
@@ -100,7 +121,10 @@ impl Hittable for BVH<'_> {
                 }
             }
 
-            BVH::Leaf { hittables } => hittables.hit(ray, t_min, t_max, rng),
+            BVH::Leaf { hittables } => {
+                count(Counter::PrimitiveTests, hittables.hittables.len() as u64);
+                hittables.hit(ray, t_min, t_max, rng)
+            }
         }
     }
 
@@ -114,7 +138,7 @@ impl Hittable for BVH<'_> {
 
 impl fmt::Debug for BVH<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &*self {
+        match self {
             BVH::Node { left, right, aabb }=>
             f.debug_struct("Node")
              .field("left", &left)
@@ -126,3 +150,35 @@ impl fmt::Debug for BVH<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sphere;
+    use crate::Vec3;
+    use crate::material::Lambertian;
+
+    #[test]
+    fn test_miss() {
+        let mat = Lambertian::new(Vec3::new(0.5, 0.5, 0.5));
+        let spheres: Vec<Sphere> = (0..300)
+            .map(|i| Sphere::new(Vec3::new(10.0 + i as f32, 0.0, 0.0), 0.4, &mat))
+            .collect();
+        let bvh = BVH::new(spheres.iter().map(|s| s as &dyn Hittable).collect());
+        let aabb = bvh.get_aabb().unwrap();
+        let mut rng = RNG::with_seed(1);
+
+        /* A ray passing above the spheres is culled by the root */
+        let ray = Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(!aabb.hit(&ray, 1e-3, f32::INFINITY));
+        assert!(bvh.hit(&ray, 1e-3, f32::INFINITY, &mut rng).is_none());
+
+        /* Beyond t_max the spheres are not reached either */
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(!aabb.hit(&ray, 1e-3, 5.0));
+        assert!(bvh.hit(&ray, 1e-3, 5.0, &mut rng).is_none());
+
+        let hit = bvh.hit(&ray, 1e-3, f32::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 9.6).abs() < 1e-3);
+    }
+}
@@ -1,48 +1,492 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
 use crate::Vec3;
 use crate::Ray;
-use crate::rng::*;
+use crate::sampler::{Sampler, CAMERA_TIME_DIM};
+use crate::aperture::Aperture;
+
+/* A point on the lens seen from a point in the scene */
+pub struct CameraSample {
+    /* Film position as get_ray takes it */
+    pub s: f32,
+    pub t: f32,
+    /* Point on the lens */
+    pub p: Vec3,
+    /* Importance arriving from p over the solid angle density of sampling it */
+    pub weight: f32,
+}
+
+/*
+ * Maps film coordinates s, t in [0, 1] (t = 0 at the bottom) to a primary
+ * ray. None if the point is outside the area the projection covers.
+ */
+pub trait Camera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray>;
+
+    /* Interval the times of the rays are drawn from */
+    fn shutter(&self) -> Shutter;
+
+    /*
+     * Samples a point on the lens that p is seen from, for connecting paths
+     * traced from the lights to the film. None if p is outside the view or
+     * the camera doesn't support it.
+     */
+    fn sample_wi(&self, _p: Vec3, _u: (f32, f32)) -> Option<CameraSample> {
+        None
+    }
+
+    /* Solid angle density of the direction of get_ray over the whole film, zero if unsupported */
+    fn pdf_dir(&self, _dir: Vec3) -> f32 {
+        0.0
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraKind {
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+    /* Traced through a lens prescription */
+    Realistic,
+}
+
+impl FromStr for CameraKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perspective" => Ok(CameraKind::Perspective),
+            "orthographic" => Ok(CameraKind::Orthographic),
+            "fisheye" => Ok(CameraKind::Fisheye),
+            "equirect" | "equirectangular" => Ok(CameraKind::Equirectangular),
+            "realistic" => Ok(CameraKind::Realistic),
+            _ => Err(format!("Unknown camera: {}", s)),
+        }
+    }
+}
+
+/* How the angle from the optical axis maps to the distance from the image center */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FisheyeMapping {
+    /* r = f * theta */
+    Equidistant,
+    /* r = 2 * f * sin(theta / 2), preserves areas */
+    Equisolid,
+}
+
+impl FromStr for FisheyeMapping {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "equidistant" => Ok(FisheyeMapping::Equidistant),
+            "equisolid" => Ok(FisheyeMapping::Equisolid),
+            _ => Err(format!("Unknown fisheye mapping: {}", s)),
+        }
+    }
+}
+
+/*
+ * Interval during which the shutter is open. Times are in the units object
+ * motion is given in, where moving spheres span [0, 1].
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+}
+
+impl Shutter {
+    /* Uniform time within the interval, drawn even if it is empty to keep the dimensions fixed */
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> f32 {
+        sampler.set_dimension(CAMERA_TIME_DIM);
+        let u = sampler.get_1d();
+        self.open + (self.close - self.open) * u
+    }
+}
+
+/* A named camera placement, name:x,y,z:tx,ty,tz on the command line */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ViewDesc {
+    pub name: String,
+    pub pos: Vec3,
+    pub target: Vec3,
+}
+
+impl FromStr for ViewDesc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        match fields.as_slice() {
+            [name, pos, target] if !name.is_empty() => match (Vec3::from_csv(pos), Vec3::from_csv(target)) {
+                (Some(pos), Some(target)) if pos != target =>
+                    Ok(ViewDesc { name: name.to_string(), pos, target }),
+                _ => Err(format!("Invalid view: {}", s)),
+            },
+            _ => Err(format!("Invalid view: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+impl Eye {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Eye::Left => "left",
+            Eye::Right => "right",
+        }
+    }
+
+    /* Signed distance of the eye from the center along the camera's right axis */
+    pub fn offset(&self, ipd: f32) -> f32 {
+        match self {
+            Eye::Left => -ipd / 2.0,
+            Eye::Right => ipd / 2.0,
+        }
+    }
+}
+
+/*
+ * Stereo pair given as ipd[:convergence] on the command line. Objects at
+ * the convergence distance line up in both images, by default it is the
+ * focus distance. Both are in scene units.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Stereo {
+    pub ipd: f32,
+    pub convergence: Option<f32>,
+}
+
+impl FromStr for Stereo {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| match v.parse::<f32>() {
+            Ok(v) if v > 0.0 => Ok(v),
+            _ => Err(format!("Invalid stereo: {}", s)),
+        };
+        match s.split_once(':') {
+            Some((ipd, convergence)) => Ok(Stereo { ipd: parse(ipd)?, convergence: Some(parse(convergence)?) }),
+            None => Ok(Stereo { ipd: parse(s)?, convergence: None }),
+        }
+    }
+}
+
+/* Orthonormal camera frame, looking along -w with v up */
+#[derive(Copy, Clone, Debug)]
+pub struct Frame {
+    pub orig: Vec3,
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
 
-pub struct Camera {
+impl Frame {
+    pub fn look_at(pos: Vec3, tgt: Vec3, up: Vec3) -> Frame {
+        let w = (pos - tgt).normalized();
+        let u = up.cross(w).normalized();
+        let v = w.cross(u);
+        Frame { orig: pos, u, v, w }
+    }
+
+    /* Moved sideways along u, e.g. to one eye of a stereo pair */
+    pub fn shifted(&self, offset: f32) -> Frame {
+        Frame { orig: self.orig + self.u * offset, ..*self }
+    }
+
+    /* From camera space, looking along +z */
+    pub fn local_to_world(&self, d: Vec3) -> Vec3 {
+        self.u * d.x + self.v * d.y - self.w * d.z
+    }
+}
+
+/* Thin lens perspective camera, the aperture gives the shape of the bokeh */
+pub struct PerspectiveCamera {
     orig: Vec3,
     lower_left: Vec3,
     horiz: Vec3,
     vert: Vec3,
-    w: Vec3,
     u: Vec3,
     v: Vec3,
+    focus: f32,
     lens_radius: f32,
+    aperture: Box<dyn Aperture>,
+    shutter: Shutter,
 }
 
-impl Camera {
-    pub fn new(pos: Vec3, tgt: Vec3, up: Vec3,
-               ar: f32, vfov: f32, aperture: f32, focus: f32) -> Camera {
-        let theta = (vfov / 180.0) * std::f32::consts::PI;
+impl PerspectiveCamera {
+    /* The lens diameter is in scene units */
+    pub fn new(frame: Frame, ar: f32, vfov: f32, diameter: f32, focus: f32,
+               aperture: Box<dyn Aperture>, shutter: Shutter) -> PerspectiveCamera {
+        let theta = (vfov / 180.0) * PI;
         let h = (theta / 2.0).tan();
         let viewport_h = 2.0 * h;
         let viewport_w = ar * viewport_h;
 
-        let w = (pos - tgt).normalized();
-        let u = up.cross(w).normalized();
-        let v = w.cross(u);
-
-        let orig = pos;
+        let Frame { orig, u, v, w } = frame;
         let horiz = u * viewport_w * focus;
         let vert = v * viewport_h * focus;
         let lower_left = orig - horiz * 0.5 - vert * 0.5 - w * focus;
 
-        let lens_radius = aperture / 2.0;
+        let lens_radius = diameter / 2.0;
 
-        Camera { orig: orig, lower_left: lower_left,
-                 horiz: horiz, vert: vert,
-                 w: w, u: u, v: v,
-                 lens_radius: lens_radius }
+        PerspectiveCamera { orig, lower_left,
+                            horiz, vert,
+                            u, v,
+                            focus,
+                            lens_radius,
+                            aperture,
+                            shutter }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, rng: &mut RNG) -> Ray {
-        let rd = random_in_unit_disk(rng) * self.lens_radius ;
+    /*
+     * Off-axis projection for a camera moved sideways by eye_offset from
+     * the center of a stereo pair: the image window is shifted back so the
+     * views of both eyes meet at the convergence distance instead of
+     * turning the cameras in, which would give vertical parallax.
+     */
+    pub fn converge(mut self, eye_offset: f32, convergence: f32) -> PerspectiveCamera {
+        self.lower_left -= self.u * (eye_offset * self.focus / convergence);
+        self
+    }
+}
+
+impl PerspectiveCamera {
+    /* Viewing direction, the normal of the lens */
+    fn forward(&self) -> Vec3 {
+        self.v.cross(self.u)
+    }
+
+    /* Area of the film at unit distance from the lens */
+    fn film_area(&self) -> f32 {
+        self.horiz.len() * self.vert.len() / (self.focus * self.focus)
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let rd = self.aperture.sample(sampler.get_2d()) * self.lens_radius;
         let offset = self.u * rd.x + self.v * rd.y;
 
         let dir = self.lower_left + self.horiz * s + self.vert * t - self.orig - offset;
-        Ray::new(self.orig + offset, dir)
+        Some(Ray::new(self.orig + offset, dir, self.shutter.sample(sampler)))
+    }
+
+    fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+
+    /*
+     * The film position is where the ray from the lens point through p
+     * meets the plane in focus. The importance and the density of the lens
+     * point both follow the aperture, which cancels in their ratio.
+     */
+    fn sample_wi(&self, p: Vec3, u: (f32, f32)) -> Option<CameraSample> {
+        let rd = self.aperture.sample(u) * self.lens_radius;
+        let lens = self.orig + self.u * rd.x + self.v * rd.y;
+        let d = p - lens;
+        let depth = d.dot(self.forward());
+        if depth <= 0.0 {
+            return None;
+        }
+        let focal = lens + d * (self.focus / depth) - self.lower_left;
+        let s = focal.dot(self.horiz) / self.horiz.len2();
+        let t = focal.dot(self.vert) / self.vert.len2();
+        if !(0.0..1.0).contains(&s) || !(0.0..1.0).contains(&t) {
+            return None;
+        }
+
+        let dist = d.len();
+        let cos = depth / dist;
+        Some(CameraSample {
+            s,
+            t,
+            p: lens,
+            weight: 1.0 / (self.film_area() * cos * cos * cos * dist * dist),
+        })
+    }
+
+    fn pdf_dir(&self, dir: Vec3) -> f32 {
+        let cos = dir.normalized().dot(self.forward());
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        1.0 / (self.film_area() * cos * cos * cos)
+    }
+}
+
+/* Parallel projection, height is the extent of the view in scene units */
+pub struct OrthographicCamera {
+    frame: Frame,
+    width: f32,
+    height: f32,
+    shutter: Shutter,
+}
+
+impl OrthographicCamera {
+    pub fn new(frame: Frame, ar: f32, height: f32, shutter: Shutter) -> OrthographicCamera {
+        OrthographicCamera { frame, width: height * ar, height, shutter }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let f = &self.frame;
+        let orig = f.orig + f.u * ((s - 0.5) * self.width) + f.v * ((t - 0.5) * self.height);
+        Some(Ray::new(orig, -f.w, self.shutter.sample(sampler)))
+    }
+
+    fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+}
+
+/* Fisheye lens whose image circle touches the shorter side of the film */
+pub struct FisheyeCamera {
+    frame: Frame,
+    ar: f32,
+    /* Half of the field of view covered by the image circle, in radians */
+    theta_max: f32,
+    mapping: FisheyeMapping,
+    shutter: Shutter,
+}
+
+impl FisheyeCamera {
+    /* fov in degrees, up to 360 */
+    pub fn new(frame: Frame, ar: f32, fov: f32, mapping: FisheyeMapping,
+               shutter: Shutter) -> FisheyeCamera {
+        let theta_max = (fov.to_radians() / 2.0).clamp(1e-3, PI);
+        FisheyeCamera { frame, ar, theta_max, mapping, shutter }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        /* Image circle of radius one */
+        let (x, y) = if self.ar >= 1.0 {
+            ((2.0 * s - 1.0) * self.ar, 2.0 * t - 1.0)
+        } else {
+            (2.0 * s - 1.0, (2.0 * t - 1.0) / self.ar)
+        };
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+
+        let theta = match self.mapping {
+            FisheyeMapping::Equidistant => r * self.theta_max,
+            FisheyeMapping::Equisolid => 2.0 * (r * (self.theta_max / 2.0).sin()).asin(),
+        };
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (cos_phi, sin_phi) = if r > 0.0 { (x / r, y / r) } else { (1.0, 0.0) };
+        let d = Vec3::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta);
+        Some(Ray::new(self.frame.orig, self.frame.local_to_world(d), self.shutter.sample(sampler)))
+    }
+
+    fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+}
+
+/* Full 360 by 180 degree latitude-longitude panorama, centered on the view direction */
+pub struct EquirectangularCamera {
+    frame: Frame,
+    eye_offset: f32,
+    convergence: f32,
+    shutter: Shutter,
+}
+
+impl EquirectangularCamera {
+    pub fn new(frame: Frame, shutter: Shutter) -> EquirectangularCamera {
+        EquirectangularCamera { frame, eye_offset: 0.0, convergence: f32::INFINITY, shutter }
+    }
+
+    /*
+     * Omni-directional stereo: every ray starts on a circle of radius
+     * |eye_offset| around the center, offset to the side of its horizontal
+     * direction, and is turned towards the center's view at the
+     * convergence distance.
+     */
+    pub fn stereo(mut self, eye_offset: f32, convergence: f32) -> EquirectangularCamera {
+        self.eye_offset = eye_offset;
+        self.convergence = convergence;
+        self
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let phi = (s - 0.5) * 2.0 * PI;
+        let elevation = (t - 0.5) * PI;
+        let d = Vec3::new(elevation.cos() * phi.sin(), elevation.sin(), elevation.cos() * phi.cos());
+        let orig = Vec3::new(phi.cos(), 0.0, -phi.sin()) * self.eye_offset;
+        let dir = if self.convergence.is_finite() { d * self.convergence - orig } else { d };
+        Some(Ray::new(self.frame.orig + self.frame.local_to_world(orig), self.frame.local_to_world(dir),
+                      self.shutter.sample(sampler)))
+    }
+
+    fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{new_sampler, SamplerKind};
+    use crate::aperture::CircleAperture;
+
+    #[test]
+    fn test_projections() {
+        let frame = Frame::look_at(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        let mut sampler = new_sampler(SamplerKind::Independent, 0, 1);
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+        let shutter = Shutter { open: 0.25, close: 0.5 };
+
+        let fisheye = FisheyeCamera::new(frame, 2.0, 180.0, FisheyeMapping::Equisolid, shutter);
+        let center = fisheye.get_ray(0.5, 0.5, sampler.as_mut()).unwrap();
+        assert!((center.dir - forward).len() < 1e-5);
+        /* The edge of the image circle is 90 degrees off axis */
+        let edge = fisheye.get_ray(0.5, 1.0, sampler.as_mut()).unwrap();
+        assert!((edge.dir - Vec3::new(0.0, 1.0, 0.0)).len() < 1e-5);
+        assert!(fisheye.get_ray(0.0, 0.5, sampler.as_mut()).is_none());
+
+        let pano = EquirectangularCamera::new(frame, shutter);
+        let center = pano.get_ray(0.5, 0.5, sampler.as_mut()).unwrap();
+        assert!((center.dir - forward).len() < 1e-5);
+        let behind = pano.get_ray(0.0, 0.5, sampler.as_mut()).unwrap();
+        assert!((behind.dir + forward).len() < 1e-5);
+        let right = pano.get_ray(0.75, 0.5, sampler.as_mut()).unwrap();
+        assert!((right.dir - Vec3::new(1.0, 0.0, 0.0)).len() < 1e-5);
+
+        let ortho = OrthographicCamera::new(frame, 2.0, 4.0, shutter);
+        let corner = ortho.get_ray(1.0, 1.0, sampler.as_mut()).unwrap();
+        assert!((corner.orig - Vec3::new(4.0, 2.0, 0.0)).len() < 1e-5);
+        assert!((corner.dir - forward).len() < 1e-5);
+        assert!(corner.time >= 0.25 && corner.time <= 0.5);
+    }
+
+    /* The windows of both eyes coincide at the convergence distance */
+    #[test]
+    fn test_stereo_convergence() {
+        let frame = Frame::look_at(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        let mut sampler = new_sampler(SamplerKind::Independent, 0, 1);
+        let shutter = Shutter { open: 0.0, close: 0.0 };
+        let at_convergence = |eye: Eye, sampler: &mut dyn Sampler| {
+            let offset = eye.offset(0.2);
+            let cam = PerspectiveCamera::new(frame.shifted(offset), 1.0, 40.0, 0.0, 2.0,
+                                              Box::new(CircleAperture), shutter).converge(offset, 5.0);
+            let ray = cam.get_ray(0.8, 0.3, sampler).unwrap();
+            ray.at(-5.0 / ray.dir.z)
+        };
+        let left = at_convergence(Eye::Left, sampler.as_mut());
+        let right = at_convergence(Eye::Right, sampler.as_mut());
+        assert!((left - right).len() < 1e-4);
+        assert_eq!("0.065:2".parse::<Stereo>(), Ok(Stereo { ipd: 0.065, convergence: Some(2.0) }));
+        assert!("top:0,1,0:0,1,0".parse::<ViewDesc>().is_err());
     }
 }
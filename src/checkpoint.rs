@@ -0,0 +1,187 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use crate::Vec3;
+use crate::aov::AovFilm;
+use crate::film::Film;
+
+#[derive(Debug)]
+pub enum CheckpointError {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckpointError::Io(why) => write!(f, "{}", why),
+            CheckpointError::Format(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl From<io::Error> for CheckpointError {
+    fn from(why: io::Error) -> CheckpointError {
+        CheckpointError::Io(why)
+    }
+}
+
+const MAGIC: &[u8; 8] = b"RRTCKPT2";
+
+/* Little endian encoding of the state of a render */
+pub struct Encoder {
+    pub bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn vec3(&mut self, v: Vec3) {
+        self.f32(v.x);
+        self.f32(v.y);
+        self.f32(v.z);
+    }
+}
+
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], CheckpointError> {
+        match self.bytes.get(self.pos..self.pos + N) {
+            Some(bytes) => {
+                self.pos += N;
+                let mut out = [0; N];
+                out.copy_from_slice(bytes);
+                Ok(out)
+            }
+            None => Err(CheckpointError::Format("Checkpoint is truncated".to_string())),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, CheckpointError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, CheckpointError> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3, CheckpointError> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    /* Reads a value written for the current render and fails if it differs */
+    pub fn expect(&mut self, what: &str, value: u64) -> Result<(), CheckpointError> {
+        let stored = self.u64()?;
+        if stored != value {
+            return Err(CheckpointError::Format(format!("Checkpoint has a different {}: {} instead of {}",
+                                                       what, stored, value)));
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Everything needed to continue a render: the accumulated film and AOVs
+ * and the number of samples taken for every pixel. Samplers derive their
+ * state from the seed, the pixel and the sample index, so no generator
+ * state is stored beyond the seed. The scene itself is not, resuming with
+ * a different one mixes both.
+ */
+pub struct RenderState<'f> {
+    pub seed: u64,
+    pub film: &'f mut Film,
+    pub aovs: &'f mut AovFilm,
+    pub counts: &'f mut [u32],
+}
+
+impl RenderState<'_> {
+    /* Writes to a temporary file first, so a render killed while saving keeps its last checkpoint */
+    pub fn save(&self, path: &str) -> Result<(), CheckpointError> {
+        let mut out = Encoder { bytes: MAGIC.to_vec() };
+        out.u64(self.seed);
+        self.film.encode(&mut out);
+        self.aovs.encode(&mut out);
+        out.u64(self.counts.len() as u64);
+        for n in self.counts.iter() {
+            out.u32(*n);
+        }
+
+        let temp = format!("{}.tmp", path);
+        let mut file = File::create(&temp)?;
+        file.write_all(&out.bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn load(&mut self, path: &str) -> Result<(), CheckpointError> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if !bytes.starts_with(MAGIC) {
+            return Err(CheckpointError::Format(format!("{} is not a checkpoint", path)));
+        }
+        let mut input = Decoder { bytes: &bytes, pos: MAGIC.len() };
+        input.expect("seed", self.seed)?;
+        self.film.decode(&mut input)?;
+        self.aovs.decode(&mut input)?;
+        input.expect("number of pixels", self.counts.len() as u64)?;
+        for n in self.counts.iter_mut() {
+            *n = input.u32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::{AovKind, AovSample};
+    use crate::film::CropWindow;
+    use crate::filter::{new_filter, FilterKind};
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let new_film = || Film::new(4, 2, CropWindow::default(), new_filter(FilterKind::Tent, None));
+        let mut film = new_film();
+        let mut aovs = AovFilm::new(vec![AovKind::Albedo], film.crop);
+        film.add_sample(1, 1, (0.3, 0.6), Vec3::new(1.0, 2.0, 3.0));
+        aovs.add_sample(1, 1, (0.3, 0.6), Vec3::one(), &AovSample { albedo: Vec3::one(), ..AovSample::default() });
+        let mut counts = vec![0, 0, 0, 0, 0, 1, 0, 0];
+        let path = std::env::temp_dir().join("rrt-test-checkpoint.bin");
+        let path = path.to_str().unwrap();
+        RenderState { seed: 3, film: &mut film, aovs: &mut aovs, counts: &mut counts }.save(path).unwrap();
+
+        let mut film2 = new_film();
+        let mut aovs2 = AovFilm::new(vec![AovKind::Albedo], film.crop);
+        let mut counts2 = vec![0; 8];
+        let mut state = RenderState { seed: 3, film: &mut film2, aovs: &mut aovs2, counts: &mut counts2 };
+        state.load(path).unwrap();
+        state.seed = 4;
+        assert!(state.load(path).is_err());
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(film2.image(), film.image());
+        assert_eq!(film2.pixel(1, 1).unwrap().n, 1);
+        assert_eq!(aovs2.planes(AovKind::Albedo), aovs.planes(AovKind::Albedo));
+        assert_eq!(counts2, counts);
+    }
+}
@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::hittable::Hittable;
+use crate::aov::AovSample;
+use crate::film::Splat;
+use crate::integrator::{Integrator, T_MAX, T_MIN};
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::stats::{self, count, Counter};
+use crate::tonemap::srgb_eotf;
+
+/* What a debug render shows instead of the radiance */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DebugMode {
+    /* Heatmaps of the BVH nodes visited and the primitives tested by camera rays */
+    BvhNodes,
+    Primitives,
+    /* Heatmap of the number of vertices of the paths */
+    PathDepth,
+    /* Heatmap of the samples taken for every pixel by a normal render */
+    Samples,
+    GeometricNormal,
+    ShadingNormal,
+    Uv,
+    /* Barycentric coordinates of triangles as red, green and blue */
+    Barycentric,
+}
+
+impl FromStr for DebugMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bvh" => Ok(DebugMode::BvhNodes),
+            "primitives" => Ok(DebugMode::Primitives),
+            "depth" => Ok(DebugMode::PathDepth),
+            "samples" => Ok(DebugMode::Samples),
+            "geometric-normal" => Ok(DebugMode::GeometricNormal),
+            "normal" => Ok(DebugMode::ShadingNormal),
+            "uv" => Ok(DebugMode::Uv),
+            "barycentric" => Ok(DebugMode::Barycentric),
+            _ => Err(format!("Unknown debug mode: {}", s)),
+        }
+    }
+}
+
+impl DebugMode {
+    /* Heatmaps render a count per pixel that is only mapped to colors once the image is done */
+    pub fn is_heatmap(&self) -> bool {
+        matches!(self, DebugMode::BvhNodes | DebugMode::Primitives | DebugMode::PathDepth | DebugMode::Samples)
+    }
+}
+
+/* Color given for display, made linear so it shows as is after the output transform */
+fn display(c: Vec3) -> Vec3 {
+    Vec3::new(srgb_eotf(c.x.clamp(0.0, 1.0)), srgb_eotf(c.y.clamp(0.0, 1.0)), srgb_eotf(c.z.clamp(0.0, 1.0)))
+}
+
+/*
+ * Shows a debug mode instead of the radiance, for heatmaps the count in
+ * every channel. The samples and path depth heatmaps render with the
+ * integrator they wrap, the other modes only trace camera rays.
+ */
+pub struct DebugIntegrator<'a> {
+    pub mode: DebugMode,
+    pub inner: Box<dyn Integrator + 'a>,
+}
+
+impl Integrator for DebugIntegrator<'_> {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler, aov: Option<&mut AovSample>) -> Vec3 {
+        match self.mode {
+            DebugMode::Samples => self.inner.li(ray, scene, sampler, aov),
+            DebugMode::PathDepth => {
+                let before = stats::get(Counter::PathVertices);
+                self.inner.li(ray, scene, sampler, aov);
+                Vec3::one() * (stats::get(Counter::PathVertices) - before) as f32
+            }
+            DebugMode::BvhNodes | DebugMode::Primitives => {
+                let counter = if self.mode == DebugMode::BvhNodes { Counter::BvhNodes } else { Counter::PrimitiveTests };
+                let before = stats::get(counter);
+                count(Counter::CameraRays, 1);
+                scene.world.hit(ray, T_MIN, T_MAX, sampler.rng());
+                Vec3::one() * (stats::get(counter) - before) as f32
+            }
+            _ => {
+                count(Counter::CameraRays, 1);
+                let rec = match scene.world.hit(ray, T_MIN, T_MAX, sampler.rng()) {
+                    Some(rec) => rec,
+                    None => return Vec3::zero(),
+                };
+                let (u, v) = rec.uv;
+                display(match self.mode {
+                    DebugMode::GeometricNormal => rec.ng * 0.5 + Vec3::one() * 0.5,
+                    DebugMode::ShadingNormal => rec.n * 0.5 + Vec3::one() * 0.5,
+                    DebugMode::Uv => Vec3::new(u, v, 0.0),
+                    _ => Vec3::new(1.0 - u - v, u, v),
+                })
+            }
+        }
+    }
+
+    /* Light tracing contributions are no counts, they are dropped so they don't pile up */
+    fn take_splats(&self) -> Vec<Splat> {
+        self.inner.take_splats();
+        Vec::new()
+    }
+}
+
+/* Turbo colormap from dark blue over green to dark red, Mikhailov's polynomial fit */
+fn turbo(t: f32) -> Vec3 {
+    let t = t.clamp(0.0, 1.0);
+    let poly = |c: [f32; 6]| c[0] + t * (c[1] + t * (c[2] + t * (c[3] + t * (c[4] + t * c[5]))));
+    Vec3::new(poly([0.1357214, 4.615393, -42.66032, 132.1311, -152.9424, 59.28638]),
+              poly([0.09140261, 2.194188, 4.842967, -14.18503, 4.277299, 2.829566]),
+              poly([0.1066733, 12.64195, -60.58205, 110.3628, -89.90311, 27.34825]))
+}
+
+/* False colors of counts scaled from zero to the largest one, which is returned along */
+pub fn heatmap(values: &[f32]) -> (Vec<Vec3>, f32) {
+    let max = values.iter().cloned().fold(0.0, f32::max);
+    let scale = if max > 0.0 { 1.0 / max } else { 0.0 };
+    (values.iter().map(|v| display(turbo(v * scale))).collect(), max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heatmap() {
+        let (colors, max) = heatmap(&[1.0, 5.0, 10.0]);
+        assert_eq!(max, 10.0);
+        /* Low counts are blue, the middle green and the top red */
+        assert!(colors[0].z > colors[0].x);
+        assert!(colors[1].y > colors[1].x && colors[1].y > colors[1].z);
+        assert!(colors[2].x > colors[2].z);
+        assert!(DebugMode::Samples.is_heatmap() && !DebugMode::Uv.is_heatmap());
+    }
+}
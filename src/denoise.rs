@@ -0,0 +1,276 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::aov::{AovFilm, AovKind};
+
+/*
+ * Image to denoise and the features of the first hits guiding it, all
+ * over the same pixels. The variance is that of the mean luminance of a
+ * pixel, None where too few samples were taken to tell.
+ */
+pub struct DenoiseInput<'a> {
+    pub w: usize,
+    pub h: usize,
+    pub color: &'a [Vec3],
+    pub albedo: Vec<Vec3>,
+    pub normal: Vec<Vec3>,
+    pub depth: Vec<f32>,
+    pub variance: Vec<Option<f32>>,
+}
+
+impl DenoiseInput<'_> {
+    /* The AOV film must have recorded the albedo, normal and depth */
+    pub fn new<'a>(w: usize, h: usize, color: &'a [Vec3], aovs: &AovFilm,
+                   variance: Vec<Option<f32>>) -> DenoiseInput<'a> {
+        let vectors = |kind| {
+            let planes = aovs.planes(kind);
+            (0..w * h).map(|i| Vec3::new(planes[0][i], planes[1][i], planes[2][i])).collect()
+        };
+        DenoiseInput {
+            w,
+            h,
+            color,
+            albedo: vectors(AovKind::Albedo),
+            normal: vectors(AovKind::Normal),
+            depth: aovs.planes(AovKind::Depth).swap_remove(0),
+            variance,
+        }
+    }
+
+    /*
+     * Albedo the color is divided by before filtering and multiplied with
+     * after it, so that textures stay sharp. Black channels are left alone.
+     */
+    fn demodulation(&self, i: usize) -> Vec3 {
+        let a = self.albedo[i];
+        let keep = |c: f32| if c > 1e-3 { c } else { 1.0 };
+        Vec3::new(keep(a.x), keep(a.y), keep(a.z))
+    }
+
+    /* Weight of pixel j as a neighbor of pixel i by how alike their surfaces are */
+    fn feature_weight(&self, i: usize, j: usize, normal_power: f32, depth_sigma: f32) -> f32 {
+        let (ni, nj) = (self.normal[i], self.normal[j]);
+        /* Pixels without a hit have no normal and only match each other */
+        let normal = if ni.len2() == 0.0 && nj.len2() == 0.0 {
+            1.0
+        } else {
+            ni.normalized().dot(nj.normalized()).max(0.0).powf(normal_power)
+        };
+        let (zi, zj) = (self.depth[i], self.depth[j]);
+        let depth = (-(zi - zj).abs() / (depth_sigma * zi.max(zj) + 1e-4)).exp();
+        normal * depth
+    }
+}
+
+pub trait Denoiser {
+    fn denoise(&self, input: &DenoiseInput) -> Vec<Vec3>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DenoiserKind {
+    Bilateral,
+    Atrous,
+}
+
+impl FromStr for DenoiserKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bilateral" => Ok(DenoiserKind::Bilateral),
+            "atrous" => Ok(DenoiserKind::Atrous),
+            _ => Err(format!("Unknown denoiser: {}", s)),
+        }
+    }
+}
+
+pub fn new_denoiser(kind: DenoiserKind) -> Box<dyn Denoiser> {
+    match kind {
+        DenoiserKind::Bilateral => Box::new(BilateralDenoiser { radius: 6, sigma_spatial: 3.0, sigma_albedo: 0.1 }),
+        DenoiserKind::Atrous => Box::new(AtrousDenoiser { iterations: 5, sigma_luminance: 4.0 }),
+    }
+}
+
+/*
+ * Joint bilateral filter over a square window, the range weights coming
+ * from the albedo, normal and depth of the pixels only, as their colors
+ * are too noisy to tell edges at low sample counts.
+ */
+pub struct BilateralDenoiser {
+    radius: i32,
+    sigma_spatial: f32,
+    sigma_albedo: f32,
+}
+
+impl Denoiser for BilateralDenoiser {
+    fn denoise(&self, input: &DenoiseInput) -> Vec<Vec3> {
+        let (w, h) = (input.w as i32, input.h as i32);
+        let mut out = vec![Vec3::zero(); input.color.len()];
+        for y in 0..h {
+            for x in 0..w {
+                let i = (x + y * w) as usize;
+                let mut sum = Vec3::zero();
+                let mut weights = 0.0;
+                for qy in (y - self.radius).max(0)..(y + self.radius + 1).min(h) {
+                    for qx in (x - self.radius).max(0)..(x + self.radius + 1).min(w) {
+                        let j = (qx + qy * w) as usize;
+                        let d2 = ((qx - x) * (qx - x) + (qy - y) * (qy - y)) as f32;
+                        let albedo = (input.albedo[i] - input.albedo[j]).len2();
+                        let weight = (-d2 / (2.0 * self.sigma_spatial * self.sigma_spatial)
+                                      - albedo / (2.0 * self.sigma_albedo * self.sigma_albedo)).exp()
+                                     * input.feature_weight(i, j, 32.0, 0.05);
+                        sum += input.color[j] * input.demodulation(j).recip() * weight;
+                        weights += weight;
+                    }
+                }
+                out[i] = sum / weights * input.demodulation(i);
+            }
+        }
+        out
+    }
+}
+
+/* B3 spline, the kernel of each à-trous iteration along both axes */
+const ATROUS_KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/*
+ * Edge-avoiding à-trous wavelet filter as in SVGF: a 5x5 kernel applied
+ * with doubling gaps between its taps. Besides the features, neighbors
+ * are weighted by how far their luminance is from the pixel's, relative
+ * to the standard deviation of its noise, which is filtered along.
+ */
+pub struct AtrousDenoiser {
+    iterations: u32,
+    sigma_luminance: f32,
+}
+
+impl AtrousDenoiser {
+    /*
+     * Variance of the demodulated luminance of every pixel, from its own
+     * samples where there are enough, otherwise from its 3x3 neighborhood.
+     */
+    fn initial_variance(&self, input: &DenoiseInput, color: &[Vec3]) -> Vec<f32> {
+        let (w, h) = (input.w as i32, input.h as i32);
+        (0..w * h).map(|i| {
+            let (x, y) = (i % w, i / w);
+            match input.variance[i as usize] {
+                Some(var) => var / input.demodulation(i as usize).luminance().powi(2),
+                None => {
+                    let (mut sum, mut sum2, mut n) = (0.0, 0.0, 0.0);
+                    for qy in (y - 1).max(0)..(y + 2).min(h) {
+                        for qx in (x - 1).max(0)..(x + 2).min(w) {
+                            let l = color[(qx + qy * w) as usize].luminance();
+                            sum += l;
+                            sum2 += l * l;
+                            n += 1.0;
+                        }
+                    }
+                    (sum2 / n - (sum / n) * (sum / n)).max(0.0)
+                }
+            }
+        }).collect()
+    }
+
+    /* 3x3 Gaussian blur of the variance, which is too noisy itself to stop at edges */
+    fn blur_variance(w: i32, h: i32, variance: &[f32]) -> Vec<f32> {
+        let kernel = [0.25, 0.5, 0.25];
+        (0..w * h).map(|i| {
+            let (x, y) = (i % w, i / w);
+            let (mut sum, mut weights) = (0.0, 0.0);
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (qx, qy) = (x + dx, y + dy);
+                    if qx < 0 || qy < 0 || qx >= w || qy >= h {
+                        continue;
+                    }
+                    let k = kernel[(dx + 1) as usize] * kernel[(dy + 1) as usize];
+                    sum += variance[(qx + qy * w) as usize] * k;
+                    weights += k;
+                }
+            }
+            sum / weights
+        }).collect()
+    }
+}
+
+impl Denoiser for AtrousDenoiser {
+    fn denoise(&self, input: &DenoiseInput) -> Vec<Vec3> {
+        let (w, h) = (input.w as i32, input.h as i32);
+        let mut color: Vec<Vec3> = input.color.iter().enumerate()
+                                        .map(|(i, c)| *c * input.demodulation(i).recip())
+                                        .collect();
+        let mut variance = self.initial_variance(input, &color);
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let blurred = Self::blur_variance(w, h, &variance);
+            let mut next_color = vec![Vec3::zero(); color.len()];
+            let mut next_variance = vec![0.0; variance.len()];
+            for y in 0..h {
+                for x in 0..w {
+                    let i = (x + y * w) as usize;
+                    let luminance = color[i].luminance();
+                    let spread = self.sigma_luminance * blurred[i].sqrt() + 1e-4;
+                    let (mut sum, mut sum_variance, mut weights) = (Vec3::zero(), 0.0, 0.0);
+                    for (ky, k_y) in ATROUS_KERNEL.iter().enumerate() {
+                        for (kx, k_x) in ATROUS_KERNEL.iter().enumerate() {
+                            let qx = x + (kx as i32 - 2) * step;
+                            let qy = y + (ky as i32 - 2) * step;
+                            if qx < 0 || qy < 0 || qx >= w || qy >= h {
+                                continue;
+                            }
+                            let j = (qx + qy * w) as usize;
+                            let weight = k_x * k_y
+                                         * input.feature_weight(i, j, 128.0, 0.1 * step as f32)
+                                         * (-(luminance - color[j].luminance()).abs() / spread).exp();
+                            sum += color[j] * weight;
+                            sum_variance += variance[j] * weight * weight;
+                            weights += weight;
+                        }
+                    }
+                    next_color[i] = sum / weights;
+                    next_variance[i] = sum_variance / (weights * weights);
+                }
+            }
+            color = next_color;
+            variance = next_variance;
+        }
+
+        color.iter().enumerate().map(|(i, c)| *c * input.demodulation(i)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::RNG;
+
+    /* Noise on a flat surface is smoothed away, the edge to a surface facing elsewhere stays */
+    #[test]
+    fn test_denoisers() {
+        let (w, h) = (16, 16);
+        let mut rng = RNG::with_seed(7);
+        let left = |i: usize| i % w < w / 2;
+        let color: Vec<Vec3> = (0..w * h).map(|i| {
+            let base = if left(i) { 0.2 } else { 0.8 };
+            Vec3::one() * (base + 0.2 * (rng.sample_01() - 0.5))
+        }).collect();
+        let input = DenoiseInput {
+            w,
+            h,
+            color: &color,
+            albedo: vec![Vec3::one(); w * h],
+            normal: (0..w * h).map(|i| if left(i) { Vec3::new(1.0, 0.0, 0.0) } else { Vec3::new(0.0, 1.0, 0.0) }).collect(),
+            depth: vec![1.0; w * h],
+            variance: vec![None; w * h],
+        };
+
+        for kind in [DenoiserKind::Bilateral, DenoiserKind::Atrous] {
+            let out = new_denoiser(kind).denoise(&input);
+            let error = |image: &[Vec3]| (0..w * h).map(|i| {
+                let base = if left(i) { 0.2 } else { 0.8 };
+                (image[i].x - base).abs()
+            }).fold(0.0, f32::max);
+            assert!(error(&out) < 0.5 * error(&color), "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+/* Piecewise constant 1D distribution over [0, 1) */
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    func_int: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f32]) -> Distribution1D {
+        let n = func.len();
+        let func: Vec<f32> = func.iter().map(|f| f.abs()).collect();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f32;
+        }
+
+        let func_int = cdf[n];
+        if func_int == 0.0 {
+            /* Degenerate function, fall back to uniform */
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f32 / n as f32;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= func_int;
+            }
+        }
+
+        Distribution1D { func, cdf, func_int }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.func_int
+    }
+
+    fn find_interval(&self, u: f32) -> usize {
+        /* Last index with cdf[i] <= u */
+        let i = self.cdf.partition_point(|&c| c <= u);
+        i.saturating_sub(1).min(self.count() - 1)
+    }
+
+    /* Probability of sampling bucket i */
+    pub fn pmf(&self, i: usize) -> f32 {
+        if self.func_int > 0.0 {
+            self.func[i] / (self.func_int * self.count() as f32)
+        } else {
+            1.0 / self.count() as f32
+        }
+    }
+
+    /* Returns the sampled bucket and its probability */
+    pub fn sample_discrete(&self, u: f32) -> (usize, f32) {
+        let offset = self.find_interval(u);
+        (offset, self.pmf(offset))
+    }
+
+    /* Returns the sampled point, its density and the bucket it is in */
+    pub fn sample_continuous(&self, u: f32) -> (f32, f32, usize) {
+        let offset = self.find_interval(u);
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let pdf = if self.func_int > 0.0 { self.func[offset] / self.func_int } else { 1.0 };
+        let x = ((offset as f32 + du) / self.count() as f32).min(1.0 - f32::EPSILON / 2.0);
+        (x, pdf, offset)
+    }
+}
+
+/* Piecewise constant 2D distribution over [0, 1)^2, rows along v */
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], nu: usize, nv: usize) -> Distribution2D {
+        let conditional: Vec<Distribution1D> = (0..nv)
+            .map(|v| Distribution1D::new(&func[v * nu..(v + 1) * nu]))
+            .collect();
+        let marginal_func: Vec<f32> = conditional.iter().map(|d| d.integral()).collect();
+        let marginal = Distribution1D::new(&marginal_func);
+        Distribution2D { conditional, marginal }
+    }
+
+    pub fn sample_continuous(&self, u: (f32, f32)) -> ((f32, f32), f32) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u.1);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u.0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, p: (f32, f32)) -> f32 {
+        let nu = self.conditional[0].count();
+        let nv = self.marginal.count();
+        let iu = ((p.0 * nu as f32) as usize).min(nu - 1);
+        let iv = ((p.1 * nv as f32) as usize).min(nv - 1);
+        if self.marginal.integral() == 0.0 {
+            return 1.0;
+        }
+        self.conditional[iv].func[iu] / self.marginal.integral()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_1d_sampling() {
+        let d = Distribution1D::new(&[1.0, 3.0, 0.0, 4.0]);
+        assert_eq!(d.integral(), 2.0);
+
+        let (x, pdf, offset) = d.sample_continuous(0.05);
+        assert_eq!(offset, 0);
+        assert!(x < 0.25);
+        assert!((pdf - 0.5).abs() < 1e-6);
+
+        /* The zero bucket can never be picked */
+        for i in 0..100 {
+            let (_, _, offset) = d.sample_continuous(i as f32 / 100.0);
+            assert_ne!(offset, 2);
+        }
+
+        let (offset, pmf) = d.sample_discrete(0.3);
+        assert_eq!(offset, 1);
+        assert!((pmf - 0.375).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_2d_pdf_matches_sample() {
+        let func = [1.0, 2.0, 3.0, 4.0, 0.5, 0.0];
+        let d = Distribution2D::new(&func, 3, 2);
+        for i in 0..10 {
+            for j in 0..10 {
+                let u = (i as f32 / 10.0 + 0.05, j as f32 / 10.0 + 0.05);
+                let (p, pdf) = d.sample_continuous(u);
+                assert!((d.pdf(p) - pdf).abs() < 1e-4);
+            }
+        }
+    }
+}
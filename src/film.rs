@@ -0,0 +1,334 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::checkpoint::{CheckpointError, Decoder, Encoder};
+use crate::filter::Filter;
+
+/* Running mean and variance of the samples of one pixel (Welford) */
+#[derive(Copy, Clone, Debug)]
+pub struct PixelStats {
+    pub mean: Vec3,
+    /* Sum of squared luminance differences from the mean */
+    pub m2: f32,
+    pub n: u32,
+}
+
+impl PixelStats {
+    pub fn new() -> PixelStats {
+        PixelStats { mean: Vec3::zero(), m2: 0.0, n: 0 }
+    }
+
+    pub fn add(&mut self, sample: Vec3) {
+        let old_lum = self.mean.luminance();
+        self.n += 1;
+        self.mean += (sample - self.mean) / self.n as f32;
+        self.m2 += (sample.luminance() - old_lum) * (sample.luminance() - self.mean.luminance());
+    }
+
+    /* Sample variance of the luminance */
+    pub fn variance(&self) -> f32 {
+        if self.n < 2 {
+            return 0.0;
+        }
+        self.m2 / (self.n - 1) as f32
+    }
+
+    /* Standard error of the mean luminance relative to the mean itself */
+    pub fn relative_error(&self) -> f32 {
+        if self.n < 2 {
+            return f32::INFINITY;
+        }
+        let std_err = (self.variance() / self.n as f32).sqrt();
+        std_err / (self.mean.luminance() + 1e-3)
+    }
+}
+
+/*
+ * Crop window as fractions of the image, x0,x1,y0,y1 on the command line
+ * with y0 = 0 at the top. Pixels are cropped the way pbrt does, rounding
+ * both edges up.
+ */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CropWindow {
+    pub x0: f32,
+    pub x1: f32,
+    pub y0: f32,
+    pub y1: f32,
+}
+
+impl Default for CropWindow {
+    fn default() -> CropWindow {
+        CropWindow { x0: 0.0, x1: 1.0, y0: 0.0, y1: 1.0 }
+    }
+}
+
+impl FromStr for CropWindow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<f32> = match s.split(',').map(|v| v.parse()).collect() {
+            Ok(values) => values,
+            Err(_) => return Err(format!("Invalid crop window: {}", s)),
+        };
+        match values.as_slice() {
+            [x0, x1, y0, y1] if 0.0 <= *x0 && x0 < x1 && *x1 <= 1.0 && 0.0 <= *y0 && y0 < y1 && *y1 <= 1.0 =>
+                Ok(CropWindow { x0: *x0, x1: *x1, y0: *y0, y1: *y1 }),
+            _ => Err(format!("Invalid crop window: {}", s)),
+        }
+    }
+}
+
+/* Half open range of pixels [x0, x1) by [y0, y1) */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PixelBounds {
+    pub x0: usize,
+    pub x1: usize,
+    pub y0: usize,
+    pub y1: usize,
+}
+
+impl PixelBounds {
+    pub fn w(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn h(&self) -> usize {
+        self.y1 - self.y0
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        (self.x0..self.x1).contains(&x) && (self.y0..self.y1).contains(&y)
+    }
+}
+
+/* Light reaching a film position from a path traced from the lights, with y going down */
+#[derive(Copy, Clone, Debug)]
+pub struct Splat {
+    pub x: f32,
+    pub y: f32,
+    pub value: Vec3,
+}
+
+/* Filtered radiance of a pixel, the sums of weighted samples and weights */
+#[derive(Copy, Clone, Debug)]
+struct FilmPixel {
+    sum: Vec3,
+    weight: f32,
+}
+
+/*
+ * Accumulation buffer for the cropped part of the image. Every sample is
+ * splatted onto the pixels within the radius of the reconstruction filter,
+ * weighted by it. Alongside, the unfiltered samples taken for each pixel
+ * are tracked for adaptive sampling and the sample count map. Paths
+ * traced from the lights add to the pixel they land on without filtering,
+ * and so do estimates made for a pixel as a whole.
+ */
+pub struct Film {
+    /* Resolution of the whole image */
+    pub w: usize,
+    pub h: usize,
+    pub crop: PixelBounds,
+    filter: Box<dyn Filter>,
+    pixels: Vec<FilmPixel>,
+    stats: Vec<PixelStats>,
+    splats: Vec<Vec3>,
+    /* Number of paths traced from the lights, over the whole image */
+    light_paths: u64,
+    /* Left out of checkpoints, the photon mapper making them can't be resumed */
+    estimates: Vec<Vec3>,
+}
+
+impl Film {
+    pub fn new(w: usize, h: usize, crop: CropWindow, filter: Box<dyn Filter>) -> Film {
+        let edge = |f: f32, n: usize| ((f * n as f32).ceil() as usize).min(n);
+        let mut crop = PixelBounds { x0: edge(crop.x0, w), x1: edge(crop.x1, w),
+                                     y0: edge(crop.y0, h), y1: edge(crop.y1, h) };
+        /* Tiny windows still get a pixel */
+        crop.x1 = crop.x1.max((crop.x0 + 1).min(w));
+        crop.y1 = crop.y1.max((crop.y0 + 1).min(h));
+        crop.x0 = crop.x0.min(crop.x1 - 1);
+        crop.y0 = crop.y0.min(crop.y1 - 1);
+
+        let n = crop.w() * crop.h();
+        Film { w, h, crop, filter,
+               pixels: vec![FilmPixel { sum: Vec3::zero(), weight: 0.0 }; n],
+               stats: vec![PixelStats::new(); n],
+               splats: vec![Vec3::zero(); n],
+               light_paths: 0,
+               estimates: vec![Vec3::zero(); n] }
+    }
+
+    /* Pixels whose samples can reach into the crop window through the filter */
+    pub fn sample_bounds(&self) -> PixelBounds {
+        let r = (self.filter.radius() - 0.5).max(0.0).ceil() as usize;
+        PixelBounds { x0: self.crop.x0.saturating_sub(r), x1: (self.crop.x1 + r).min(self.w),
+                      y0: self.crop.y0.saturating_sub(r), y1: (self.crop.y1 + r).min(self.h) }
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (x - self.crop.x0) + (y - self.crop.y0) * self.crop.w()
+    }
+
+    /*
+     * Adds a sample taken for pixel x, y at the offset jitter within it,
+     * with y going down the image.
+     */
+    pub fn add_sample(&mut self, x: usize, y: usize, jitter: (f32, f32), radiance: Vec3) {
+        if self.crop.contains(x, y) {
+            let i = self.index(x, y);
+            self.stats[i].add(radiance);
+        }
+
+        let (px, py) = (x as f32 + jitter.0, y as f32 + jitter.1);
+        let r = self.filter.radius();
+        let x0 = ((px - 0.5 - r).ceil().max(self.crop.x0 as f32)) as usize;
+        let x1 = ((px - 0.5 + r).floor() + 1.0).clamp(0.0, self.crop.x1 as f32) as usize;
+        let y0 = ((py - 0.5 - r).ceil().max(self.crop.y0 as f32)) as usize;
+        let y1 = ((py - 0.5 + r).floor() + 1.0).clamp(0.0, self.crop.y1 as f32) as usize;
+        for fy in y0..y1 {
+            for fx in x0..x1 {
+                let weight = self.filter.eval(fx as f32 + 0.5 - px, fy as f32 + 0.5 - py);
+                if weight == 0.0 {
+                    continue;
+                }
+                let i = self.index(fx, fy);
+                self.pixels[i].sum += radiance * weight;
+                self.pixels[i].weight += weight;
+            }
+        }
+    }
+
+    /* Adds the splats of one path traced from the lights, those outside of the crop only count as a path */
+    pub fn add_splats(&mut self, splats: &[Splat]) {
+        self.light_paths += 1;
+        for splat in splats {
+            let (x, y) = (splat.x as usize, splat.y as usize);
+            if splat.x >= 0.0 && splat.y >= 0.0 && self.crop.contains(x, y) {
+                let i = self.index(x, y);
+                self.splats[i] += splat.value;
+            }
+        }
+    }
+
+    /* Adds radiance estimated for pixel x, y as a whole, like the photons gathered for it, ignored outside of the crop */
+    pub fn add_estimate(&mut self, x: usize, y: usize, radiance: Vec3) {
+        if self.crop.contains(x, y) {
+            let i = self.index(x, y);
+            self.estimates[i] += radiance;
+        }
+    }
+
+    /* Statistics of the samples taken for a pixel of the crop window */
+    pub fn pixel(&self, x: usize, y: usize) -> Option<&PixelStats> {
+        if self.crop.contains(x, y) { Some(&self.stats[self.index(x, y)]) } else { None }
+    }
+
+    /*
+     * Filtered image of the crop window, black where the weights don't add
+     * up to anything. Splats are spread over all light paths as these land
+     * anywhere on the image, so the scale is by the whole image even when only
+     * the crop window was sampled: the paths launched for it still cover all
+     * of the image, the ones landing outside the crop are just dropped.
+     */
+    pub fn image(&self) -> Vec<Vec3> {
+        let splat_scale = if self.light_paths > 0 { (self.w * self.h) as f32 / self.light_paths as f32 } else { 0.0 };
+        self.pixels.iter().zip(&self.splats).zip(&self.estimates)
+            .map(|((p, splat), estimate)| {
+                let filtered = if p.weight > 0.0 { p.sum / p.weight } else { Vec3::zero() };
+                filtered + *splat * splat_scale + *estimate
+            })
+            .collect()
+    }
+
+    pub fn weights(&self) -> Vec<f32> {
+        self.pixels.iter().map(|p| p.weight).collect()
+    }
+
+    /* Variance of the mean luminance of every pixel, None with fewer than two samples */
+    pub fn mean_variances(&self) -> Vec<Option<f32>> {
+        self.stats.iter().map(|p| if p.n < 2 { None } else { Some(p.variance() / p.n as f32) }).collect()
+    }
+
+    pub fn sample_counts(&self) -> Vec<f32> {
+        self.stats.iter().map(|p| p.n as f32).collect()
+    }
+
+    pub fn encode(&self, out: &mut Encoder) {
+        for v in [self.w, self.h, self.crop.x0, self.crop.x1, self.crop.y0, self.crop.y1] {
+            out.u64(v as u64);
+        }
+        for ((pixel, stats), splat) in self.pixels.iter().zip(&self.stats).zip(&self.splats) {
+            out.vec3(pixel.sum);
+            out.f32(pixel.weight);
+            out.vec3(stats.mean);
+            out.f32(stats.m2);
+            out.u32(stats.n);
+            out.vec3(*splat);
+        }
+        out.u64(self.light_paths);
+    }
+
+    /* Restores the accumulated samples, the image and crop window must be the same */
+    pub fn decode(&mut self, input: &mut Decoder) -> Result<(), CheckpointError> {
+        input.expect("width", self.w as u64)?;
+        input.expect("height", self.h as u64)?;
+        for v in [self.crop.x0, self.crop.x1, self.crop.y0, self.crop.y1] {
+            input.expect("crop window", v as u64)?;
+        }
+        for ((pixel, stats), splat) in self.pixels.iter_mut().zip(self.stats.iter_mut()).zip(self.splats.iter_mut()) {
+            pixel.sum = input.vec3()?;
+            pixel.weight = input.f32()?;
+            stats.mean = input.vec3()?;
+            stats.m2 = input.f32()?;
+            stats.n = input.u32()?;
+            *splat = input.vec3()?;
+        }
+        self.light_paths = input.u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{new_filter, FilterKind};
+
+    #[test]
+    fn test_running_variance() {
+        let samples = [1.0, 2.0, 4.0, 7.0];
+        let mut stats = PixelStats::new();
+        for s in samples.iter() {
+            stats.add(Vec3::one() * *s);
+        }
+
+        let mean = samples.iter().sum::<f32>() / 4.0;
+        let var = samples.iter().map(|s| (s - mean) * (s - mean)).sum::<f32>() / 3.0;
+
+        assert!((stats.mean.x - mean).abs() < 1e-5);
+        assert!((stats.variance() - var).abs() < 1e-4);
+        assert_eq!(stats.n, 4);
+    }
+
+    /* A constant image stays constant under any filter, also at the crop edges */
+    #[test]
+    fn test_film_splatting() {
+        let crop: CropWindow = "0.25,0.75,0,0.5".parse().unwrap();
+        let mut film = Film::new(8, 8, crop, new_filter(FilterKind::Mitchell, None));
+        assert_eq!(film.crop, PixelBounds { x0: 2, x1: 6, y0: 0, y1: 4 });
+
+        let bounds = film.sample_bounds();
+        for y in bounds.y0..bounds.y1 {
+            for x in bounds.x0..bounds.x1 {
+                for j in 0..4 {
+                    let jitter = ((j % 2) as f32 * 0.5 + 0.25, (j / 2) as f32 * 0.5 + 0.25);
+                    film.add_sample(x, y, jitter, Vec3::one() * 0.5);
+                }
+            }
+        }
+        assert_eq!(film.image().len(), 16);
+        assert!(film.image().iter().all(|p| (p.x - 0.5).abs() < 1e-4));
+        assert!(film.weights().iter().all(|w| *w > 0.0));
+        assert_eq!(film.pixel(2, 3).unwrap().n, 4);
+        assert!(film.pixel(1, 3).is_none());
+    }
+}
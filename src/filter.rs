@@ -0,0 +1,201 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+/*
+ * Pixel reconstruction filter. Offsets are in pixels from the pixel
+ * center, the filter is zero outside of [-radius, radius] on both axes.
+ * Weights may be negative, the film normalizes by their sum.
+ */
+pub trait Filter {
+    fn radius(&self) -> f32;
+    fn eval(&self, x: f32, y: f32) -> f32;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" | "triangle" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(format!("Unknown filter: {}", s)),
+        }
+    }
+}
+
+impl FilterKind {
+    pub fn default_radius(&self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+pub fn new_filter(kind: FilterKind, radius: Option<f32>) -> Box<dyn Filter> {
+    let radius = radius.unwrap_or(kind.default_radius());
+    match kind {
+        FilterKind::Box => Box::new(BoxFilter { radius }),
+        FilterKind::Tent => Box::new(TentFilter { radius }),
+        FilterKind::Gaussian => Box::new(GaussianFilter::new(radius, radius / 3.0)),
+        FilterKind::Mitchell => Box::new(MitchellFilter { radius, b: 1.0 / 3.0, c: 1.0 / 3.0 }),
+        FilterKind::Lanczos => Box::new(LanczosFilter { radius }),
+    }
+}
+
+pub struct BoxFilter {
+    radius: f32,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn eval(&self, x: f32, y: f32) -> f32 {
+        if x.abs() <= self.radius && y.abs() <= self.radius { 1.0 } else { 0.0 }
+    }
+}
+
+pub struct TentFilter {
+    radius: f32,
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn eval(&self, x: f32, y: f32) -> f32 {
+        (self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+    }
+}
+
+/* Gaussian shifted down to reach zero at the radius */
+pub struct GaussianFilter {
+    radius: f32,
+    sigma: f32,
+    edge: f32,
+}
+
+impl GaussianFilter {
+    pub fn new(radius: f32, sigma: f32) -> GaussianFilter {
+        let edge = (-radius * radius / (2.0 * sigma * sigma)).exp();
+        GaussianFilter { radius, sigma, edge }
+    }
+
+    fn gaussian(&self, x: f32) -> f32 {
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        ((-x * x / (2.0 * self.sigma * self.sigma)).exp() - self.edge).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn eval(&self, x: f32, y: f32) -> f32 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+/* Mitchell-Netravali cubic, B = C = 1/3 by default, with slightly negative lobes */
+pub struct MitchellFilter {
+    radius: f32,
+    b: f32,
+    c: f32,
+}
+
+impl MitchellFilter {
+    /* The cubic over [-2, 2] */
+    fn mitchell(&self, x: f32) -> f32 {
+        let (b, c) = (self.b, self.c);
+        let x = x.abs();
+        if x < 1.0 {
+            ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+             + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+             + (6.0 - 2.0 * b)) / 6.0
+        } else if x < 2.0 {
+            ((-b - 6.0 * c) * x * x * x
+             + (6.0 * b + 30.0 * c) * x * x
+             + (-12.0 * b - 48.0 * c) * x
+             + (8.0 * b + 24.0 * c)) / 6.0
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn eval(&self, x: f32, y: f32) -> f32 {
+        self.mitchell(2.0 * x / self.radius) * self.mitchell(2.0 * y / self.radius)
+    }
+}
+
+/* Sinc windowed by a wider sinc that reaches its first zero at the radius */
+pub struct LanczosFilter {
+    radius: f32,
+}
+
+impl LanczosFilter {
+    fn lanczos(&self, x: f32) -> f32 {
+        let sinc = |x: f32| if x.abs() < 1e-5 { 1.0 } else { (PI * x).sin() / (PI * x) };
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.radius)
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    fn eval(&self, x: f32, y: f32) -> f32 {
+        self.lanczos(x) * self.lanczos(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        for kind in [FilterKind::Box, FilterKind::Tent, FilterKind::Gaussian,
+                     FilterKind::Mitchell, FilterKind::Lanczos] {
+            let filter = new_filter(kind, None);
+            let r = filter.radius();
+            assert!(filter.eval(0.0, 0.0) > 0.0);
+            assert_eq!(filter.eval(r + 0.01, 0.0), 0.0);
+            assert!((filter.eval(0.3, -0.2) - filter.eval(-0.3, 0.2)).abs() < 1e-6);
+        }
+
+        /* Mitchell with B = C = 1/3 is 8/9 at the center and dips below zero */
+        let mitchell = new_filter(FilterKind::Mitchell, None);
+        assert!((mitchell.eval(0.0, 0.0) - (8.0f32 / 9.0).powi(2)).abs() < 1e-5);
+        assert!(mitchell.eval(1.5, 0.0) < 0.0);
+    }
+}
@@ -3,32 +3,50 @@ use crate::Ray;
 use crate::material::Material;
 use crate::rng::RNG;
 use crate::aabb::AABB;
+use crate::sphere::Sphere;
+use crate::tri::Tri;
 
 #[derive(Copy, Clone)]
 pub struct HitRecord<'a> {
     pub p: Vec3,
+    /* Shading normal, and the normal of the actual surface, both facing the side of the ray */
     pub n: Vec3,
+    pub ng: Vec3,
+    /*
+     * Surface coordinates of the hit, the polar angles over [0, 1] on
+     * spheres and the barycentric coordinates of the second and third
+     * vertex on triangles
+     */
+    pub uv: (f32, f32),
     pub mat: &'a dyn Material,
     pub t: f32,
     pub front_face: bool,
+    /* Number of the scene object that was hit, zero if it isn't tagged */
+    pub object: u32,
 }
 
 impl HitRecord<'_> {
-    pub fn new<'a>(p: Vec3, out_normal: Vec3, t: f32, ray: &Ray,
-                   material: &'a dyn Material) -> HitRecord<'a> {
+    pub fn new<'a>(p: Vec3, out_normal: Vec3, t: f32, ray: &Ray, material: &'a dyn Material,
+                   uv: (f32, f32)) -> HitRecord<'a> {
         let front_face = ray.dir.dot(out_normal) < 0.0;
+        let n = if front_face { out_normal } else { -out_normal };
         HitRecord {
-            p: p,
-            n: if front_face { out_normal } else { -out_normal },
-            t: t,
+            p,
+            n,
+            ng: n,
+            uv,
+            t,
             mat: material,
-            front_face: front_face,
+            front_face,
+            object: 0,
         }
     }
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord>;
+    /* Intersection at the time of the ray */
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord<'_>>;
+    /* Bounds over every time the object can be hit at, so the BVH holds moving objects too */
     fn get_aabb(&self) -> Option<AABB>;
 }
 
@@ -37,28 +55,57 @@ pub struct HittableList<'a> {
 }
 
 impl Hittable for HittableList<'_> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord<'_>> {
         fn hit_ord(hit_a: &HitRecord, hit_b: &HitRecord) -> std::cmp::Ordering {
             if hit_a.t == hit_b.t {
-                return std::cmp::Ordering::Equal;
+                std::cmp::Ordering::Equal
             } else if hit_a.t > hit_b.t {
-                return std::cmp::Ordering::Greater;
+                std::cmp::Ordering::Greater
             } else {
-                return std::cmp::Ordering::Less;
+                std::cmp::Ordering::Less
             }
         }
 
-        let iter = (&self.hittables).into_iter();
+        let iter = self.hittables.iter();
         /* Call Hittable::hit on each an filter out Nones */
         let mut res = iter.filter_map(|item| item.hit(ray, t_min, t_max, rng)).peekable();
-        if res.peek().is_none() {
-            return None;
-        }
+        res.peek()?;
         let min = res.min_by(hit_ord).unwrap();
-        return Some(min);
+        Some(min)
     }
 
     fn get_aabb(&self) -> Option<AABB> {
         AABB::union(&self.hittables)
     }
 }
+
+/* Primitive counted as part of a numbered object, for the object ID output */
+pub struct Tagged<'a> {
+    pub id: u32,
+    pub object: &'a dyn Hittable,
+}
+
+impl Hittable for Tagged<'_> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord<'_>> {
+        let mut rec = self.object.hit(ray, t_min, t_max, rng)?;
+        rec.object = self.id;
+        Some(rec)
+    }
+
+    fn get_aabb(&self) -> Option<AABB> {
+        self.object.get_aabb()
+    }
+}
+
+/* Primitives owned outside of the hardcoded scene, e.g. built from the command line */
+pub struct Shapes<'a> {
+    pub spheres: Vec<Sphere<'a>>,
+    pub tris: Vec<Tri<'a>>,
+}
+
+impl Shapes<'_> {
+    pub fn hittables(&self) -> impl Iterator<Item = &dyn Hittable> {
+        let spheres = self.spheres.iter().map(|s| s as &dyn Hittable);
+        spheres.chain(self.tris.iter().map(|t| t as &dyn Hittable))
+    }
+}
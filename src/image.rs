@@ -0,0 +1,404 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use crate::Vec3;
+
+/* Linear float image, row 0 is the top row */
+pub struct Image {
+    pub w: usize,
+    pub h: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(why) => write!(f, "{}", why),
+            ImageError::Format(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl From<io::Error> for ImageError {
+    fn from(why: io::Error) -> ImageError {
+        ImageError::Io(why)
+    }
+}
+
+impl Image {
+    pub fn get(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[x + y * self.w]
+    }
+
+    /* Loads a Radiance .hdr or a .pfm image based on the file extension */
+    pub fn load(path: &str) -> Result<Image, ImageError> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        if path.ends_with(".pfm") {
+            parse_pfm(&data)
+        } else if path.ends_with(".hdr") || path.ends_with(".pic") {
+            parse_hdr(&data)
+        } else {
+            Err(ImageError::Format(format!("Unsupported image format: {}", path)))
+        }
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn line(&mut self) -> Result<&str, ImageError> {
+        let start = self.pos;
+        while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+            self.pos += 1;
+        }
+        if self.pos >= self.data.len() {
+            return Err(ImageError::Format("Unexpected end of header".to_string()));
+        }
+        self.pos += 1;
+        match std::str::from_utf8(&self.data[start..self.pos - 1]) {
+            Ok(line) => Ok(line.trim()),
+            Err(_) => Err(ImageError::Format("Invalid header".to_string())),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, ImageError> {
+        match self.data.get(self.pos) {
+            Some(b) => {
+                self.pos += 1;
+                Ok(*b)
+            }
+            None => Err(ImageError::Format("Unexpected end of data".to_string())),
+        }
+    }
+}
+
+/* Sizes of an image that isn't empty and whose pixels can be counted in bytes, up to 12 each */
+fn parse_dims(fields: &[&str]) -> Result<(usize, usize), ImageError> {
+    let parse = |s: &str| match s.parse::<usize>() {
+        Ok(v) if v > 0 => Ok(v),
+        _ => Err(ImageError::Format(format!("Invalid image size: {}", s))),
+    };
+    let (a, b) = (parse(fields[0])?, parse(fields[1])?);
+    match a.checked_mul(b).and_then(|n| n.checked_mul(12)) {
+        Some(_) => Ok((a, b)),
+        None => Err(ImageError::Format(format!("Image too large: {} by {}", fields[0], fields[1]))),
+    }
+}
+
+fn rgbe_to_vec3(rgbe: [u8; 4]) -> Vec3 {
+    if rgbe[3] == 0 {
+        return Vec3::zero();
+    }
+    let f = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+    Vec3::new(rgbe[0] as f32 * f, rgbe[1] as f32 * f, rgbe[2] as f32 * f)
+}
+
+/* Radiance RGBE, flat or with new style run length encoded scanlines */
+fn parse_hdr(data: &[u8]) -> Result<Image, ImageError> {
+    let mut cur = Cursor { data, pos: 0 };
+
+    let magic = cur.line()?;
+    if !magic.starts_with("#?") {
+        return Err(ImageError::Format("Not a Radiance HDR file".to_string()));
+    }
+    loop {
+        let line = cur.line()?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(ImageError::Format(format!("Unsupported {}", line)));
+        }
+    }
+
+    let res: Vec<&str> = cur.line()?.split_whitespace().collect();
+    if res.len() != 4 || res[0] != "-Y" || res[2] != "+X" {
+        return Err(ImageError::Format("Unsupported HDR orientation".to_string()));
+    }
+    let (h, w) = parse_dims(&[res[1], res[3]])?;
+
+    let mut pixels = Vec::with_capacity(w * h);
+    let mut scanline = vec![[0u8; 4]; w];
+    for _ in 0..h {
+        let start = [cur.byte()?, cur.byte()?, cur.byte()?, cur.byte()?];
+        let is_rle = (8..0x8000).contains(&w) && start[0] == 2 && start[1] == 2 && start[2] & 0x80 == 0;
+
+        if is_rle {
+            if ((start[2] as usize) << 8 | start[3] as usize) != w {
+                return Err(ImageError::Format("Bad scanline width".to_string()));
+            }
+            for c in 0..4 {
+                let mut x = 0;
+                while x < w {
+                    let count = cur.byte()? as usize;
+                    if count > 128 {
+                        let count = count - 128;
+                        let value = cur.byte()?;
+                        if x + count > w {
+                            return Err(ImageError::Format("Bad run length".to_string()));
+                        }
+                        for px in &mut scanline[x..x + count] {
+                            px[c] = value;
+                        }
+                        x += count;
+                    } else {
+                        if count == 0 || x + count > w {
+                            return Err(ImageError::Format("Bad run length".to_string()));
+                        }
+                        for px in &mut scanline[x..x + count] {
+                            px[c] = cur.byte()?;
+                        }
+                        x += count;
+                    }
+                }
+            }
+        } else {
+            scanline[0] = start;
+            for px in scanline.iter_mut().skip(1) {
+                *px = [cur.byte()?, cur.byte()?, cur.byte()?, cur.byte()?];
+            }
+        }
+
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_vec3(rgbe)));
+    }
+
+    Ok(Image { w, h, pixels })
+}
+
+/* Portable Float Map, either three channel (PF) or grayscale (Pf) */
+fn parse_pfm(data: &[u8]) -> Result<Image, ImageError> {
+    let mut cur = Cursor { data, pos: 0 };
+
+    let channels = match cur.line()? {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(ImageError::Format("Not a PFM file".to_string())),
+    };
+    let dims: Vec<&str> = cur.line()?.split_whitespace().collect();
+    if dims.len() != 2 {
+        return Err(ImageError::Format("Invalid PFM size".to_string()));
+    }
+    let (w, h) = parse_dims(&dims)?;
+    let scale = match cur.line()?.parse::<f32>() {
+        Ok(scale) => scale,
+        Err(_) => return Err(ImageError::Format("Invalid PFM scale".to_string())),
+    };
+    let little_endian = scale < 0.0;
+
+    let body = &data[cur.pos..];
+    let size = w.checked_mul(h).and_then(|n| n.checked_mul(channels * 4));
+    if size.is_none_or(|size| body.len() < size) {
+        return Err(ImageError::Format("Truncated PFM data".to_string()));
+    }
+    let value = |i: usize| {
+        let bytes = [body[i * 4], body[i * 4 + 1], body[i * 4 + 2], body[i * 4 + 3]];
+        if little_endian { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) }
+    };
+
+    let mut pixels = vec![Vec3::zero(); w * h];
+    for y in 0..h {
+        /* Rows are stored bottom to top */
+        let row = h - 1 - y;
+        for x in 0..w {
+            let i = (row * w + x) * channels;
+            pixels[x + y * w] = if channels == 3 {
+                Vec3::new(value(i), value(i + 1), value(i + 2))
+            } else {
+                Vec3::one() * value(i)
+            };
+        }
+    }
+
+    Ok(Image { w, h, pixels })
+}
+
+/* RGB Portable Float Map, little endian, rows stored bottom to top */
+pub fn write_pfm(path: &str, w: usize, h: usize, pixels: &[Vec3]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "PF\n{} {}\n-1.0\n", w, h)?;
+    for y in (0..h).rev() {
+        for p in &pixels[y * w..(y + 1) * w] {
+            for v in [p.x, p.y, p.z] {
+                file.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
+
+/* Plain text PPM of 8 bit pixels, rows from the top */
+pub fn write_ppm(out: &mut dyn Write, w: usize, h: usize, pixels: &[[u8; 3]]) -> io::Result<()> {
+    writeln!(out, "P3")?;
+    writeln!(out, "{} {}", w, h)?;
+    writeln!(out, "255")?;
+    for y in 0..h {
+        for p in &pixels[y * w..(y + 1) * w] {
+            write!(out, "{} {} {} ", p[0], p[1], p[2])?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/* Single channel Portable Float Map, little endian, rows stored bottom to top */
+pub fn write_pfm_gray(path: &str, w: usize, h: usize, values: &[f32]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "Pf\n{} {}\n-1.0\n", w, h)?;
+    for y in (0..h).rev() {
+        for v in &values[y * w..(y + 1) * w] {
+            file.write_all(&v.to_le_bytes())?;
+        }
+    }
+    file.flush()
+}
+
+/* Named attribute of an OpenEXR header */
+fn exr_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/*
+ * Uncompressed scanline OpenEXR file of 32 bit float channels, one plane
+ * of values per channel, rows from the top. Layered channels are named
+ * layer.channel, e.g. albedo.R.
+ */
+pub fn write_exr(path: &str, w: usize, h: usize, channels: &[(String, &[f32])]) -> io::Result<()> {
+    /* Readers expect the channels sorted by name, in the header and in the pixel data */
+    let mut channels: Vec<&(String, &[f32])> = channels.iter().collect();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    let mut list = Vec::new();
+    for (name, _) in &channels {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        /* FLOAT pixels, not perceptually linear, no subsampling */
+        list.extend_from_slice(&2i32.to_le_bytes());
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+    exr_attribute(&mut header, "channels", "chlist", &list);
+    exr_attribute(&mut header, "compression", "compression", &[0]);
+    let window: Vec<u8> = [0, 0, w as i32 - 1, h as i32 - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    /* Every scanline is a chunk of its y, the size of its data and the channels one after another */
+    let line_size = channels.len() * w * 4;
+    let chunk_size = 8 + line_size;
+    let first_chunk = header.len() + 8 * h;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&header)?;
+    for y in 0..h {
+        file.write_all(&((first_chunk + y * chunk_size) as u64).to_le_bytes())?;
+    }
+    for y in 0..h {
+        file.write_all(&(y as i32).to_le_bytes())?;
+        file.write_all(&(line_size as i32).to_le_bytes())?;
+        for (_, values) in &channels {
+            for v in &values[y * w..(y + 1) * w] {
+                file.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_parse_hdr_rle() {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        /* R: run of 8 x 128, G: 8 literals, B: run of 8 x 0, E: run of 8 x 129 */
+        data.extend_from_slice(&[128 + 8, 128]);
+        data.extend_from_slice(&[8, 0, 16, 32, 48, 64, 80, 96, 112]);
+        data.extend_from_slice(&[128 + 8, 0]);
+        data.extend_from_slice(&[128 + 8, 129]);
+
+        let img = parse_hdr(&data).unwrap();
+        assert_eq!((img.w, img.h), (8, 1));
+        assert_eq!(img.get(0, 0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(img.get(2, 0), Vec3::new(1.0, 0.25, 0.0));
+    }
+
+    #[test]
+    fn test_parse_pfm() {
+        let mut data = b"PF\n1 2\n-1.0\n".to_vec();
+        for v in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0].iter() {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+
+        let img = parse_pfm(&data).unwrap();
+        assert_eq!(img.get(0, 0), Vec3::new(4.0, 5.0, 6.0));
+        assert_eq!(img.get(0, 1), Vec3::new(1.0, 2.0, 3.0));
+
+        /* Written files read back the same */
+        let path = std::env::temp_dir().join("rrt-test-roundtrip.pfm");
+        let path = path.to_str().unwrap();
+        write_pfm(path, img.w, img.h, &img.pixels).unwrap();
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(parse_pfm(&data).unwrap().pixels, img.pixels);
+    }
+
+    /* Empty images and ones too large to count in bytes are refused before anything is allocated */
+    #[test]
+    fn test_invalid_sizes() {
+        let huge = format!("{} 2", usize::MAX / 2);
+        for dims in ["0 2", "2 0", "0 0", huge.as_str()] {
+            let pfm = format!("PF\n{}\n-1.0\n", dims);
+            assert!(matches!(parse_pfm(pfm.as_bytes()), Err(ImageError::Format(_))), "{}", dims);
+            let (w, h) = dims.split_once(' ').unwrap();
+            let hdr = format!("#?RADIANCE\n\n-Y {} +X {}\n", h, w);
+            assert!(matches!(parse_hdr(hdr.as_bytes()), Err(ImageError::Format(_))), "{}", dims);
+        }
+    }
+
+    #[test]
+    fn test_write_exr() {
+        let path = std::env::temp_dir().join("rrt-test-layers.exr");
+        let path = path.to_str().unwrap();
+        let (r, z) = ([1.0f32, 2.0, 3.0, 4.0], [5.0f32, 6.0, 7.0, 8.0]);
+        write_exr(path, 2, 2, &[("depth.Z".to_string(), &z), ("R".to_string(), &r)]).unwrap();
+        let mut data = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut data).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(&data[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        /* The offset table points at the last scanline, R sorts before depth.Z */
+        let table = data.len() - 2 * (8 + 2 * 2 * 4) - 16;
+        let offset = u64::from_le_bytes(data[table + 8..table + 16].try_into().unwrap()) as usize;
+        assert_eq!(i32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()), 1);
+        let value = |i: usize| f32::from_le_bytes(data[offset + 8 + 4 * i..offset + 12 + 4 * i].try_into().unwrap());
+        assert_eq!([value(0), value(1), value(2), value(3)], [3.0, 4.0, 7.0, 8.0]);
+    }
+}
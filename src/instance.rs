@@ -0,0 +1,246 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::aabb::AABB;
+use crate::bvh::BVH;
+use crate::hittable::{Hittable, HitRecord, Shapes};
+use crate::material::Material;
+use crate::mesh::Mesh;
+use crate::options::parse_vec3;
+use crate::rng::RNG;
+use crate::transform::{Transform, Quat};
+
+/* Pose of an instance at a point in time: scale, then rotate, then translate */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translate: Vec3,
+    pub rotate: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Keyframe {
+    fn default() -> Keyframe {
+        Keyframe { time: 0.0, translate: Vec3::zero(), rotate: Quat::default(), scale: Vec3::one() }
+    }
+}
+
+impl Keyframe {
+    pub fn transform(&self) -> Transform {
+        Transform::scale(self.scale)
+            .then(&Transform::from_quat(&self.rotate))
+            .then(&Transform::translate(self.translate))
+    }
+
+    /* Translation and scale are interpolated linearly and the rotation along the sphere */
+    fn lerp(&self, next: &Keyframe, t: f32) -> Keyframe {
+        Keyframe {
+            time: self.time + (next.time - self.time) * t,
+            translate: self.translate + (next.translate - self.translate) * t,
+            rotate: self.rotate.slerp(&next.rotate, t),
+            scale: self.scale + (next.scale - self.scale) * t,
+        }
+    }
+}
+
+/*
+ * Keyframe as given on the command line, the rotation being an axis and an
+ * angle in degrees:
+ *   time:tx,ty,tz[:ax,ay,az,degrees[:sx,sy,sz]]
+ */
+impl FromStr for Keyframe {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let (time, translate, rest) = match fields.as_slice() {
+            [time, translate, rest @ ..] if rest.len() <= 2 => (time, translate, rest),
+            _ => return Err(format!("Invalid keyframe: {}", s)),
+        };
+
+        let mut key = Keyframe {
+            time: time.parse().map_err(|_| format!("Invalid time: {}", time))?,
+            translate: parse_vec3(translate)?,
+            ..Keyframe::default()
+        };
+        if let Some(rotate) = rest.first() {
+            let values: Vec<f32> = match rotate.split(',').map(|v| v.parse()).collect() {
+                Ok(values) => values,
+                Err(_) => return Err(format!("Invalid rotation: {}", rotate)),
+            };
+            match values.as_slice() {
+                [x, y, z, degrees] => {
+                    key.rotate = Quat::from_axis_angle(Vec3::new(*x, *y, *z), degrees.to_radians());
+                }
+                _ => return Err(format!("Invalid rotation: {}", rotate)),
+            }
+        }
+        if let Some(scale) = rest.get(1) {
+            key.scale = parse_vec3(scale)?;
+            if key.scale.x == 0.0 || key.scale.y == 0.0 || key.scale.z == 0.0 {
+                return Err(format!("Invalid scale: {}", scale));
+            }
+        }
+        Ok(key)
+    }
+}
+
+/* Steps between keyframes at which the bounds of a rotating instance are taken */
+const BOUNDS_STEPS: usize = 16;
+
+/*
+ * Object placed in the scene by a transform animated over keyframes. A ray
+ * is taken into the space of the object at the pose for its time. Before
+ * the first and after the last keyframe the instance holds still.
+ */
+pub struct Instance<'a> {
+    object: Box<dyn Hittable + 'a>,
+    keyframes: Vec<Keyframe>,
+    aabb: Option<AABB>,
+}
+
+impl<'a> Instance<'a> {
+    pub fn new(object: Box<dyn Hittable + 'a>, mut keyframes: Vec<Keyframe>) -> Instance<'a> {
+        if keyframes.is_empty() {
+            keyframes.push(Keyframe::default());
+        }
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        let aabb = object.get_aabb().map(|local| motion_bounds(&local, &keyframes));
+        Instance { object, keyframes, aabb }
+    }
+
+    pub fn pose(&self, time: f32) -> Keyframe {
+        let keys = &self.keyframes;
+        let next = keys.partition_point(|key| key.time <= time);
+        if next == 0 {
+            return keys[0];
+        }
+        if next == keys.len() {
+            return keys[keys.len() - 1];
+        }
+
+        let (a, b) = (&keys[next - 1], &keys[next]);
+        a.lerp(b, (time - a.time) / (b.time - a.time))
+    }
+}
+
+fn corners(aabb: &AABB) -> impl Iterator<Item = Vec3> + '_ {
+    (0..8).map(move |i| Vec3::new(if i & 1 == 0 { aabb.min.x } else { aabb.max.x },
+                                  if i & 2 == 0 { aabb.min.y } else { aabb.max.y },
+                                  if i & 4 == 0 { aabb.min.z } else { aabb.max.z }))
+}
+
+/*
+ * Bounds of the local box over the whole animation. Poses are sampled
+ * between keyframes and the box is grown by how far a corner can bulge
+ * out of the chord between two samples while rotating.
+ */
+fn motion_bounds(local: &AABB, keyframes: &[Keyframe]) -> AABB {
+    let radius = corners(local).map(|c| c.len()).fold(0.0, f32::max);
+    let mut poses = vec![keyframes[0]];
+    let mut pad: f32 = 0.0;
+    for pair in keyframes.windows(2) {
+        let step = pair[0].rotate.angle_to(&pair[1].rotate) / BOUNDS_STEPS as f32;
+        let scale = pair[0].scale.abs().max_component().max(pair[1].scale.abs().max_component());
+        pad = pad.max(radius * scale * (1.0 - (step / 2.0).cos()));
+        poses.extend((1..=BOUNDS_STEPS).map(|i| pair[0].lerp(&pair[1], i as f32 / BOUNDS_STEPS as f32)));
+    }
+
+    let mut aabb: Option<AABB> = None;
+    for pose in &poses {
+        let transform = pose.transform();
+        for corner in corners(local) {
+            let p = transform.point(corner);
+            let point = AABB::new(p, p);
+            aabb = Some(match aabb {
+                Some(aabb) => aabb.merge(&point),
+                None => point,
+            });
+        }
+    }
+
+    let aabb = aabb.unwrap();
+    let pad = Vec3::new(pad, pad, pad);
+    AABB::new(aabb.min - pad, aabb.max + pad)
+}
+
+impl Hittable for Instance<'_> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, rng: &mut RNG) -> Option<HitRecord<'_>> {
+        let to_world = self.pose(ray.time).transform();
+        /* The local direction isn't normalized so the hit distance carries over */
+        let local = to_world.inverse().ray(ray);
+        let mut rec = self.object.hit(&local, t_min, t_max, rng)?;
+        rec.p = to_world.point(rec.p);
+        rec.n = to_world.normal(rec.n).normalized();
+        rec.ng = to_world.normal(rec.ng).normalized();
+        Some(rec)
+    }
+
+    fn get_aabb(&self) -> Option<AABB> {
+        self.aabb
+    }
+}
+
+/*
+ * Diffuse mesh instance as given on the command line, file.obj:r,g,b with
+ * the albedo last. Keyframes are added by the options that follow it.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstanceDesc {
+    pub path: String,
+    pub albedo: Vec3,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl FromStr for InstanceDesc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((path, albedo)) => match Vec3::from_csv(albedo) {
+                Some(albedo) => Ok(InstanceDesc { path: path.to_string(), albedo, keyframes: Vec::new() }),
+                None => Err(format!("Invalid albedo: {}", albedo)),
+            },
+            None => Err(format!("Invalid instance: {}", s)),
+        }
+    }
+}
+
+impl InstanceDesc {
+    pub fn shapes<'a>(&self, mat: &'a dyn Material) -> Result<Shapes<'a>, String> {
+        let mesh = Mesh::load_obj(&self.path).map_err(|why| format!("Could not load {}: {}", self.path, why))?;
+        Ok(Shapes { spheres: Vec::new(), tris: mesh.get_mesh(mat) })
+    }
+
+    pub fn build<'a>(&self, shapes: &'a Shapes<'a>) -> Instance<'a> {
+        let object = BVH::new(shapes.hittables().collect());
+        Instance::new(Box::new(object), self.keyframes.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sphere;
+    use crate::material::Lambertian;
+
+    #[test]
+    fn test_keyframed_instance() {
+        let mat = Lambertian::new(Vec3::one());
+        let sphere = Sphere::new(Vec3::zero(), 1.0, &mat);
+        let keyframes = vec!["1:4,0,0:0,1,0,90:2,2,2".parse().unwrap(), "0:0,0,0".parse().unwrap()];
+        let instance = Instance::new(Box::new(sphere), keyframes);
+        let mut rng = RNG::with_seed(0);
+
+        /* Halfway the sphere is at x = 2 with a radius of 1.5 */
+        let ray = Ray::new(Vec3::new(2.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.5);
+        let hit = instance.hit(&ray, 0.0, f32::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 3.5).abs() < 1e-4);
+        assert!((hit.n - Vec3::new(0.0, 1.0, 0.0)).len() < 1e-4);
+        /* Past the last keyframe it stays at the end */
+        assert_eq!(instance.pose(3.0).translate, Vec3::new(4.0, 0.0, 0.0));
+
+        let aabb = instance.get_aabb().unwrap();
+        assert!(aabb.min.x <= -1.0 && aabb.max.x >= 6.0 && aabb.max.y >= 2.0);
+        assert!("0:1,2".parse::<Keyframe>().is_err());
+    }
+}
@@ -0,0 +1,629 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::aov::AovSample;
+use crate::bdpt::BdptIntegrator;
+use crate::camera::Camera;
+use crate::film::Splat;
+use crate::hittable::{Hittable, HitRecord};
+use crate::material::Lobe;
+use crate::medium::{Medium, MediumSample};
+use crate::rng::RNG;
+use crate::sampler::{Sampler, BOUNCE_BSDF_DIM, BOUNCE_LIGHT_DIM};
+use crate::scene::Scene;
+use crate::stats::{count, Counter};
+use crate::warp::sample_unit_sphere;
+
+pub const T_MIN: f32 = 0.00001;
+pub const T_MAX: f32 = 9999.0;
+pub const SHADOW_EPSILON: f32 = 1e-4;
+
+/* Limits on the number of bounces of a path, in total and per lobe */
+#[derive(Copy, Clone, Debug)]
+pub struct DepthPolicy {
+    pub max_depth: u32,
+    pub max_diffuse: u32,
+    pub max_specular: u32,
+    pub max_transmission: u32,
+    pub max_volume: u32,
+    /* Bounces before Russian roulette may terminate a path */
+    pub rr_depth: u32,
+}
+
+impl Default for DepthPolicy {
+    fn default() -> DepthPolicy {
+        DepthPolicy {
+            max_depth: 50,
+            max_diffuse: 50,
+            max_specular: 50,
+            max_transmission: 50,
+            max_volume: 50,
+            rr_depth: 3,
+        }
+    }
+}
+
+/* Number of bounces taken so far through each lobe */
+#[derive(Default)]
+pub struct BounceCounts {
+    diffuse: u32,
+    specular: u32,
+    transmission: u32,
+    volume: u32,
+}
+
+impl BounceCounts {
+    /* Counts the bounce and returns false if it exceeds the policy */
+    pub fn add(&mut self, lobe: Lobe, policy: &DepthPolicy) -> bool {
+        match lobe {
+            Lobe::Diffuse => {
+                self.diffuse += 1;
+                self.diffuse <= policy.max_diffuse
+            }
+            Lobe::Specular => {
+                self.specular += 1;
+                self.specular <= policy.max_specular
+            }
+            Lobe::Transmission => {
+                self.transmission += 1;
+                self.transmission <= policy.max_transmission
+            }
+            Lobe::Volume => {
+                self.volume += 1;
+                self.volume <= policy.max_volume
+            }
+        }
+    }
+}
+
+pub fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32 {
+    let a2 = pdf_a * pdf_a;
+    let b2 = pdf_b * pdf_b;
+    if a2 + b2 == 0.0 { 0.0 } else { a2 / (a2 + b2) }
+}
+
+/* Next event estimation towards the background, MIS weighted against BSDF sampling */
+pub fn sample_background(scene: &Scene, ray: &Ray, rec: &HitRecord,
+                         sampler: &mut dyn Sampler) -> Vec3 {
+    let u = sampler.get_2d();
+    let sample = match scene.background.sample(u) {
+        Some(sample) => sample,
+        None => return Vec3::zero(),
+    };
+
+    let wo = -ray.dir.normalized();
+    let f = rec.mat.eval(wo, sample.dir, rec);
+    if f == Vec3::zero() || sample.pdf == 0.0 {
+        return Vec3::zero();
+    }
+
+    let tr = transmittance(scene, rec, sample.dir, T_MAX, ray.time, sampler);
+    if tr == Vec3::zero() {
+        return Vec3::zero();
+    }
+
+    let weight = power_heuristic(sample.pdf, rec.mat.pdf(wo, sample.dir, rec));
+    f * tr * sample.radiance * (weight / sample.pdf)
+}
+
+/* Direct light from a single light picked by the scene's light sampler */
+fn sample_lights(scene: &Scene, ray: &Ray, rec: &HitRecord,
+                 sampler: &mut dyn Sampler) -> Vec3 {
+    let u_light = sampler.get_1d();
+    let u = sampler.get_2d();
+    let picked = match scene.light_sampler.sample(rec.p, rec.n, u_light) {
+        Some(picked) => picked,
+        None => return Vec3::zero(),
+    };
+
+    let sample = match scene.lights[picked.index].sample_li(rec.p, u) {
+        Some(sample) => sample,
+        None => return Vec3::zero(),
+    };
+
+    let wo = -ray.dir.normalized();
+    let f = rec.mat.eval(wo, sample.wi, rec);
+    if f == Vec3::zero() || sample.pdf == 0.0 {
+        return Vec3::zero();
+    }
+
+    let tr = transmittance(scene, rec, sample.wi, sample.dist, ray.time, sampler);
+    f * tr * sample.radiance / (sample.pdf * picked.pmf)
+}
+
+/*
+ * Shadow ray test, dist is the distance to the light along the unit
+ * direction dir. Returns zero if a surface is in the way and otherwise the
+ * attenuation by the media along the ray. The origin is pushed off the
+ * surface to the side of dir so the ray doesn't hit the surface it starts
+ * from, medium interactions have a zero normal and stay in place. The
+ * shadow ray is traced at the time of the path so moving occluders line up.
+ * It stops short of the light by a margin wide enough for the distances to
+ * sphere lights, which lose precision towards the rim of the sampled cone.
+ */
+fn transmittance(scene: &Scene, rec: &HitRecord, dir: Vec3, dist: f32, time: f32,
+                 sampler: &mut dyn Sampler) -> Vec3 {
+    let offset = if dir.dot(rec.n) > 0.0 { rec.n } else { -rec.n };
+    let shadow = Ray::new(rec.p + offset * SHADOW_EPSILON, dir, time);
+    let t_max = (dist * (1.0 - 1e-3)).min(T_MAX);
+    count(Counter::ShadowRays, 1);
+    if scene.world.hit(&shadow, T_MIN, t_max, sampler.rng()).is_some() {
+        return Vec3::zero();
+    }
+
+    let mut tr = Vec3::one();
+    for medium in &scene.media {
+        tr *= medium.transmittance(&shadow, t_max, sampler.rng());
+    }
+    tr
+}
+
+/* Outcome of tracking a ray segment through the media */
+struct MediumEvent<'m> {
+    weight: Vec3,
+    /* Parameter of the scattering event along the ray and the medium it happened in */
+    scatter: Option<(f32, &'m dyn Medium)>,
+}
+
+/*
+ * Samples the nearest collision along the ray up to t_max among all media.
+ * The first medium uses the sampler's dimension, any further ones the RNG.
+ */
+fn sample_media<'m>(media: &'m [Box<dyn Medium + 'm>], ray: &Ray, t_max: f32, u: f32,
+                    rng: &mut RNG) -> MediumEvent<'m> {
+    let mut nearest: Option<(usize, MediumSample)> = None;
+    for (i, medium) in media.iter().enumerate() {
+        let u = if i == 0 { u } else { rng.sample_01() };
+        let t_limit = nearest.as_ref().map_or(t_max, |(_, s)| s.t);
+        if let Some(sample) = medium.sample(ray, t_limit, u, rng) {
+            nearest = Some((i, sample));
+        }
+    }
+
+    let t_end = nearest.as_ref().map_or(t_max, |(_, s)| s.t);
+    let mut weight = Vec3::one();
+    for (i, medium) in media.iter().enumerate() {
+        match &nearest {
+            Some((j, sample)) if *j == i => weight *= sample.weight,
+            _ => weight *= medium.pass_weight(ray, t_end, rng),
+        }
+    }
+
+    MediumEvent { weight, scatter: nearest.map(|(i, s)| (s.t, media[i].as_ref())) }
+}
+
+/* Records what a camera ray hits first in its AOV sample */
+pub fn record_first_hit(aov: &mut AovSample, scene: &Scene, ray: &Ray, rec: &HitRecord) {
+    aov.albedo = rec.mat.albedo(rec);
+    aov.normal = rec.n;
+    aov.position = rec.p;
+    aov.depth = rec.t * ray.dir.len();
+    aov.material = scene.material_id(rec.mat);
+    aov.object = rec.object;
+}
+
+/*
+ * Radiance arriving along a camera ray. With an AOV sample, what the ray
+ * hits first is recorded in it, and the light that was emitted or scattered
+ * into the path at the first vertex is counted as direct.
+ */
+pub fn trace_ray(ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler,
+                 policy: &DepthPolicy, mut aov: Option<&mut AovSample>) -> Vec3 {
+    let mut radiance = Vec3::zero();
+    let mut direct = Vec3::zero();
+    let mut throughput = Vec3::one();
+    let mut ray = *ray;
+    let mut bounces = BounceCounts::default();
+    /* Density of the last scattered direction, zero for camera rays and singular lobes */
+    let mut scatter_pdf = 0.0;
+
+    for depth in 0..policy.max_depth {
+        sampler.start_bounce(depth);
+        /* Drawn unconditionally to keep the dimensions of the bounce fixed */
+        let rr_u = sampler.get_1d();
+        let medium_u = sampler.get_1d();
+
+        count(if depth == 0 { Counter::CameraRays } else { Counter::ScatterRays }, 1);
+        let mut hit = scene.world.hit(&ray, T_MIN, T_MAX, sampler.rng());
+
+        if !scene.media.is_empty() {
+            let t_max = hit.map_or(T_MAX, |rec| rec.t);
+            let event = sample_media(&scene.media, &ray, t_max, medium_u, sampler.rng());
+            throughput *= event.weight;
+            if throughput == Vec3::zero() {
+                break;
+            }
+            if let Some((t, medium)) = event.scatter {
+                hit = Some(HitRecord {
+                    p: ray.at(t),
+                    n: Vec3::zero(),
+                    ng: Vec3::zero(),
+                    uv: (0.0, 0.0),
+                    mat: medium.phase(),
+                    t,
+                    front_face: true,
+                    object: 0,
+                });
+            }
+        }
+
+        let rec = match hit {
+            Some(rec) => rec,
+            None => {
+                let weight = if scatter_pdf > 0.0 {
+                    power_heuristic(scatter_pdf, scene.background.pdf(ray.dir))
+                } else {
+                    1.0
+                };
+                let background = throughput * scene.background.eval(ray.dir) * weight;
+                radiance += background;
+                if depth <= 1 {
+                    direct += background;
+                }
+                break;
+            }
+        };
+        count(Counter::PathVertices, 1);
+
+        if depth == 0 {
+            if let Some(aov) = aov.as_deref_mut() {
+                record_first_hit(aov, scene, &ray, &rec);
+            }
+        }
+
+        /*
+         * Emitters reached through a non-singular bounce were already
+         * accounted for by next event estimation at the previous vertex.
+         */
+        if scatter_pdf == 0.0 {
+            let emitted = throughput * rec.mat.emitted(&rec);
+            radiance += emitted;
+            if depth <= 1 {
+                direct += emitted;
+            }
+        }
+
+        let scatter = match rec.mat.scatter(&ray, &rec, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+
+        if !bounces.add(scatter.lobe, policy) {
+            break;
+        }
+
+        if scatter.pdf > 0.0 {
+            sampler.set_bounce_dimension(depth, BOUNCE_LIGHT_DIM);
+            let lit = throughput * (sample_background(scene, &ray, &rec, sampler)
+                                    + sample_lights(scene, &ray, &rec, sampler));
+            radiance += lit;
+            if depth == 0 {
+                direct += lit;
+            }
+        }
+
+        throughput *= scatter.attenuation;
+
+        /* Russian roulette, survivors are reweighted to keep the estimate unbiased */
+        if depth + 1 >= policy.rr_depth {
+            let survive = throughput.max_component().min(0.95);
+            if rr_u >= survive {
+                break;
+            }
+            throughput = throughput / survive;
+        }
+
+        ray = scatter.ray;
+        scatter_pdf = scatter.pdf;
+    }
+
+    if let Some(aov) = aov {
+        aov.direct = direct;
+    }
+    radiance
+}
+
+/* Estimates the radiance arriving at the camera */
+pub trait Integrator {
+    /* Radiance along a camera ray, with an AOV sample what it hits first is recorded in it */
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler, aov: Option<&mut AovSample>) -> Vec3;
+
+    /* Light the last call of li left on other parts of the film, taken out */
+    fn take_splats(&self) -> Vec<Splat> {
+        Vec::new()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IntegratorKind {
+    Path,
+    AmbientOcclusion,
+    Whitted,
+    Bidirectional,
+    /* Stochastic progressive photon mapping, rendered by sppm::render */
+    PhotonMapping,
+}
+
+impl FromStr for IntegratorKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(IntegratorKind::Path),
+            "ao" => Ok(IntegratorKind::AmbientOcclusion),
+            "whitted" => Ok(IntegratorKind::Whitted),
+            "bdpt" => Ok(IntegratorKind::Bidirectional),
+            "sppm" => Ok(IntegratorKind::PhotonMapping),
+            _ => Err(format!("Unknown integrator: {}", s)),
+        }
+    }
+}
+
+/*
+ * The occlusion radius and sample count only matter to ambient occlusion.
+ * Bidirectional path tracing connects to the camera and splats onto a film
+ * of film_size pixels.
+ */
+pub fn new_integrator<'a>(kind: IntegratorKind, policy: DepthPolicy, ao_radius: f32, ao_samples: u32,
+                          camera: &'a dyn Camera, scene: &Scene, film_size: (usize, usize)) -> Box<dyn Integrator + 'a> {
+    match kind {
+        IntegratorKind::Path => Box::new(PathIntegrator { policy }),
+        IntegratorKind::AmbientOcclusion => Box::new(AmbientOcclusionIntegrator { radius: ao_radius, samples: ao_samples }),
+        IntegratorKind::Whitted => Box::new(WhittedIntegrator { policy }),
+        IntegratorKind::Bidirectional => Box::new(BdptIntegrator::new(policy, camera, scene, film_size)),
+        /* Photon mapping renders the whole image at once, camera rays on their own only go through glass */
+        IntegratorKind::PhotonMapping => Box::new(WhittedIntegrator { policy }),
+    }
+}
+
+/* Unidirectional path tracing with next event estimation, see trace_ray */
+pub struct PathIntegrator {
+    policy: DepthPolicy,
+}
+
+impl Integrator for PathIntegrator {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler, aov: Option<&mut AovSample>) -> Vec3 {
+        trace_ray(ray, scene, sampler, &self.policy, aov)
+    }
+}
+
+/*
+ * Ambient occlusion: the fraction of cosine distributed directions over the
+ * first hit that no surface blocks within radius, as a gray value. Media,
+ * lights and materials play no part. Every occlusion ray takes the
+ * dimensions of a bounce of its own.
+ */
+pub struct AmbientOcclusionIntegrator {
+    radius: f32,
+    samples: u32,
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler, aov: Option<&mut AovSample>) -> Vec3 {
+        count(Counter::CameraRays, 1);
+        let rec = match scene.world.hit(ray, T_MIN, T_MAX, sampler.rng()) {
+            Some(rec) => rec,
+            None => return Vec3::zero(),
+        };
+        count(Counter::PathVertices, 1);
+        if let Some(aov) = aov {
+            record_first_hit(aov, scene, ray, &rec);
+        }
+
+        let n = if rec.n.dot(ray.dir) > 0.0 { -rec.n } else { rec.n };
+        let origin = rec.p + n * SHADOW_EPSILON;
+        let mut open = 0;
+        for i in 0..self.samples {
+            sampler.set_bounce_dimension(i, 0);
+            let dir = n + sample_unit_sphere(sampler.get_2d());
+            if dir.len2() < 1e-8 {
+                open += 1;
+                continue;
+            }
+            count(Counter::ShadowRays, 1);
+            let occlusion = Ray::new(origin, dir.normalized(), ray.time);
+            if scene.world.hit(&occlusion, T_MIN, self.radius, sampler.rng()).is_none() {
+                open += 1;
+            }
+        }
+        Vec3::one() * (open as f32 / self.samples.max(1) as f32)
+    }
+}
+
+/* First surface along a camera path that is not a perfect mirror or glass, with the throughput up to it */
+pub struct DiffuseHit<'a> {
+    pub rec: HitRecord<'a>,
+    pub wo: Vec3,
+    pub throughput: Vec3,
+}
+
+/*
+ * Radiance along a camera ray that only recurses through perfectly
+ * specular reflection and refraction, taking direct light from the lights
+ * and the background at the first non-singular hit, which is returned
+ * along. Both the light and the scattered direction are sampled for the
+ * background, combined by MIS. Media are left out.
+ */
+pub fn trace_specular<'s>(ray: &Ray, scene: &'s Scene, sampler: &mut dyn Sampler, policy: &DepthPolicy,
+                          mut aov: Option<&mut AovSample>) -> (Vec3, Option<DiffuseHit<'s>>) {
+    let mut radiance = Vec3::zero();
+    let mut direct = Vec3::zero();
+    let mut throughput = Vec3::one();
+    let mut ray = *ray;
+    let mut bounces = BounceCounts::default();
+    let mut diffuse = None;
+
+    for depth in 0..policy.max_depth {
+        sampler.start_bounce(depth);
+        count(if depth == 0 { Counter::CameraRays } else { Counter::ScatterRays }, 1);
+        let rec = match scene.world.hit(&ray, T_MIN, T_MAX, sampler.rng()) {
+            Some(rec) => rec,
+            None => {
+                let background = throughput * scene.background.eval(ray.dir);
+                radiance += background;
+                if depth <= 1 {
+                    direct += background;
+                }
+                break;
+            }
+        };
+        count(Counter::PathVertices, 1);
+        if depth == 0 {
+            if let Some(aov) = aov.as_deref_mut() {
+                record_first_hit(aov, scene, &ray, &rec);
+            }
+        }
+
+        let emitted = throughput * rec.mat.emitted(&rec);
+        radiance += emitted;
+        if depth <= 1 {
+            direct += emitted;
+        }
+        sampler.set_bounce_dimension(depth, BOUNCE_BSDF_DIM);
+        let scatter = match rec.mat.scatter(&ray, &rec, sampler) {
+            Some(scatter) => scatter,
+            None => break,
+        };
+        /* Any surface that is not a perfect mirror or glass ends the path with its direct light */
+        if scatter.pdf > 0.0 {
+            sampler.set_bounce_dimension(depth, BOUNCE_LIGHT_DIM);
+            let mut lit = sample_background(scene, &ray, &rec, sampler) + sample_lights(scene, &ray, &rec, sampler);
+            /* The background is also looked for along the scattered ray, the only way to find one that can't be sampled */
+            count(Counter::ScatterRays, 1);
+            if scene.world.hit(&scatter.ray, T_MIN, T_MAX, sampler.rng()).is_none() {
+                let weight = power_heuristic(scatter.pdf, scene.background.pdf(scatter.ray.dir));
+                lit += scatter.attenuation * scene.background.eval(scatter.ray.dir) * weight;
+            }
+            let lit = throughput * lit;
+            radiance += lit;
+            if depth == 0 {
+                direct += lit;
+            }
+            diffuse = Some(DiffuseHit { rec, wo: -ray.dir.normalized(), throughput });
+            break;
+        }
+        if !bounces.add(scatter.lobe, policy) {
+            break;
+        }
+        throughput *= scatter.attenuation;
+        ray = scatter.ray;
+    }
+
+    if let Some(aov) = aov {
+        aov.direct = direct;
+    }
+    (radiance, diffuse)
+}
+
+/*
+ * Whitted style ray tracing for previews, see trace_specular. Indirect
+ * diffuse light is left out, so the image is darker than the path traced
+ * one.
+ */
+pub struct WhittedIntegrator {
+    policy: DepthPolicy,
+}
+
+impl Integrator for WhittedIntegrator {
+    fn li(&self, ray: &Ray, scene: &Scene, sampler: &mut dyn Sampler, aov: Option<&mut AovSample>) -> Vec3 {
+        trace_specular(ray, scene, sampler, &self.policy, aov).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::{Light, PointLight};
+    use crate::material::{DiffuseLight, Lambertian, Metal};
+    use crate::sampler::IndependentSampler;
+    use crate::scene::test_scene;
+    use crate::sphere::Sphere;
+
+    /* A point on open ground is unoccluded, under a low ceiling it is mostly occluded */
+    #[test]
+    fn test_ambient_occlusion() {
+        let mat = Lambertian::new(Vec3::one() * 0.5);
+        let ground = Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &mat);
+        let ceiling = Sphere::new(Vec3::new(0.0, 10.2, 0.0), 10.0, &mat);
+        let open = test_scene(vec![&ground], Vec::new(), Vec3::one(), vec![&mat]);
+        let covered = test_scene(vec![&ground, &ceiling], Vec::new(), Vec3::one(), vec![&mat]);
+
+        let ao = AmbientOcclusionIntegrator { radius: 1.0, samples: 64 };
+        let ray = Ray::new(Vec3::new(0.0, 0.1, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let mut sampler = IndependentSampler::new(1);
+        sampler.start_pixel_sample(0, 0);
+        let mut aov = AovSample::default();
+        assert_eq!(ao.li(&ray, &open, &mut sampler, Some(&mut aov)), Vec3::one());
+        assert_eq!(aov.material, 1);
+        assert!(ao.li(&ray, &covered, &mut sampler, None).x < 0.5);
+        assert!("whitted".parse::<IntegratorKind>().is_ok());
+    }
+
+    /* A mirror shows the emitter above it darkened by its albedo, and nothing off to the side */
+    #[test]
+    fn test_whitted_mirror() {
+        let mirror = Metal::new(Vec3::one() * 0.8, 0.0);
+        let emitter = DiffuseLight::new(Vec3::one() * 4.0, false);
+        let ground = Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &mirror);
+        let bulb = Sphere::new(Vec3::new(0.0, 5.0, 0.0), 1.0, &emitter);
+        let scene = test_scene(vec![&ground, &bulb], Vec::new(), Vec3::zero(), vec![&mirror, &emitter]);
+
+        let whitted = WhittedIntegrator { policy: DepthPolicy::default() };
+        let mut sampler = IndependentSampler::new(1);
+        sampler.start_pixel_sample(0, 0);
+        let down = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let seen = whitted.li(&down, &scene, &mut sampler, None);
+        assert!((seen - Vec3::one() * 3.2).len() < 1e-4);
+        let slanted = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), 0.0);
+        assert_eq!(whitted.li(&slanted, &scene, &mut sampler, None), Vec3::zero());
+    }
+
+    /*
+     * Inside a sphere of albedo one half lit by a point light at its center
+     * every bounce halves the light, so the radiance is twice the direct
+     * light with or without Russian roulette.
+     */
+    #[test]
+    fn test_russian_roulette_keeps_the_mean() {
+        let mat = Lambertian::new(Vec3::one() * 0.5);
+        let shell = Sphere::new(Vec3::zero(), 1.0, &mat);
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(PointLight::new(Vec3::zero(), Vec3::one(), 0.0))];
+        let scene = test_scene(vec![&shell], lights, Vec3::zero(), vec![&mat]);
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 0.0);
+
+        let mut sampler = IndependentSampler::new(2);
+        let mean = |rr_depth: u32, sampler: &mut IndependentSampler| {
+            let policy = DepthPolicy { rr_depth, ..DepthPolicy::default() };
+            let n = 4000;
+            (0..n).map(|i| {
+                sampler.start_pixel_sample(rr_depth as usize, i);
+                trace_ray(&ray, &scene, sampler, &policy, None).x
+            }).sum::<f32>() / n as f32
+        };
+        let expected = 2.0 * 0.5 / std::f32::consts::PI;
+        let with_rr = mean(0, &mut sampler);
+        let without_rr = mean(u32::MAX, &mut sampler);
+        assert!((with_rr - expected).abs() < 0.03 * expected, "{}", with_rr);
+        assert!((without_rr - expected).abs() < 0.03 * expected, "{}", without_rr);
+    }
+
+    /* The emitter seen over two mirrors is cut off by a limit of one specular bounce, not by the total depth */
+    #[test]
+    fn test_specular_depth_limit() {
+        let mirror = Metal::new(Vec3::one() * 0.8, 0.0);
+        let emitter = DiffuseLight::new(Vec3::one() * 4.0, false);
+        let ground = Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, &mirror);
+        let wall = Sphere::new(Vec3::new(1003.0, 0.0, 0.0), 1000.0, &mirror);
+        let bulb = Sphere::new(Vec3::new(1.0, 4.0, 0.0), 0.5, &emitter);
+        let scene = test_scene(vec![&ground, &wall, &bulb], Vec::new(), Vec3::zero(), vec![&mirror, &emitter]);
+        let ray = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), 0.0);
+
+        let mut sampler = IndependentSampler::new(1);
+        sampler.start_pixel_sample(0, 0);
+        let policy = DepthPolicy { max_specular: 2, ..DepthPolicy::default() };
+        let seen = trace_ray(&ray, &scene, &mut sampler, &policy, None);
+        assert!((seen - Vec3::one() * 0.8 * 0.8 * 4.0).len() < 1e-3);
+        let policy = DepthPolicy { max_specular: 1, ..DepthPolicy::default() };
+        assert_eq!(trace_ray(&ray, &scene, &mut sampler, &policy, None), Vec3::zero());
+    }
+}
@@ -0,0 +1,316 @@
+use std::fs;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::aperture::Aperture;
+use crate::camera::{Camera, Frame, Shutter};
+use crate::sampler::Sampler;
+use crate::warp::sample_unit_disk;
+
+/* Prescriptions are in millimeters and the scene in meters */
+const MM: f32 = 0.001;
+
+/* One surface of a lens system, or the aperture stop if the radius is zero */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LensElement {
+    pub curvature_radius: f32,
+    /* Distance along the axis to the next surface towards the film */
+    pub thickness: f32,
+    /* Index of refraction behind the surface, towards the film */
+    pub eta: f32,
+    pub aperture_radius: f32,
+}
+
+/*
+ * Parses a lens prescription, one surface per line from the front of the
+ * lens to the back (the format used by pbrt):
+ *   curvature_radius thickness eta aperture_diameter
+ * Lines starting with # are comments and an eta of zero means air.
+ */
+pub fn parse_prescription(text: &str) -> Result<Vec<LensElement>, String> {
+    let mut elements = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values: Vec<f32> = match line.split_whitespace().map(|v| v.parse()).collect() {
+            Ok(values) => values,
+            Err(_) => return Err(format!("Invalid lens element: {}", line)),
+        };
+        match values.as_slice() {
+            [radius, thickness, eta, diameter] => elements.push(LensElement {
+                curvature_radius: radius * MM,
+                thickness: thickness * MM,
+                eta: if *eta == 0.0 { 1.0 } else { *eta },
+                aperture_radius: diameter * MM / 2.0,
+            }),
+            _ => return Err(format!("Invalid lens element: {}", line)),
+        }
+    }
+    if elements.is_empty() {
+        return Err("Empty lens prescription".to_string());
+    }
+    Ok(elements)
+}
+
+/* Refraction of the unit direction d through a surface with normal n facing against it */
+fn refract(d: Vec3, n: Vec3, eta_i: f32, eta_t: f32) -> Option<Vec3> {
+    let eta = eta_i / eta_t;
+    let cos_i = -d.dot(n);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(d * eta + n * (eta * cos_i - cos_t))
+}
+
+/*
+ * Intersection with the spherical surface centered at z_center on the
+ * axis, taking the hit on the side of the sphere the lens is on. Returns
+ * the distance and the normal facing against the ray.
+ */
+fn intersect_element(radius: f32, z_center: f32, ray: &Ray) -> Option<(f32, Vec3)> {
+    let o = ray.orig - Vec3::new(0.0, 0.0, z_center);
+    let a = ray.dir.len2();
+    let b = 2.0 * ray.dir.dot(o);
+    let c = o.len2() - radius * radius;
+    let disc = b * b - 4.0 * a * c;
+    if disc < 0.0 {
+        return None;
+    }
+    let sqrt_disc = disc.sqrt();
+    let (t0, t1) = ((-b - sqrt_disc) / (2.0 * a), (-b + sqrt_disc) / (2.0 * a));
+    let closer = (ray.dir.z > 0.0) ^ (radius < 0.0);
+    let t = if closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0.0 {
+        return None;
+    }
+    let n = (o + ray.dir * t).normalized();
+    Some((t, if n.dot(ray.dir) > 0.0 { -n } else { n }))
+}
+
+/*
+ * Camera simulating a system of spherical lenses in front of the film
+ * (Kolb et al., following pbrt). Rays from the film are traced through
+ * every surface, those missing an element are lost, which vignettes the
+ * corners, and the curved surfaces give the distortion of the real lens.
+ *
+ * In lens space the film is at z = 0 and the lens extends towards -z. The
+ * camera space of the traced rays looks along +z.
+ */
+pub struct RealisticCamera {
+    frame: Frame,
+    elements: Vec<LensElement>,
+    film_w: f32,
+    film_h: f32,
+    aperture: Box<dyn Aperture>,
+    shutter: Shutter,
+}
+
+impl RealisticCamera {
+    /* The film diagonal is in millimeters, the focus distance in scene units */
+    pub fn new(frame: Frame, ar: f32, elements: Vec<LensElement>, film_diagonal: f32,
+               focus: f32, aperture: Box<dyn Aperture>, shutter: Shutter) -> Result<RealisticCamera, String> {
+        let diagonal = film_diagonal * MM;
+        let film_h = diagonal / (1.0 + ar * ar).sqrt();
+        let mut camera = RealisticCamera { frame, elements, film_w: film_h * ar, film_h, aperture, shutter };
+
+        let back = camera.focus_thick_lens(focus).ok_or("Could not focus the lens")?;
+        camera.elements.last_mut().unwrap().thickness = back;
+        Ok(camera)
+    }
+
+    pub fn load(path: &str) -> Result<Vec<LensElement>, String> {
+        let text = fs::read_to_string(path).map_err(|why| format!("Could not load {}: {}", path, why))?;
+        parse_prescription(&text)
+    }
+
+    fn rear_z(&self) -> f32 {
+        self.elements.last().unwrap().thickness
+    }
+
+    fn front_z(&self) -> f32 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    /*
+     * Traces a camera space ray from the film out of the front of the lens,
+     * None if it is blocked. The stop is tested against the aperture shape
+     * and partial transmission is decided by u.
+     */
+    fn trace_from_film(&self, ray: &Ray, u: f32) -> Option<Ray> {
+        let flip = |v: Vec3| Vec3::new(v.x, v.y, -v.z);
+        let mut o = flip(ray.orig);
+        let mut d = flip(ray.dir).normalized();
+        let mut z = 0.0;
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            z -= element.thickness;
+            let stop = element.curvature_radius == 0.0;
+            let (t, n) = if stop {
+                if d.z >= 0.0 {
+                    return None;
+                }
+                ((z - o.z) / d.z, Vec3::zero())
+            } else {
+                intersect_element(element.curvature_radius, z + element.curvature_radius,
+                                  &Ray::new(o, d, ray.time))?
+            };
+
+            let p = o + d * t;
+            let r2 = p.x * p.x + p.y * p.y;
+            if r2 > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            if stop {
+                let local = Vec3::new(p.x, p.y, 0.0) / element.aperture_radius;
+                if u >= self.aperture.transmission(local) {
+                    return None;
+                }
+            }
+            o = p;
+
+            if !stop {
+                let eta_t = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+                d = refract(d, n, element.eta, eta_t)?.normalized();
+            }
+        }
+        Some(Ray::new(flip(o), flip(d), ray.time))
+    }
+
+    /* Traces a camera space ray entering the front of the lens towards the film */
+    fn trace_from_scene(&self, ray: &Ray) -> Option<Ray> {
+        let flip = |v: Vec3| Vec3::new(v.x, v.y, -v.z);
+        let mut o = flip(ray.orig);
+        let mut d = flip(ray.dir).normalized();
+        let mut z = -self.front_z();
+        for (i, element) in self.elements.iter().enumerate() {
+            let stop = element.curvature_radius == 0.0;
+            let (t, n) = if stop {
+                ((z - o.z) / d.z, Vec3::zero())
+            } else {
+                intersect_element(element.curvature_radius, z + element.curvature_radius,
+                                  &Ray::new(o, d, ray.time))?
+            };
+
+            let p = o + d * t;
+            if p.x * p.x + p.y * p.y > element.aperture_radius * element.aperture_radius {
+                return None;
+            }
+            o = p;
+
+            if !stop {
+                let eta_i = if i > 0 { self.elements[i - 1].eta } else { 1.0 };
+                d = refract(d, n, eta_i, element.eta)?.normalized();
+            }
+            z += element.thickness;
+        }
+        Some(Ray::new(flip(o), flip(d), ray.time))
+    }
+
+    /*
+     * Principal plane and focal point along z of a ray parallel to the axis
+     * entering the lens, given where it leaves.
+     */
+    fn cardinal_points(r_in: &Ray, r_out: &Ray) -> (f32, f32) {
+        let tf = -r_out.orig.x / r_out.dir.x;
+        let fz = -r_out.at(tf).z;
+        let tp = (r_in.orig.x - r_out.orig.x) / r_out.dir.x;
+        let pz = -r_out.at(tp).z;
+        (pz, fz)
+    }
+
+    /*
+     * Distance from the rear element to the film that brings the focus
+     * distance into focus, using the thick lens approximation found by
+     * tracing rays parallel to the axis from both sides.
+     */
+    fn focus_thick_lens(&self, focus: f32) -> Option<f32> {
+        let x = 0.001 * (self.film_w * self.film_w + self.film_h * self.film_h).sqrt();
+
+        let from_scene = Ray::new(Vec3::new(x, 0.0, self.front_z() + 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let to_film = self.trace_from_scene(&from_scene)?;
+        let (pz0, fz0) = RealisticCamera::cardinal_points(&from_scene, &to_film);
+
+        let from_film = Ray::new(Vec3::new(x, 0.0, self.rear_z() - 1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let to_scene = self.trace_from_film(&from_film, 0.0)?;
+        let (pz1, _) = RealisticCamera::cardinal_points(&from_film, &to_scene);
+
+        let f = fz0 - pz0;
+        let z = -focus;
+        let c = (pz1 - z - pz0) * (pz1 - z - 4.0 * f - pz0);
+        if c <= 0.0 {
+            return None;
+        }
+        let delta = 0.5 * (pz1 - z + pz0 - c.sqrt());
+        Some(self.rear_z() + delta)
+    }
+}
+
+impl Camera for RealisticCamera {
+    /*
+     * The point on the film is mirrored through the center since the lens
+     * inverts the image. Rays aim at a uniform point of the rear element,
+     * those the lens blocks come back as None.
+     */
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let film = Vec3::new((0.5 - s) * self.film_w, (0.5 - t) * self.film_h, 0.0);
+        let rear = self.elements.last().unwrap();
+        let lens = sample_unit_disk(sampler.get_2d()) * rear.aperture_radius;
+        let target = Vec3::new(lens.x, lens.y, self.rear_z());
+
+        let time = self.shutter.sample(sampler);
+        let u = sampler.rng().sample_01();
+        let ray = self.trace_from_film(&Ray::new(film, target - film, time), u)?;
+
+        let orig = self.frame.orig + self.frame.local_to_world(ray.orig);
+        Some(Ray::new(orig, self.frame.local_to_world(ray.dir), time))
+    }
+
+    fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aperture::CircleAperture;
+
+    /* Double Gauss 50 mm f/2, US patent 2,673,491 */
+    const DGAUSS: &str = "
+        # radius thickness eta diameter
+        29.475   3.76   1.67   25.2
+        84.83    0.12   1      25.2
+        19.275   4.025  1.67   23
+        40.77    3.275  1.699  23
+        12.75    5.705  1      18
+        0        4.5    0      17.1
+        -14.495  1.18   1.603  17
+        40.77    6.065  1.658  20
+        -20.385  0.19   1      20
+        437.065  3.22   1.717  20
+        -39.73   0      1      20
+    ";
+
+    #[test]
+    fn test_double_gauss() {
+        let elements = parse_prescription(DGAUSS).unwrap();
+        assert_eq!(elements.len(), 11);
+        let frame = Frame::look_at(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        let shutter = Shutter { open: 0.0, close: 0.0 };
+        let camera = RealisticCamera::new(frame, 1.5, elements, 35.0, 10.0,
+                                          Box::new(CircleAperture), shutter).unwrap();
+
+        /* Focused at 10 m the film sits a little over the focal length behind the lens */
+        let back = camera.rear_z();
+        assert!(back > 0.03 && back < 0.06, "{}", back);
+
+        /* A ray along the axis leaves the lens undeviated */
+        let axis = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let out = camera.trace_from_film(&axis, 0.0).unwrap();
+        assert!(out.orig.x.abs() < 1e-6 && (out.dir - Vec3::new(0.0, 0.0, 1.0)).len() < 1e-5);
+        assert!(parse_prescription("1 2 3").is_err());
+    }
+}
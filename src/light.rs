@@ -0,0 +1,687 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::aabb::AABB;
+use crate::hittable::Shapes;
+use crate::lightsampler::LightBounds;
+use crate::material::{Material, DiffuseLight};
+use crate::mesh::Mesh;
+use crate::options::{parse_f32, parse_vec3};
+use crate::sphere::Sphere;
+use crate::warp::*;
+
+/* Incident light at a point, sampled towards a light source */
+pub struct LightSample {
+    /* Unit direction from the shaded point towards the light */
+    pub wi: Vec3,
+    /* Incident radiance, already including the distance falloff */
+    pub radiance: Vec3,
+    pub pdf: f32,
+    /* Distance to the light, infinite for directional lights */
+    pub dist: f32,
+    /* Normal of the light at the sampled point, zero for punctual lights */
+    pub n: Vec3,
+}
+
+/* A ray leaving a light, to trace a path from the light into the scene */
+pub struct LightEmission {
+    /* Unit direction */
+    pub ray: Ray,
+    /* Normal at the origin, zero for punctual lights */
+    pub n: Vec3,
+    /* Emitted radiance, the intensity for punctual lights */
+    pub radiance: Vec3,
+    /* Density of the origin by area, one for punctual lights, and of the direction by solid angle */
+    pub pdf_pos: f32,
+    pub pdf_dir: f32,
+}
+
+pub trait Light {
+    fn sample_li(&self, p: Vec3, u: (f32, f32)) -> Option<LightSample>;
+
+    /* Total emitted power as luminance, lights at infinity cover a disk of scene_radius */
+    fn power(&self, scene_radius: f32) -> f32;
+
+    /* Bounds for the light BVH, None for lights at infinity */
+    fn bounds(&self) -> Option<LightBounds> {
+        None
+    }
+
+    /* Samples a ray leaving the light, None for lights at infinity which don't support it */
+    fn sample_le(&self, _u_pos: (f32, f32), _u_dir: (f32, f32), _time: f32) -> Option<LightEmission> {
+        None
+    }
+
+    /*
+     * Densities of sample_le leaving from p with normal n along dir, zero
+     * by area for punctual lights as no other sampling finds those points.
+     */
+    fn pdf_le(&self, _p: Vec3, _n: Vec3, _dir: Vec3) -> (f32, f32) {
+        (0.0, 0.0)
+    }
+
+    /* Lights at a single point can only be reached by sampling them */
+    fn is_punctual(&self) -> bool {
+        false
+    }
+
+    /* Whether p lies on the emitting surface, to tell which light a path has hit */
+    fn on_surface(&self, _p: Vec3) -> bool {
+        false
+    }
+
+    /* Smooth cutoff over the squared distance, on top of the inverse square falloff */
+    fn cutoff(&self, _dist2: f32) -> f32 {
+        1.0
+    }
+}
+
+fn point_bounds(p: Vec3, w: Vec3, phi: f32, cos_theta_o: f32, cos_theta_e: f32) -> LightBounds {
+    LightBounds { bounds: AABB::new(p, p), w, phi, cos_theta_o, cos_theta_e, two_sided: false }
+}
+
+/* Isotropic point light with inverse square falloff */
+pub struct PointLight {
+    pos: Vec3,
+    intensity: Vec3,
+    /* Distance at which the light is smoothly faded out, no cutoff if zero */
+    range: f32,
+}
+
+impl PointLight {
+    pub fn new(pos: Vec3, intensity: Vec3, range: f32) -> PointLight {
+        PointLight { pos, intensity, range }
+    }
+}
+
+/* Window that fades the light out smoothly, reaching zero at range */
+fn window(dist2: f32, range: f32) -> f32 {
+    if range > 0.0 {
+        let r = dist2 / (range * range);
+        let w = (1.0 - r * r).clamp(0.0, 1.0);
+        w * w
+    } else {
+        1.0
+    }
+}
+
+/* Windowed inverse square falloff, reaches zero at range */
+fn falloff(dist2: f32, range: f32) -> f32 {
+    window(dist2, range) / dist2
+}
+
+/* Uniform direction around n weighted by the cosine, and its density */
+fn sample_cosine(n: Vec3, u: (f32, f32)) -> (Vec3, f32) {
+    let dir = (n + sample_unit_sphere(u)).normalized();
+    if !dir.x.is_finite() || n.dot(dir) <= 0.0 {
+        return (n, 1.0 / PI);
+    }
+    (dir, n.dot(dir) / PI)
+}
+
+impl Light for PointLight {
+    fn sample_li(&self, p: Vec3, _u: (f32, f32)) -> Option<LightSample> {
+        let d = self.pos - p;
+        let dist2 = d.len2();
+        if dist2 == 0.0 {
+            return None;
+        }
+        let dist = dist2.sqrt();
+        Some(LightSample {
+            wi: d / dist,
+            radiance: self.intensity * falloff(dist2, self.range),
+            pdf: 1.0,
+            dist,
+            n: Vec3::zero(),
+        })
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        4.0 * PI * self.intensity.luminance()
+    }
+
+    fn sample_le(&self, _u_pos: (f32, f32), u_dir: (f32, f32), time: f32) -> Option<LightEmission> {
+        Some(LightEmission {
+            ray: Ray::new(self.pos, sample_unit_sphere(u_dir), time),
+            n: Vec3::zero(),
+            radiance: self.intensity,
+            pdf_pos: 1.0,
+            pdf_dir: 1.0 / (4.0 * PI),
+        })
+    }
+
+    fn pdf_le(&self, _p: Vec3, _n: Vec3, _dir: Vec3) -> (f32, f32) {
+        (0.0, 1.0 / (4.0 * PI))
+    }
+
+    fn is_punctual(&self) -> bool {
+        true
+    }
+
+    fn cutoff(&self, dist2: f32) -> f32 {
+        window(dist2, self.range)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        /* Emits in all directions: the normal cone is the sphere, plus 90 degrees */
+        Some(point_bounds(self.pos, Vec3::new(0.0, 0.0, 1.0), self.power(0.0), -1.0, 0.0))
+    }
+}
+
+/* Point light restricted to a cone, fading out smoothly between the inner and outer angle */
+pub struct SpotLight {
+    pos: Vec3,
+    dir: Vec3,
+    intensity: Vec3,
+    cos_inner: f32,
+    cos_outer: f32,
+    range: f32,
+}
+
+impl SpotLight {
+    /* Cone angles are half angles in radians */
+    pub fn new(pos: Vec3, dir: Vec3, intensity: Vec3,
+               inner: f32, outer: f32, range: f32) -> SpotLight {
+        let outer = outer.max(inner);
+        SpotLight {
+            pos,
+            dir: dir.normalized(),
+            intensity,
+            cos_inner: inner.cos(),
+            cos_outer: outer.cos(),
+            range,
+        }
+    }
+
+    fn cone_falloff(&self, cos_theta: f32) -> f32 {
+        if cos_theta >= self.cos_inner {
+            return 1.0;
+        }
+        if cos_theta <= self.cos_outer {
+            return 0.0;
+        }
+        let t = (cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_li(&self, p: Vec3, _u: (f32, f32)) -> Option<LightSample> {
+        let d = self.pos - p;
+        let dist2 = d.len2();
+        if dist2 == 0.0 {
+            return None;
+        }
+        let dist = dist2.sqrt();
+        let wi = d / dist;
+        let cone = self.cone_falloff((-wi).dot(self.dir));
+        if cone == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            wi,
+            radiance: self.intensity * (cone * falloff(dist2, self.range)),
+            pdf: 1.0,
+            dist,
+            n: Vec3::zero(),
+        })
+    }
+
+    /* Directions are uniform within the outer cone */
+    fn sample_le(&self, _u_pos: (f32, f32), u_dir: (f32, f32), time: f32) -> Option<LightEmission> {
+        let one_minus_cos_max = 1.0 - self.cos_outer;
+        let local = sample_cone(u_dir, one_minus_cos_max);
+        let (t, b) = self.dir.basis();
+        let dir = (t * local.x + b * local.y + self.dir * local.z).normalized();
+        Some(LightEmission {
+            ray: Ray::new(self.pos, dir, time),
+            n: Vec3::zero(),
+            radiance: self.intensity * self.cone_falloff(dir.dot(self.dir)),
+            pdf_pos: 1.0,
+            pdf_dir: cone_pdf(one_minus_cos_max),
+        })
+    }
+
+    fn pdf_le(&self, _p: Vec3, _n: Vec3, dir: Vec3) -> (f32, f32) {
+        if dir.normalized().dot(self.dir) < self.cos_outer {
+            return (0.0, 0.0);
+        }
+        (0.0, cone_pdf(1.0 - self.cos_outer))
+    }
+
+    fn is_punctual(&self) -> bool {
+        true
+    }
+
+    fn cutoff(&self, dist2: f32) -> f32 {
+        window(dist2, self.range)
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        /* Falloff region approximated by its average */
+        let solid_angle = 2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer));
+        solid_angle * self.intensity.luminance()
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let cos_theta_e = (self.cos_outer.acos() - self.cos_inner.acos()).cos();
+        Some(point_bounds(self.pos, self.dir, self.power(0.0), self.cos_inner, cos_theta_e))
+    }
+}
+
+/* Light arriving from a single direction, e.g. a distant sun */
+pub struct DirectionalLight {
+    /* Direction the light travels in */
+    dir: Vec3,
+    irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    pub fn new(dir: Vec3, irradiance: Vec3) -> DirectionalLight {
+        DirectionalLight { dir: dir.normalized(), irradiance }
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample_li(&self, _p: Vec3, _u: (f32, f32)) -> Option<LightSample> {
+        Some(LightSample {
+            wi: -self.dir,
+            radiance: self.irradiance,
+            pdf: 1.0,
+            dist: f32::INFINITY,
+            n: Vec3::zero(),
+        })
+    }
+
+    fn power(&self, scene_radius: f32) -> f32 {
+        PI * scene_radius * scene_radius * self.irradiance.luminance()
+    }
+}
+
+/* Triangle with uniform emitted radiance, matching a DiffuseLight on a Tri */
+pub struct TriangleLight {
+    verts: [Vec3; 3],
+    /* Unit normal on the emitting side, following the winding like Tri */
+    n: Vec3,
+    area: f32,
+    radiance: Vec3,
+    two_sided: bool,
+}
+
+impl TriangleLight {
+    pub fn new(verts: [Vec3; 3], radiance: Vec3, two_sided: bool) -> TriangleLight {
+        let cross = (verts[1] - verts[0]).cross(verts[2] - verts[0]);
+        let area = cross.len() / 2.0;
+        let n = if area > 0.0 { cross.normalized() } else { Vec3::new(0.0, 0.0, 1.0) };
+        TriangleLight { verts, n, area, radiance, two_sided }
+    }
+}
+
+impl TriangleLight {
+    /* Uniform point on the triangle */
+    fn sample_point(&self, u: (f32, f32)) -> Vec3 {
+        let su = u.0.sqrt();
+        let b0 = 1.0 - su;
+        let b1 = u.1 * su;
+        self.verts[0] * b0 + self.verts[1] * b1 + self.verts[2] * (1.0 - b0 - b1)
+    }
+}
+
+impl Light for TriangleLight {
+    fn sample_li(&self, p: Vec3, u: (f32, f32)) -> Option<LightSample> {
+        if self.area == 0.0 {
+            return None;
+        }
+
+        let q = self.sample_point(u);
+        let d = q - p;
+        let dist2 = d.len2();
+        if dist2 == 0.0 {
+            return None;
+        }
+        let dist = dist2.sqrt();
+        let wi = d / dist;
+
+        let mut cos_light = -self.n.dot(wi);
+        if self.two_sided {
+            cos_light = cos_light.abs();
+        }
+        if cos_light <= 0.0 {
+            return None;
+        }
+
+        /* Area density converted to solid angle */
+        let pdf = dist2 / (cos_light * self.area);
+        Some(LightSample { wi, radiance: self.radiance, pdf, dist, n: self.n })
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        let sides = if self.two_sided { 2.0 } else { 1.0 };
+        sides * PI * self.area * self.radiance.luminance()
+    }
+
+    /* Two-sided triangles pick either side with the first dimension of u_dir */
+    fn sample_le(&self, u_pos: (f32, f32), u_dir: (f32, f32), time: f32) -> Option<LightEmission> {
+        if self.area == 0.0 {
+            return None;
+        }
+        let (n, u_dir, sides) = match (self.two_sided, u_dir.0 < 0.5) {
+            (false, _) => (self.n, u_dir, 1.0),
+            (true, true) => (self.n, (u_dir.0 * 2.0, u_dir.1), 2.0),
+            (true, false) => (-self.n, (u_dir.0 * 2.0 - 1.0, u_dir.1), 2.0),
+        };
+        let (dir, pdf_dir) = sample_cosine(n, u_dir);
+        Some(LightEmission {
+            ray: Ray::new(self.sample_point(u_pos), dir, time),
+            n,
+            radiance: self.radiance,
+            pdf_pos: 1.0 / self.area,
+            pdf_dir: pdf_dir / sides,
+        })
+    }
+
+    fn pdf_le(&self, _p: Vec3, _n: Vec3, dir: Vec3) -> (f32, f32) {
+        let cos = self.n.dot(dir.normalized());
+        let pdf_dir = if self.two_sided { cos.abs() / (2.0 * PI) } else { cos.max(0.0) / PI };
+        (1.0 / self.area, pdf_dir)
+    }
+
+    fn on_surface(&self, p: Vec3) -> bool {
+        let [v0, v1, v2] = self.verts;
+        let scale = (v1 - v0).len2().max((v2 - v0).len2()).sqrt();
+        if (p - v0).dot(self.n).abs() > 1e-4 * scale {
+            return false;
+        }
+        /* Inside if p is on the inner side of all three edges */
+        let inside = |a: Vec3, b: Vec3| (b - a).cross(p - a).dot(self.n) >= -1e-6 * scale * scale;
+        inside(v0, v1) && inside(v1, v2) && inside(v2, v0)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let [v0, v1, v2] = self.verts;
+        let min = Vec3::new(v0.x.min(v1.x).min(v2.x), v0.y.min(v1.y).min(v2.y), v0.z.min(v1.z).min(v2.z));
+        let max = Vec3::new(v0.x.max(v1.x).max(v2.x), v0.y.max(v1.y).max(v2.y), v0.z.max(v1.z).max(v2.z));
+        Some(LightBounds {
+            bounds: AABB::new(min, max),
+            w: self.n,
+            phi: self.power(0.0),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: self.two_sided,
+        })
+    }
+}
+
+/* Sphere emitting uniform radiance outwards, matching a DiffuseLight on a Sphere */
+pub struct SphereLight {
+    c: Vec3,
+    r: f32,
+    radiance: Vec3,
+}
+
+impl SphereLight {
+    pub fn new(c: Vec3, r: f32, radiance: Vec3) -> SphereLight {
+        SphereLight { c, r, radiance }
+    }
+}
+
+impl Light for SphereLight {
+    fn sample_li(&self, p: Vec3, u: (f32, f32)) -> Option<LightSample> {
+        let d = self.c - p;
+        let dist2 = d.len2();
+        /* The inside is not lit */
+        if dist2 <= self.r * self.r {
+            return None;
+        }
+
+        /* Uniform direction in the cone subtended by the sphere */
+        let sin2_max = self.r * self.r / dist2;
+        let one_minus_cos_max = if sin2_max < 1e-4 {
+            /* Avoids cancellation for small or distant spheres */
+            sin2_max / 2.0
+        } else {
+            1.0 - (1.0 - sin2_max).sqrt()
+        };
+        let axis = d / dist2.sqrt();
+        let local = sample_cone(u, one_minus_cos_max);
+        let (t, b) = axis.basis();
+        let wi = (t * local.x + b * local.y + axis * local.z).normalized();
+
+        /* Distance to the near intersection, the tangent point if the ray grazes past */
+        let proj = d.dot(wi);
+        let disc = self.r * self.r - (dist2 - proj * proj);
+        let dist = proj - disc.max(0.0).sqrt();
+
+        let n = (p + wi * dist - self.c) / self.r;
+        Some(LightSample { wi, radiance: self.radiance, pdf: cone_pdf(one_minus_cos_max), dist, n })
+    }
+
+    fn power(&self, _scene_radius: f32) -> f32 {
+        PI * 4.0 * PI * self.r * self.r * self.radiance.luminance()
+    }
+
+    fn sample_le(&self, u_pos: (f32, f32), u_dir: (f32, f32), time: f32) -> Option<LightEmission> {
+        let n = sample_unit_sphere(u_pos);
+        let (dir, pdf_dir) = sample_cosine(n, u_dir);
+        Some(LightEmission {
+            ray: Ray::new(self.c + n * self.r, dir, time),
+            n,
+            radiance: self.radiance,
+            pdf_pos: 1.0 / (4.0 * PI * self.r * self.r),
+            pdf_dir,
+        })
+    }
+
+    fn pdf_le(&self, p: Vec3, _n: Vec3, dir: Vec3) -> (f32, f32) {
+        let n = (p - self.c).normalized();
+        (1.0 / (4.0 * PI * self.r * self.r), n.dot(dir.normalized()).max(0.0) / PI)
+    }
+
+    fn on_surface(&self, p: Vec3) -> bool {
+        ((p - self.c).len() - self.r).abs() <= 1e-3 * self.r
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let r = Vec3::new(self.r, self.r, self.r);
+        Some(LightBounds {
+            bounds: AABB::new(self.c - r, self.c + r),
+            w: Vec3::new(0.0, 0.0, 1.0),
+            phi: self.power(0.0),
+            cos_theta_o: -1.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+}
+
+/*
+ * Light description as given on the command line, fields separated by
+ * colons and vectors by commas:
+ *   point:x,y,z:r,g,b[:range]
+ *   spot:x,y,z:dx,dy,dz:r,g,b:inner_deg,outer_deg[:range]
+ *   directional:dx,dy,dz:r,g,b
+ *   sphere:x,y,z:radius:r,g,b
+ *   mesh:file.obj:r,g,b[:x,y,z]
+ * The last two add emissive geometry, every triangle of a mesh being a
+ * light of its own.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum LightDesc {
+    Point { pos: Vec3, intensity: Vec3, range: f32 },
+    Spot { pos: Vec3, dir: Vec3, intensity: Vec3, inner: f32, outer: f32, range: f32 },
+    Directional { dir: Vec3, irradiance: Vec3 },
+    Sphere { center: Vec3, radius: f32, radiance: Vec3 },
+    Mesh { path: String, radiance: Vec3, offset: Vec3 },
+}
+
+fn parse_floats(s: &str) -> Result<Vec<f32>, String> {
+    s.split(',')
+     .map(|f| parse_f32(f.trim()))
+     .collect()
+}
+
+fn parse_range(s: Option<&&str>) -> Result<f32, String> {
+    match s {
+        Some(s) => s.parse::<f32>().map_err(|_| format!("Invalid range: {}", s)),
+        None => Ok(0.0),
+    }
+}
+
+impl FromStr for LightDesc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        match fields.as_slice() {
+            ["point", pos, intensity, rest @ ..] if rest.len() <= 1 => Ok(LightDesc::Point {
+                pos: parse_vec3(pos)?,
+                intensity: parse_vec3(intensity)?,
+                range: parse_range(rest.first())?,
+            }),
+            ["spot", pos, dir, intensity, angles, rest @ ..] if rest.len() <= 1 => {
+                let angles = parse_floats(angles)?;
+                if angles.len() != 2 {
+                    return Err(format!("Expected inner and outer angle: {}", s));
+                }
+                Ok(LightDesc::Spot {
+                    pos: parse_vec3(pos)?,
+                    dir: parse_vec3(dir)?,
+                    intensity: parse_vec3(intensity)?,
+                    inner: angles[0].to_radians(),
+                    outer: angles[1].to_radians(),
+                    range: parse_range(rest.first())?,
+                })
+            }
+            ["directional", dir, irradiance] => Ok(LightDesc::Directional {
+                dir: parse_vec3(dir)?,
+                irradiance: parse_vec3(irradiance)?,
+            }),
+            ["sphere", center, radius, radiance] => Ok(LightDesc::Sphere {
+                center: parse_vec3(center)?,
+                radius: radius.parse::<f32>().map_err(|_| format!("Invalid radius: {}", radius))?,
+                radiance: parse_vec3(radiance)?,
+            }),
+            ["mesh", path, radiance, rest @ ..] if rest.len() <= 1 => Ok(LightDesc::Mesh {
+                path: path.to_string(),
+                radiance: parse_vec3(radiance)?,
+                offset: match rest.first() {
+                    Some(offset) => parse_vec3(offset)?,
+                    None => Vec3::zero(),
+                },
+            }),
+            _ => Err(format!("Invalid light: {}", s)),
+        }
+    }
+}
+
+/* Lights built from a LightDesc along with the geometry they need in the scene */
+pub struct Emitter<'a> {
+    pub lights: Vec<Box<dyn Light>>,
+    pub shapes: Shapes<'a>,
+}
+
+impl<'a> Emitter<'a> {
+    fn light(light: Box<dyn Light>) -> Emitter<'a> {
+        Emitter { lights: vec![light], shapes: Shapes { spheres: Vec::new(), tris: Vec::new() } }
+    }
+}
+
+impl LightDesc {
+    /* Material of the emissive geometry, black for punctual lights */
+    pub fn material(&self) -> DiffuseLight {
+        match *self {
+            LightDesc::Sphere { radiance, .. } => DiffuseLight::new(radiance, false),
+            LightDesc::Mesh { radiance, .. } => DiffuseLight::new(radiance, true),
+            _ => DiffuseLight::new(Vec3::zero(), false),
+        }
+    }
+
+    /* mat is the material() of this description, kept alive by the caller */
+    pub fn build<'a>(&self, mat: &'a dyn Material) -> Result<Emitter<'a>, String> {
+        match *self {
+            LightDesc::Point { pos, intensity, range } =>
+                Ok(Emitter::light(Box::new(PointLight::new(pos, intensity, range)))),
+            LightDesc::Spot { pos, dir, intensity, inner, outer, range } =>
+                Ok(Emitter::light(Box::new(SpotLight::new(pos, dir, intensity, inner, outer, range)))),
+            LightDesc::Directional { dir, irradiance } =>
+                Ok(Emitter::light(Box::new(DirectionalLight::new(dir, irradiance)))),
+            LightDesc::Sphere { center, radius, radiance } => Ok(Emitter {
+                lights: vec![Box::new(SphereLight::new(center, radius, radiance))],
+                shapes: Shapes { spheres: vec![Sphere::new(center, radius, mat)], tris: Vec::new() },
+            }),
+            LightDesc::Mesh { ref path, radiance, offset } => {
+                let mesh = Mesh::load_obj(path).map_err(|why| format!("Could not load {}: {}", path, why))?;
+                let mut tris = mesh.get_mesh(mat);
+                let mut lights: Vec<Box<dyn Light>> = Vec::new();
+                for tri in &mut tris {
+                    for v in &mut tri.verts {
+                        *v += offset;
+                    }
+                    lights.push(Box::new(TriangleLight::new(tri.verts, radiance, true)));
+                }
+                Ok(Emitter { lights, shapes: Shapes { spheres: Vec::new(), tris } })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lights() {
+        let point: LightDesc = "point:0,5,0:10,10,10".parse().unwrap();
+        assert_eq!(point, LightDesc::Point {
+            pos: Vec3::new(0.0, 5.0, 0.0),
+            intensity: Vec3::one() * 10.0,
+            range: 0.0,
+        });
+        assert!("spot:0,5,0:0,-1,0:1,1,1:20,30:8".parse::<LightDesc>().is_ok());
+        assert!("directional:0,-1,0:3,3,3".parse::<LightDesc>().is_ok());
+        assert!("sphere:0,5,0:0.5:4,4,4".parse::<LightDesc>().is_ok());
+        assert!("mesh:lamp.obj:4,4,4:0,1,0".parse::<LightDesc>().is_ok());
+        assert!("point:0,5:1,1,1".parse::<LightDesc>().is_err());
+        assert!("area:0,5,0:1,1,1".parse::<LightDesc>().is_err());
+    }
+
+    /* Average of 1 / pdf over the samples, i.e. the solid angle covered by the light */
+    fn solid_angle(light: &dyn Light, p: Vec3) -> f32 {
+        let n = 32;
+        let mut sum = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let u = ((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32);
+                let s = light.sample_li(p, u).unwrap();
+                assert!(s.wi.y > 0.0 && s.dist > 0.0);
+                sum += 1.0 / s.pdf;
+            }
+        }
+        sum / (n * n) as f32
+    }
+
+    #[test]
+    fn test_area_light_pdf() {
+        let sphere = SphereLight::new(Vec3::new(0.0, 4.0, 0.0), 1.0, Vec3::one());
+        let expected = 2.0 * PI * (1.0 - (15.0f32 / 16.0).sqrt());
+        assert!((solid_angle(&sphere, Vec3::zero()) - expected).abs() / expected < 1e-3);
+
+        /* Small and far away, so the solid angle is close to area / distance^2 */
+        let tri = TriangleLight::new([Vec3::new(-0.5, 20.0, -0.5), Vec3::new(0.5, 20.0, -0.5),
+                                      Vec3::new(0.0, 20.0, 0.5)], Vec3::one(), true);
+        let expected = 0.5 / 400.0;
+        assert!((solid_angle(&tri, Vec3::zero()) - expected).abs() / expected < 1e-2);
+    }
+
+    #[test]
+    fn test_spot_cone() {
+        let spot = SpotLight::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::one(),
+                                  10f32.to_radians(), 20f32.to_radians(), 0.0);
+        let inside = spot.sample_li(Vec3::zero(), (0.5, 0.5)).unwrap();
+        assert_eq!(inside.radiance, Vec3::one());
+        assert!(spot.sample_li(Vec3::new(1.0, 0.0, 0.0), (0.5, 0.5)).is_none());
+        let edge = spot.sample_li(Vec3::new(15f32.to_radians().tan(), 0.0, 0.0), (0.5, 0.5)).unwrap();
+        assert!(edge.radiance.x > 0.0 && edge.radiance.x < 1.0);
+    }
+}
@@ -0,0 +1,453 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::aabb::AABB;
+use crate::distribution::Distribution1D;
+use crate::light::Light;
+
+/* A light picked for next event estimation and the probability of picking it */
+pub struct SampledLight {
+    pub index: usize,
+    pub pmf: f32,
+}
+
+/* Chooses one of the scene lights for a shading point p with normal n */
+pub trait LightSampler {
+    fn sample(&self, p: Vec3, n: Vec3, u: f32) -> Option<SampledLight>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightSamplerKind {
+    Uniform,
+    Power,
+    BVH,
+}
+
+impl FromStr for LightSamplerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(LightSamplerKind::Uniform),
+            "power" => Ok(LightSamplerKind::Power),
+            "bvh" => Ok(LightSamplerKind::BVH),
+            _ => Err(format!("Unknown light sampler: {}", s)),
+        }
+    }
+}
+
+/* scene_radius is only used to estimate the power of lights at infinity */
+pub fn new_light_sampler(kind: LightSamplerKind, lights: &[Box<dyn Light>],
+                         scene_radius: f32) -> Box<dyn LightSampler> {
+    match kind {
+        LightSamplerKind::Uniform => Box::new(UniformLightSampler { count: lights.len() }),
+        LightSamplerKind::Power => Box::new(PowerLightSampler::new(lights, scene_radius)),
+        LightSamplerKind::BVH => Box::new(BVHLightSampler::new(lights)),
+    }
+}
+
+pub struct UniformLightSampler {
+    count: usize,
+}
+
+impl LightSampler for UniformLightSampler {
+    fn sample(&self, _p: Vec3, _n: Vec3, u: f32) -> Option<SampledLight> {
+        if self.count == 0 {
+            return None;
+        }
+        let index = ((u * self.count as f32) as usize).min(self.count - 1);
+        Some(SampledLight { index, pmf: 1.0 / self.count as f32 })
+    }
+}
+
+/* Picks lights proportionally to their emitted power, regardless of the shading point */
+pub struct PowerLightSampler {
+    distribution: Option<Distribution1D>,
+}
+
+impl PowerLightSampler {
+    pub fn new(lights: &[Box<dyn Light>], scene_radius: f32) -> PowerLightSampler {
+        if lights.is_empty() {
+            return PowerLightSampler { distribution: None };
+        }
+        let power: Vec<f32> = lights.iter().map(|light| light.power(scene_radius)).collect();
+        PowerLightSampler { distribution: Some(Distribution1D::new(&power)) }
+    }
+}
+
+impl LightSampler for PowerLightSampler {
+    fn sample(&self, _p: Vec3, _n: Vec3, u: f32) -> Option<SampledLight> {
+        let (index, pmf) = self.distribution.as_ref()?.sample_discrete(u);
+        Some(SampledLight { index, pmf })
+    }
+}
+
+/* Cone of directions around the unit axis w */
+#[derive(Copy, Clone, Debug)]
+struct DirectionCone {
+    w: Vec3,
+    cos_theta: f32,
+}
+
+/* Rotates v around the unit axis by angle theta */
+fn rotate(v: Vec3, axis: Vec3, theta: f32) -> Vec3 {
+    let (s, c) = theta.sin_cos();
+    v * c + axis.cross(v) * s + axis * (axis.dot(v) * (1.0 - c))
+}
+
+impl DirectionCone {
+    fn entire_sphere() -> DirectionCone {
+        DirectionCone { w: Vec3::new(0.0, 0.0, 1.0), cos_theta: -1.0 }
+    }
+
+    /* Smallest cone containing both a and b */
+    fn union(a: DirectionCone, b: DirectionCone) -> DirectionCone {
+        let theta_a = a.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = b.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = a.w.dot(b.w).clamp(-1.0, 1.0).acos();
+
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return a;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return b;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= PI {
+            return DirectionCone::entire_sphere();
+        }
+
+        let axis = a.w.cross(b.w);
+        if axis.len2() == 0.0 {
+            return DirectionCone::entire_sphere();
+        }
+        let w = rotate(a.w, axis.normalized(), theta_o - theta_a);
+        DirectionCone { w: w.normalized(), cos_theta: theta_o.cos() }
+    }
+}
+
+/*
+ * Conservative bounds on where a light is and in which directions it
+ * emits: the normals lie within cos_theta_o of w and light leaves each
+ * normal at most at cos_theta_e. Kulla and Conty, "Importance Sampling of
+ * Many Lights with Adaptive Tree Splitting", 2018.
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct LightBounds {
+    pub bounds: AABB,
+    pub w: Vec3,
+    /* Emitted power */
+    pub phi: f32,
+    pub cos_theta_o: f32,
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+/* cos(max(0, a - b)) given the sines and cosines of a and b */
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 1.0 } else { cos_a * cos_b + sin_a * sin_b }
+}
+
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32 {
+    if cos_a > cos_b { 0.0 } else { sin_a * cos_b - cos_a * sin_b }
+}
+
+fn sin_from_cos(cos: f32) -> f32 {
+    (1.0 - cos * cos).max(0.0).sqrt()
+}
+
+fn surface_area(b: &AABB) -> f32 {
+    let d = b.max - b.min;
+    2.0 * (d.x * d.y + d.x * d.z + d.y * d.z)
+}
+
+impl LightBounds {
+    fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+        let cone = DirectionCone::union(DirectionCone { w: a.w, cos_theta: a.cos_theta_o },
+                                        DirectionCone { w: b.w, cos_theta: b.cos_theta_o });
+        LightBounds {
+            bounds: a.bounds.merge(&b.bounds),
+            w: cone.w,
+            phi: a.phi + b.phi,
+            cos_theta_o: cone.cos_theta,
+            cos_theta_e: a.cos_theta_e.min(b.cos_theta_e),
+            two_sided: a.two_sided || b.two_sided,
+        }
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.bounds.min + self.bounds.max) * 0.5
+    }
+
+    /* Upper bound on the contribution to p, n may be zero for points not on a surface */
+    fn importance(&self, p: Vec3, n: Vec3) -> f32 {
+        let pc = self.centroid();
+        let dist2 = (p - pc).len2();
+        if dist2 == 0.0 {
+            return self.phi;
+        }
+        let d2 = dist2.max((self.bounds.max - self.bounds.min).len() / 2.0);
+        let wi = (p - pc).normalized();
+
+        let mut cos_theta_w = self.w.dot(wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = sin_from_cos(cos_theta_w);
+
+        /* Directions from p subtended by the bounding sphere of the box */
+        let radius = (self.bounds.max - pc).len();
+        let cos_theta_b = if dist2 < radius * radius {
+            -1.0
+        } else {
+            (1.0 - radius * radius / dist2).max(0.0).sqrt()
+        };
+        let sin_theta_b = sin_from_cos(cos_theta_b);
+
+        /* Angle between the emission cone and the direction to p, shrunk by the bounds */
+        let sin_theta_o = sin_from_cos(self.cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / d2;
+        if n != Vec3::zero() {
+            let cos_theta_i = wi.dot(n).abs();
+            let sin_theta_i = sin_from_cos(cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        importance.max(0.0)
+    }
+
+    /* Solid angle measure of the emission used by the build cost */
+    fn orientation_measure(&self) -> f32 {
+        let theta_o = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let sin_theta_o = sin_from_cos(self.cos_theta_o);
+        2.0 * PI * (1.0 - self.cos_theta_o) +
+            PI / 2.0 * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() -
+                        2.0 * theta_o * sin_theta_o + self.cos_theta_o)
+    }
+}
+
+const SPLIT_BUCKETS: usize = 12;
+
+enum LightNodeKind {
+    Leaf(usize),
+    /* The first child directly follows its parent */
+    Interior(usize),
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    kind: LightNodeKind,
+}
+
+/*
+ * Bounding volume hierarchy over the lights with finite bounds, traversed
+ * stochastically by the estimated importance of each child. Lights at
+ * infinity are picked uniformly with a probability of their own.
+ */
+pub struct BVHLightSampler {
+    nodes: Vec<LightNode>,
+    infinite: Vec<usize>,
+}
+
+impl BVHLightSampler {
+    pub fn new(lights: &[Box<dyn Light>]) -> BVHLightSampler {
+        let mut infinite = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(bounds) if bounds.phi > 0.0 => bounded.push((i, bounds)),
+                Some(_) => (),
+                None => infinite.push(i),
+            }
+        }
+
+        let mut sampler = BVHLightSampler { nodes: Vec::new(), infinite };
+        if !bounded.is_empty() {
+            sampler.build(&mut bounded);
+        }
+        sampler
+    }
+
+    fn build(&mut self, items: &mut [(usize, LightBounds)]) -> usize {
+        let bounds = items[1..].iter().fold(items[0].1, |acc, item| LightBounds::union(&acc, &item.1));
+        let index = self.nodes.len();
+        if items.len() == 1 {
+            self.nodes.push(LightNode { bounds, kind: LightNodeKind::Leaf(items[0].0) });
+            return index;
+        }
+
+        self.nodes.push(LightNode { bounds, kind: LightNodeKind::Interior(0) });
+        let mid = Self::split(items, &bounds);
+        let (left, right) = items.split_at_mut(mid);
+        self.build(left);
+        let second = self.build(right);
+        self.nodes[index].kind = LightNodeKind::Interior(second);
+        index
+    }
+
+    /*
+     * Orders the items and returns the split point minimizing the bucketed
+     * cost of power times orientation measure times surface area.
+     */
+    fn split(items: &mut [(usize, LightBounds)], bounds: &LightBounds) -> usize {
+        let first = items[0].1.centroid();
+        let (cmin, cmax) = items.iter().fold((first, first), |(lo, hi), item| {
+            let c = item.1.centroid();
+            (Vec3::new(lo.x.min(c.x), lo.y.min(c.y), lo.z.min(c.z)),
+             Vec3::new(hi.x.max(c.x), hi.y.max(c.y), hi.z.max(c.z)))
+        });
+        let extent = bounds.bounds.max - bounds.bounds.min;
+        let max_extent = extent.max_component();
+
+        let bucket_of = |c: Vec3, dim: usize| {
+            let t = (c[dim] - cmin[dim]) / (cmax[dim] - cmin[dim]);
+            ((t * SPLIT_BUCKETS as f32) as usize).min(SPLIT_BUCKETS - 1)
+        };
+
+        let mut best: Option<(f32, usize, usize)> = None;
+        for dim in 0..3 {
+            if cmax[dim] == cmin[dim] {
+                continue;
+            }
+            let mut buckets: [Option<LightBounds>; SPLIT_BUCKETS] = [None; SPLIT_BUCKETS];
+            for item in items.iter() {
+                let b = bucket_of(item.1.centroid(), dim);
+                buckets[b] = Some(match &buckets[b] {
+                    Some(acc) => LightBounds::union(acc, &item.1),
+                    None => item.1,
+                });
+            }
+
+            /* Thin boxes are penalized so splits don't produce slivers */
+            let kr = max_extent / extent[dim].max(1e-6);
+            let cost = |b: &LightBounds| b.phi * b.orientation_measure() * surface_area(&b.bounds);
+            let merge = |acc: Option<LightBounds>, b: &Option<LightBounds>| match (acc, b) {
+                (Some(acc), Some(b)) => Some(LightBounds::union(&acc, b)),
+                (acc, b) => acc.or(*b),
+            };
+
+            for split in 1..SPLIT_BUCKETS {
+                let left = buckets[..split].iter().fold(None, merge);
+                let right = buckets[split..].iter().fold(None, merge);
+                if let (Some(left), Some(right)) = (left, right) {
+                    let c = kr * (cost(&left) + cost(&right));
+                    if best.is_none_or(|(best_cost, _, _)| c < best_cost) {
+                        best = Some((c, dim, split));
+                    }
+                }
+            }
+        }
+
+        match best {
+            Some((_, dim, split)) => {
+                items.sort_by_key(|item| bucket_of(item.1.centroid(), dim));
+                items.partition_point(|item| bucket_of(item.1.centroid(), dim) < split)
+            }
+            /* All centroids coincide */
+            None => items.len() / 2,
+        }
+    }
+}
+
+impl LightSampler for BVHLightSampler {
+    fn sample(&self, p: Vec3, n: Vec3, u: f32) -> Option<SampledLight> {
+        let p_infinite = if self.nodes.is_empty() {
+            if self.infinite.is_empty() { 0.0 } else { 1.0 }
+        } else {
+            self.infinite.len() as f32 / (self.infinite.len() + 1) as f32
+        };
+
+        if u < p_infinite {
+            let count = self.infinite.len();
+            let i = ((u / p_infinite * count as f32) as usize).min(count - 1);
+            return Some(SampledLight { index: self.infinite[i], pmf: p_infinite / count as f32 });
+        }
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut u = ((u - p_infinite) / (1.0 - p_infinite)).min(1.0 - f32::EPSILON);
+        let mut pmf = 1.0 - p_infinite;
+        let mut node = 0;
+        loop {
+            match self.nodes[node].kind {
+                LightNodeKind::Leaf(index) => {
+                    if node > 0 || self.nodes[node].bounds.importance(p, n) > 0.0 {
+                        return Some(SampledLight { index, pmf });
+                    }
+                    return None;
+                }
+                LightNodeKind::Interior(second) => {
+                    let c0 = self.nodes[node + 1].bounds.importance(p, n);
+                    let c1 = self.nodes[second].bounds.importance(p, n);
+                    if c0 == 0.0 && c1 == 0.0 {
+                        return None;
+                    }
+                    let p0 = c0 / (c0 + c1);
+                    if u < p0 {
+                        node += 1;
+                        u = (u / p0).min(1.0 - f32::EPSILON);
+                        pmf *= p0;
+                    } else {
+                        node = second;
+                        u = ((u - p0) / (1.0 - p0)).min(1.0 - f32::EPSILON);
+                        pmf *= 1.0 - p0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::{PointLight, DirectionalLight};
+
+    fn lights() -> Vec<Box<dyn Light>> {
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for i in 0..8 {
+            let pos = Vec3::new(i as f32 * 10.0, 0.0, 0.0);
+            lights.push(Box::new(PointLight::new(pos, Vec3::one() * (i + 1) as f32, 0.0)));
+        }
+        lights.push(Box::new(DirectionalLight::new(Vec3::new(0.0, -1.0, 0.0), Vec3::one())));
+        lights
+    }
+
+    /* Every sampler must pick each light with a probability matching its pmf */
+    fn check_pmf(sampler: &dyn LightSampler, count: usize, p: Vec3) -> Vec<u32> {
+        let n = 4096;
+        let mut picked = vec![0; count];
+        let mut pmf = vec![0.0; count];
+        for i in 0..n {
+            let s = sampler.sample(p, Vec3::zero(), (i as f32 + 0.5) / n as f32).unwrap();
+            picked[s.index] += 1;
+            pmf[s.index] = s.pmf;
+        }
+        for i in 0..count {
+            assert!((picked[i] as f32 / n as f32 - pmf[i]).abs() < 1e-2);
+        }
+        picked
+    }
+
+    #[test]
+    fn test_light_samplers() {
+        let lights = lights();
+        let p = Vec3::new(1.0, 1.0, 0.0);
+        check_pmf(&UniformLightSampler { count: lights.len() }, lights.len(), p);
+        check_pmf(&PowerLightSampler::new(&lights, 10.0), lights.len(), p);
+
+        /* The nearby light is picked far more often than the brighter distant ones */
+        let picked = check_pmf(&BVHLightSampler::new(&lights), lights.len(), p);
+        assert!(picked[0] > 4 * picked[7]);
+    }
+}
@@ -1,3 +1,9 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
 mod vec3;
 mod ray;
 mod sphere;
@@ -9,68 +15,381 @@ mod bvh;
 mod aabb;
 mod tri;
 mod mesh;
+mod options;
+mod sampler;
+mod warp;
+mod film;
+mod image;
+mod integrator;
+mod distribution;
+mod background;
+mod scene;
+mod sky;
+mod light;
+mod lightsampler;
+mod medium;
+mod volume;
+mod transform;
+mod instance;
+mod aperture;
+mod lens;
+mod filter;
+mod tonemap;
+mod aov;
+mod denoise;
+mod checkpoint;
+mod stats;
+mod debug;
+mod bdpt;
+mod sppm;
 
 use ray::Ray;
 use vec3::Vec3;
 use sphere::Sphere;
-use hittable::{Hittable, HittableList};
-use rng::*;
-use material::{Lambertian, Metal, Dielectric};
-use camera::Camera;
+use hittable::{Hittable, Tagged};
+use sampler::{new_sampler, Sampler};
+use material::{Material, Lambertian, Metal, Dielectric};
+use camera::*;
 use bvh::BVH;
 use tri::Tri;
 use mesh::Mesh;
+use options::Options;
+use film::Film;
+use aov::{AovFilm, AovKind, AovSample};
+use denoise::{new_denoiser, DenoiseInput};
+use checkpoint::RenderState;
+use stats::{BvhStats, ImageStats};
+use debug::{heatmap, DebugIntegrator, DebugMode};
+use filter::new_filter;
+use tonemap::OutputTransform;
+use integrator::{new_integrator, IntegratorKind};
+use sppm::PhotonMapper;
+use scene::Scene;
+use background::*;
+use image::Image;
+use sky::PhysicalSky;
+use light::Light;
+use lightsampler::new_light_sampler;
+use medium::Medium;
+use aperture::new_aperture;
+use lens::RealisticCamera;
 
-fn save_image(w: usize, h: usize, pixels: &[Vec3]) {
-    println!("P3");
-    println!("{} {}", w, h);
-    println!("255");
-    for y in 0..h {
-        for x in 0..w {
-            let p = pixels[x + y * w] * 255.99;
-            print!("{} {} {} ", p.x as u32, p.y as u32, p.z as u32);
+fn load_background(opts: &Options) -> Result<Box<dyn Background>, String> {
+    match opts.background {
+        BackgroundKind::Constant => Ok(Box::new(ConstantBackground::new(opts.background_color))),
+        BackgroundKind::Gradient => Ok(Box::new(GradientBackground::sky())),
+        BackgroundKind::EnvMap => {
+            let path = opts.envmap.as_deref().unwrap_or_default();
+            match Image::load(path) {
+                Ok(image) => Ok(Box::new(EnvironmentMap::new(image,
+                                                             opts.env_rotation.to_radians(),
+                                                             opts.env_intensity))),
+                Err(why) => Err(format!("Could not load {}: {}", path, why)),
+            }
         }
-        println!("");
+        BackgroundKind::Sky => Ok(Box::new(PhysicalSky::new(opts.sun_elevation.to_radians(),
+                                                            opts.sun_azimuth.to_radians(),
+                                                            opts.turbidity,
+                                                            opts.sky_intensity))),
     }
 }
 
-fn trace_ray<T: Hittable>(ray: &Ray, hittables: &T, rng: &mut RNG, depth: u32) -> Vec3 {
-    if depth <= 0 {
-        return Vec3::zero();
-    }
+/* Default vertical field of view of the perspective camera, in degrees */
+const PERSPECTIVE_FOV: f32 = 20.0;
 
-    if let Some(rec) = hittables.hit(ray, 0.00001, 9999.0, rng) {
-        if let Some((attennuation, scattered)) = rec.mat.scatter(ray, &rec, rng) {
-            return trace_ray(&scattered, hittables, rng, depth - 1) * attennuation;
-        } else {
-            return Vec3::zero();
+/*
+ * Camera for a view, or for one eye of it. Eyes are moved apart along the
+ * camera's right axis, only perspective and panorama cameras converge at
+ * a finite distance, the others look straight ahead.
+ */
+fn build_camera(opts: &Options, view: &ViewDesc, ar: f32, eye: Option<Eye>) -> Result<Box<dyn Camera>, String> {
+    let center = Frame::look_at(view.pos, view.target, Vec3::new(0.0, 1.0, 0.0));
+    let focus = (view.target - view.pos).len();
+    let (offset, convergence) = match (eye, opts.stereo) {
+        (Some(eye), Some(stereo)) => (eye.offset(stereo.ipd), stereo.convergence.unwrap_or(focus)),
+        _ => (0.0, f32::INFINITY),
+    };
+    let frame = center.shifted(offset);
+
+    let shutter = Shutter { open: opts.shutter_open, close: opts.shutter_close };
+    match opts.camera {
+        CameraKind::Perspective => {
+            let fov = opts.fov.unwrap_or(PERSPECTIVE_FOV);
+            let aperture = new_aperture(&opts.bokeh)?;
+            let cam = PerspectiveCamera::new(frame, ar, fov, opts.aperture, focus, aperture, shutter);
+            Ok(Box::new(cam.converge(offset, convergence)))
+        }
+        CameraKind::Orthographic => {
+            let framed = 2.0 * focus * (PERSPECTIVE_FOV.to_radians() / 2.0).tan();
+            Ok(Box::new(OrthographicCamera::new(frame, ar, opts.ortho_height.unwrap_or(framed), shutter)))
+        }
+        CameraKind::Fisheye => Ok(Box::new(FisheyeCamera::new(frame, ar, opts.fov.unwrap_or(180.0),
+                                                              opts.fisheye_mapping, shutter))),
+        CameraKind::Equirectangular =>
+            Ok(Box::new(EquirectangularCamera::new(center, shutter).stereo(offset, convergence))),
+        CameraKind::Realistic => {
+            let elements = RealisticCamera::load(opts.lens.as_deref().unwrap_or_default())?;
+            let aperture = new_aperture(&opts.bokeh)?;
+            Ok(Box::new(RealisticCamera::new(frame, ar, elements, opts.film_diagonal, focus,
+                                             aperture, shutter)?))
         }
     }
+}
+
+/* Separate AOV images are float maps next to the output, out.ppm gives out-albedo.pfm */
+fn aov_path(path: &str, name: &str) -> String {
+    let stem = match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => stem,
+        _ => path,
+    };
+    format!("{}-{}.pfm", stem, name)
+}
+
+/* Output path of one of several images, the name goes before the extension */
+fn image_path(path: &str, name: &str) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => format!("{}-{}.{}", stem, name, ext),
+        _ => format!("{}-{}", path, name),
+    }
+}
 
-    /* Fake sky */
-    let unit_dir = ray.dir.normalized();
-    let t = 0.5 * (unit_dir.y + 1.0);
-    Vec3::one() * (1.0 - t) + Vec3::new(0.5, 0.7, 1.0) * t
+/*
+ * When the rendering of an image started at now has to end, with elapsed of
+ * the budget gone already. What is left is shared by the images_left still
+ * to render, this one included.
+ */
+fn image_deadline(budget: f32, elapsed: Duration, images_left: usize, now: Instant) -> Instant {
+    let left = (budget - elapsed.as_secs_f32()).max(0.0);
+    /* Budgets too long to represent never run out */
+    let forever = Duration::from_secs(100 * 365 * 24 * 3600);
+    let share = Duration::try_from_secs_f32(left / images_left as f32).unwrap_or(forever);
+    now + share.min(forever)
 }
 
+/* How many samples the pixels get, and how many of them in each pass */
+struct Schedule {
+    samples_per_pixel: u32,
+    max_spp: u32,
+    pass_spp: u32,
+}
+
+impl Schedule {
+    /*
+     * In adaptive mode spp is the minimum, pixels stop once they converge
+     * or reach max_spp. Otherwise a deadline lifts the limit of spp.
+     */
+    fn new(opts: &Options, deadline: bool) -> Schedule {
+        let samples_per_pixel = opts.spp.max(1);
+        let max_spp = match (opts.adaptive, deadline) {
+            (true, _) => opts.max_spp.max(samples_per_pixel),
+            (false, true) => u32::MAX,
+            (false, false) => samples_per_pixel,
+        };
+        /* Against a deadline passes are kept short by default so the image fills in evenly */
+        let pass_spp = opts.pass_spp.unwrap_or(if deadline { 1 } else { samples_per_pixel });
+        Schedule { samples_per_pixel, max_spp, pass_spp }
+    }
+}
+
+/* The samples per pixel achieved, on average and the least and most of any pixel */
+fn spp_summary(counts: &[f32]) -> String {
+    format!("{:.1} samples per pixel, {} to {}", counts.iter().sum::<f32>() / counts.len() as f32,
+            counts.iter().cloned().fold(f32::INFINITY, f32::min), counts.iter().cloned().fold(0.0, f32::max))
+}
+
+/* Where the state of an image is saved between passes and when the passes have to end */
+struct Passes<'a> {
+    checkpoint: Option<&'a str>,
+    deadline: Option<Instant>,
+}
+
+/*
+ * Renders the scene through one camera in passes over the image, each
+ * adding up to pass_spp samples to the pixels that still need any. Pixels
+ * around the crop window are sampled too as far as the filter reaches into
+ * it, but only the ones inside are sampled adaptively. The state is saved
+ * to the checkpoint between passes and after the last one. With a deadline
+ * no pass is started that is expected to end after it, and one that runs
+ * over it anyway is cut short between pixels.
+ */
+fn render(cam: &dyn Camera, scene: &Scene, opts: &Options, sampler: &mut dyn Sampler,
+          img_w: usize, img_h: usize, passes: Passes) -> Result<(Film, AovFilm), String> {
+    let Passes { checkpoint, deadline } = passes;
+    let mut integrator = new_integrator(opts.integrator, opts.depth, opts.ao_radius, opts.ao_samples,
+                                    cam, scene, (img_w, img_h));
+    if let Some(mode) = opts.debug {
+        integrator = Box::new(DebugIntegrator { mode, inner: integrator });
+    }
+    let filter = new_filter(opts.filter, opts.filter_radius);
+    let mut film = Film::new(img_w, img_h, opts.crop, filter);
+    /* The denoiser is guided by AOVs of its own, written out only if asked for */
+    let mut kinds = opts.aovs.clone();
+    if opts.denoiser.is_some() {
+        for kind in [AovKind::Albedo, AovKind::Normal, AovKind::Depth] {
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+    }
+    let record_aovs = !kinds.is_empty();
+    let mut aov_film = AovFilm::new(kinds, film.crop);
+
+    /* Photon mapping goes over the whole image in every iteration, spp of them, the options reject passes and checkpoints */
+    if opts.integrator == IntegratorKind::PhotonMapping && opts.debug.is_none() {
+        let mapper = PhotonMapper {
+            policy: opts.depth,
+            iterations: opts.spp.max(1),
+            photons: opts.photons.unwrap_or(img_w * img_h),
+            radius: opts.photon_radius,
+        };
+        mapper.render(cam, scene, sampler, &mut film, &mut aov_film);
+        return Ok((film, aov_film));
+    }
+
+    let Schedule { samples_per_pixel, max_spp, pass_spp } = Schedule::new(opts, deadline.is_some());
+
+    let bounds = film.sample_bounds();
+    let mut counts = vec![0u32; bounds.w() * bounds.h()];
+    if let (Some(path), true) = (checkpoint, opts.resume) {
+        let mut state = RenderState { seed: opts.seed, film: &mut film, aovs: &mut aov_film, counts: &mut counts };
+        state.load(path).map_err(|why| format!("Could not resume from {}: {}", path, why))?;
+    }
+
+    let mut last_checkpoint = Instant::now();
+    for pass in 1.. {
+        let pass_start = Instant::now();
+        let mut sampled = false;
+        let mut out_of_time = false;
+        'pass: for y in bounds.y0..bounds.y1 {
+            eprint!("\rPass {}: {:.1}%", pass, (y - bounds.y0) as f32 / bounds.h() as f32 * 100.0);
+            for x in bounds.x0..bounds.x1 {
+                /* A pass that overruns the deadline stops where it is, pixels keep their own counts */
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    out_of_time = true;
+                    break 'pass;
+                }
+                let count = &mut counts[(x - bounds.x0) + (y - bounds.y0) * bounds.w()];
+                let converged = opts.adaptive && *count >= samples_per_pixel && match film.pixel(x, y) {
+                    Some(stats) => stats.relative_error() < opts.target_error,
+                    None => true,
+                };
+                if *count >= max_spp || converged {
+                    continue;
+                }
+                sampled = true;
+                let pass_end = count.saturating_add(pass_spp).min(max_spp);
+                while *count < pass_end {
+                    sampler.start_pixel_sample(x + y * img_w, *count);
+                    let jitter = sampler.get_2d();
+                    let u = (x as f32 + jitter.0) / img_w as f32;
+                    let v = 1.0 - (y as f32 + jitter.1) / img_h as f32;
+                    let mut aov = AovSample::default();
+                    let radiance = match cam.get_ray(u, v, sampler) {
+                        Some(ray) => integrator.li(&ray, scene, sampler, if record_aovs { Some(&mut aov) } else { None }),
+                        None => Vec3::zero(),
+                    };
+                    film.add_sample(x, y, jitter, radiance);
+                    film.add_splats(&integrator.take_splats());
+                    aov_film.add_sample(x, y, jitter, radiance, &aov);
+                    *count += 1;
+                }
+            }
+        }
+
+        out_of_time |= deadline.is_some_and(|deadline| Instant::now() + pass_start.elapsed() > deadline);
+        let finished = !sampled || out_of_time;
+        let interval_over = last_checkpoint.elapsed().as_secs_f32() >= opts.checkpoint_interval;
+        if let Some(path) = checkpoint.filter(|_| finished || interval_over) {
+            let state = RenderState { seed: opts.seed, film: &mut film, aovs: &mut aov_film, counts: &mut counts };
+            state.save(path).map_err(|why| format!("Could not write the checkpoint {}: {}", path, why))?;
+            last_checkpoint = Instant::now();
+        }
+        if finished {
+            break;
+        }
+    }
+    Ok((film, aov_film))
+}
 
 fn main() {
-    let img_ar = 16.0 / 9.0;
-    let img_w = 400;
-    let img_h = (img_w as f32  / img_ar) as usize;
-    let mut img = vec![Vec3::zero(); img_w * img_h];
+    let start = Instant::now();
+    let opts = match Options::from_args(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(why) => {
+            eprintln!("{}", why);
+            std::process::exit(1);
+        }
+    };
 
-    let samples_per_pixel = 1;
+    let img_w = opts.width;
+    let img_h = opts.height.unwrap_or((img_w as f32 / (16.0 / 9.0)) as usize).max(1);
+    let img_ar = img_w as f32 / img_h as f32;
+    let mut sampler = new_sampler(opts.sampler, opts.seed, opts.spp.max(1));
 
-    let mut rng = RNG::new();
+    let teapot = match Mesh::load_obj("teapot.obj") {
+        Ok(mesh) => mesh,
+        Err(why) => {
+            eprintln!("Could not load teapot.obj: {}", why);
+            std::process::exit(1);
+        }
+    };
 
-    let teapot = Mesh::load_obj("teapot.obj").unwrap();
+    /* The scene's own camera comes first, followed by the named views given on the command line */
+    let main_view = ViewDesc {
+        name: "main".to_string(),
+        pos: Vec3::new(15.0, 2.0, 10.0),
+        target: Vec3::new(0.0, 0.0, -1.0),
+    };
+    let views: Vec<&ViewDesc> = std::iter::once(&main_view).chain(&opts.views).collect();
+    for (i, view) in views.iter().enumerate() {
+        if views[..i].iter().any(|other| other.name == view.name) {
+            eprintln!("Duplicate view: {}", view.name);
+            std::process::exit(1);
+        }
+    }
+    let selected: Vec<&ViewDesc> = match &opts.render_views {
+        Some(names) => {
+            let mut selected = Vec::new();
+            for name in names {
+                match views.iter().find(|view| &view.name == name) {
+                    Some(view) => selected.push(*view),
+                    None => {
+                        eprintln!("Unknown view: {}", name);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            selected
+        }
+        None => views,
+    };
 
-    let cam_pos = Vec3::new(15.0, 2.0, 10.0);
-    let cam_tgt = Vec3::new(0.0, 0.0, -1.0);
-    let cam_up = Vec3::new(0.0, 1.0, 0.0);
-    let cam_focus = (cam_tgt - cam_pos).len();
-    let cam = Camera::new(cam_pos, cam_tgt, cam_up, img_ar, 20.0, 0.2, cam_focus);
+    /* Every camera is set up front so mistakes show before any rendering */
+    let eyes = match opts.stereo {
+        Some(_) => vec![Some(Eye::Left), Some(Eye::Right)],
+        None => vec![None],
+    };
+    let mut cameras = Vec::new();
+    for view in &selected {
+        for eye in &eyes {
+            let name = match eye {
+                Some(eye) if selected.len() > 1 => format!("{}-{}", view.name, eye.name()),
+                Some(eye) => eye.name().to_string(),
+                None => view.name.clone(),
+            };
+            match build_camera(&opts, view, img_ar, *eye) {
+                Ok(cam) => cameras.push((name, cam)),
+                Err(why) => {
+                    eprintln!("{}", why);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    if cameras.len() > 1 && opts.output.is_none() {
+        eprintln!("Rendering several images needs an --output path");
+        std::process::exit(1);
+    }
 
     let lambertian_b = Lambertian::new(Vec3::new(0.2, 0.3, 0.7));
     let lambertian_r = Lambertian::new(Vec3::new(0.7, 0.3, 0.2));
@@ -97,20 +416,131 @@ fn main() {
         tri.verts[2] -= teapot_offset;
     }
 
-    let mut hittables: Vec<& dyn Hittable> = vec![
-        &sphere0,
-        &sphere1,
-        &sphere2,
-        &sphere3,
-        &tri0,
-        &sphere_large,
+    /* Objects are numbered in this order for the object ID output, the teapot counting as one */
+    let mut objects: Vec<Vec<&dyn Hittable>> = vec![
+        vec![&sphere0],
+        vec![&sphere1],
+        vec![&sphere2],
+        vec![&sphere3],
+        vec![&tri0],
+        vec![&sphere_large],
+        teapot_tris.iter().map(|tri| tri as &dyn Hittable).collect(),
     ];
 
-    for i in 0..teapot_tris.len() {
-        hittables.push(&teapot_tris[i]);
+    let moving_mats: Vec<_> = opts.moving_spheres.iter().map(|desc| Lambertian::new(desc.albedo)).collect();
+    let moving_spheres: Vec<_> = opts.moving_spheres.iter().zip(&moving_mats)
+                                     .map(|(desc, mat)| Sphere::moving(desc.start, desc.end, desc.radius, mat))
+                                     .collect();
+    for sphere in &moving_spheres {
+        objects.push(vec![sphere]);
+    }
+
+    let instance_mats: Vec<_> = opts.instances.iter().map(|desc| Lambertian::new(desc.albedo)).collect();
+    let mut instance_shapes = Vec::new();
+    for (desc, mat) in opts.instances.iter().zip(&instance_mats) {
+        match desc.shapes(mat) {
+            Ok(shapes) => instance_shapes.push(shapes),
+            Err(why) => {
+                eprintln!("{}", why);
+                std::process::exit(1);
+            }
+        }
+    }
+    let instances: Vec<_> = opts.instances.iter().zip(&instance_shapes)
+                                .map(|(desc, shapes)| desc.build(shapes))
+                                .collect();
+    for instance in &instances {
+        objects.push(vec![instance]);
+    }
+
+    /* Emissive geometry of area lights lives next to the rest of the scene */
+    let light_mats: Vec<_> = opts.lights.iter().map(|desc| desc.material()).collect();
+    let mut lights: Vec<Box<dyn Light>> = Vec::new();
+    let mut emitters = Vec::new();
+    for (desc, mat) in opts.lights.iter().zip(&light_mats) {
+        match desc.build(mat) {
+            Ok(mut emitter) => {
+                lights.append(&mut emitter.lights);
+                emitters.push(emitter);
+            }
+            Err(why) => {
+                eprintln!("{}", why);
+                std::process::exit(1);
+            }
+        }
+    }
+    for emitter in &emitters {
+        objects.push(emitter.shapes.hittables().collect());
+    }
+
+    /* Media boundaries are only part of the world if they have a surface */
+    let medium_mats: Vec<_> = opts.media.iter().map(|desc| Dielectric::new(desc.ior.unwrap_or(1.0))).collect();
+    let mut medium_shapes = Vec::new();
+    for (desc, mat) in opts.media.iter().zip(&medium_mats) {
+        match desc.shapes(mat) {
+            Ok(shapes) => medium_shapes.push(shapes),
+            Err(why) => {
+                eprintln!("{}", why);
+                std::process::exit(1);
+            }
+        }
     }
+    let mut media: Vec<Box<dyn Medium>> = opts.media.iter().zip(&medium_shapes)
+                                              .map(|(desc, shapes)| desc.build(shapes))
+                                              .collect();
+    for desc in &opts.volumes {
+        match desc.build() {
+            Ok(volume) => media.push(Box::new(volume)),
+            Err(why) => {
+                eprintln!("{}", why);
+                std::process::exit(1);
+            }
+        }
+    }
+    for (desc, shapes) in opts.media.iter().zip(&medium_shapes) {
+        if desc.ior.is_some() {
+            objects.push(shapes.hittables().collect());
+        }
+    }
+
+    let background = match load_background(&opts) {
+        Ok(background) => background,
+        Err(why) => {
+            eprintln!("{}", why);
+            std::process::exit(1);
+        }
+    };
+
+    /* Tagging costs a call for every primitive tested, so it is only done for the object ID output */
+    let tagged: Vec<Tagged> = if opts.aovs.contains(&AovKind::ObjectId) {
+        objects.iter().enumerate()
+               .flat_map(|(i, parts)| parts.iter().map(move |object| Tagged { id: i as u32 + 1, object: *object }))
+               .collect()
+    } else {
+        Vec::new()
+    };
+    let hittables: Vec<&dyn Hittable> = if tagged.is_empty() {
+        objects.concat()
+    } else {
+        tagged.iter().map(|object| object as &dyn Hittable).collect()
+    };
+    let build_start = Instant::now();
+    let world = BVH::new(hittables);
+    let bvh_stats = BvhStats { build_time: build_start.elapsed(), nodes: world.node_count(), bytes: world.memory() };
+    let scene_radius = match world.get_aabb() {
+        Some(aabb) => (aabb.max - aabb.min).len() / 2.0,
+        None => 0.0,
+    };
+    let light_sampler = new_light_sampler(opts.light_sampler, &lights, scene_radius);
+
+    /* Materials are numbered in this order for the material ID output */
+    let mut materials: Vec<&dyn Material> = vec![&lambertian_b, &lambertian_r, &metal_g, &metal_r, &dielectric];
+    materials.extend(moving_mats.iter().map(|mat| mat as &dyn Material));
+    materials.extend(instance_mats.iter().map(|mat| mat as &dyn Material));
+    materials.extend(light_mats.iter().map(|mat| mat as &dyn Material));
+    materials.extend(medium_mats.iter().map(|mat| mat as &dyn Material));
 
-    let bvh = BVH::new(hittables);
+    let scene = Scene { world, background, lights, light_sampler, media, materials };
 
     /*
     let hittables = HittableList {
@@ -124,27 +554,191 @@ fn main() {
     };
     */
 
-    let scale = 1.0 / samples_per_pixel as f32;
-
-    let render_start = std::time::Instant::now();
-    for y in 0..img_h {
-        eprint!("\r{}", (y as f32 / img_h as f32) * 100.0);
-        for x in 0..img_w {
-            let sample_pixel = | pixel, _ | -> Vec3 {
-                let u = (x as f32 + rng.sample_01()) / ((img_w - 1) as f32);
-                let v = ((img_h - y) as f32 + rng.sample_01()) / ((img_h - 1) as f32);
-                let ray = cam.get_ray(u, v, &mut rng);
-                pixel + trace_ray(&ray, &bvh, &mut rng, 50)
+    let transform = OutputTransform {
+        exposure: opts.exposure,
+        white_balance: opts.white_balance,
+        tone_map: opts.tone_map,
+        dither: opts.dither,
+    };
+
+    let mut image_stats = Vec::new();
+    for (i, (name, cam)) in cameras.iter().enumerate() {
+        let render_start = Instant::now();
+        stats::take();
+        /* What is left of the time budget is shared by the images still to render */
+        let deadline = opts.time_budget.map(|budget| image_deadline(budget, start.elapsed(), cameras.len() - i,
+                                                                   render_start));
+        /* With several images each file gets the name of its camera */
+        let path_for = |path: &str| if cameras.len() > 1 { image_path(path, name) } else { path.to_string() };
+
+        let checkpoint = opts.checkpoint.as_deref().map(path_for);
+        let (film, aov_film) = match render(cam.as_ref(), &scene, &opts, sampler.as_mut(), img_w, img_h,
+                                            Passes { checkpoint: checkpoint.as_deref(), deadline }) {
+            Ok(rendered) => rendered,
+            Err(why) => {
+                eprintln!("\n{}", why);
+                std::process::exit(1);
+            }
+        };
+        let (out_w, out_h) = (film.crop.w(), film.crop.h());
+        let render_time = render_start.elapsed();
+        image_stats.push(ImageStats { name: name.clone(), time: render_time, counts: stats::take() });
+
+        let counts = film.sample_counts();
+        eprintln!("\nDone in {:?}!", render_time);
+        eprintln!("{}", spp_summary(&counts));
+
+        if let Some(path) = &opts.spp_map {
+            let path = path_for(path);
+            if let Err(why) = image::write_pfm_gray(&path, out_w, out_h, &film.sample_counts()) {
+                eprintln!("Could not write {}: {}", path, why);
+            }
+        }
+        if let Some(path) = &opts.weight_map {
+            let path = path_for(path);
+            if let Err(why) = image::write_pfm_gray(&path, out_w, out_h, &film.weights()) {
+                eprintln!("Could not write {}: {}", path, why);
+            }
+        }
+
+        let mut img = film.image();
+        if let Some(kind) = opts.denoiser {
+            let denoise_start = Instant::now();
+            let input = DenoiseInput::new(out_w, out_h, &img, &aov_film, film.mean_variances());
+            img = new_denoiser(kind).denoise(&input);
+            eprintln!("Denoised in {:?}", denoise_start.elapsed());
+        }
+        if let Some(mode) = opts.debug.filter(|mode| mode.is_heatmap()) {
+            let values: Vec<f32> = match mode {
+                DebugMode::Samples => counts.clone(),
+                _ => img.iter().map(|p| p.x).collect(),
             };
-            let sum = (0..samples_per_pixel).fold(Vec3::zero(), sample_pixel);
-            let pixel = (sum * scale).sqrt();
-            img[x + y * img_w] = pixel;
+            let (colors, max) = heatmap(&values);
+            eprintln!("Heatmap from 0 to {}", max);
+            img = colors;
+        }
+
+        /* Float output keeps the linear radiance, only 8 bit images go through the display transform */
+        let written = match &opts.output {
+            Some(path) if path.ends_with(".exr") => {
+                let path = path_for(path);
+                let rgb: Vec<Vec<f32>> = (0..3).map(|c| img.iter().map(|p| p[c]).collect()).collect();
+                let mut layers: Vec<(String, Vec<f32>)> = ["R", "G", "B"].iter().map(|c| c.to_string()).zip(rgb).collect();
+                for kind in &opts.aovs {
+                    let names = kind.channels().iter().map(|c| format!("{}.{}", kind.name(), c));
+                    layers.extend(names.zip(aov_film.planes(*kind)));
+                }
+                let channels: Vec<(String, &[f32])> = layers.iter().map(|(name, values)| (name.clone(), values.as_slice())).collect();
+                image::write_exr(&path, out_w, out_h, &channels).map_err(|why| format!("Could not write {}: {}", path, why))
+            }
+            Some(path) if path.ends_with(".pfm") => {
+                let path = path_for(path);
+                image::write_pfm(&path, out_w, out_h, &img).map_err(|why| format!("Could not write {}: {}", path, why))
+            }
+            Some(path) => {
+                let path = path_for(path);
+                let ldr = transform.apply(&img);
+                File::create(&path).and_then(|file| {
+                    let mut out = BufWriter::new(file);
+                    image::write_ppm(&mut out, out_w, out_h, &ldr)?;
+                    out.flush()
+                }).map_err(|why| format!("Could not write {}: {}", path, why))
+            }
+            None => {
+                let ldr = transform.apply(&img);
+                let mut out = BufWriter::new(io::stdout().lock());
+                image::write_ppm(&mut out, out_w, out_h, &ldr).and_then(|_| out.flush())
+                                                              .map_err(|why| format!("Could not write the image: {}", why))
+            }
+        };
+        if let Err(why) = written {
+            eprintln!("{}", why);
+            std::process::exit(1);
+        }
+
+        if let Some(path) = opts.output.as_ref().filter(|path| !path.ends_with(".exr")) {
+            for kind in &opts.aovs {
+                let path = aov_path(&path_for(path), kind.name());
+                let planes = aov_film.planes(*kind);
+                let written = match planes.as_slice() {
+                    [r, g, b] => {
+                        let pixels: Vec<Vec3> = (0..r.len()).map(|i| Vec3::new(r[i], g[i], b[i])).collect();
+                        image::write_pfm(&path, out_w, out_h, &pixels)
+                    }
+                    _ => image::write_pfm_gray(&path, out_w, out_h, &planes[0]),
+                };
+                if let Err(why) = written {
+                    eprintln!("Could not write {}: {}", path, why);
+                    std::process::exit(1);
+                }
+            }
         }
     }
-    let render_finish = std::time::Instant::now();
-    let render_time = render_finish - render_start;
 
-    eprintln!("\nDone in {:?}!", render_time);
+    if let Some(format) = opts.stats {
+        let report = stats::report(format, &bvh_stats, &image_stats);
+        match &opts.stats_output {
+            Some(path) => {
+                if let Err(why) = std::fs::write(path, report) {
+                    eprintln!("Could not write {}: {}", path, why);
+                    std::process::exit(1);
+                }
+            }
+            None => eprint!("{}", report),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::test_scene;
+
+    #[test]
+    fn test_schedule() {
+        let opts = Options { spp: 8, max_spp: 32, ..Options::default() };
+        let fixed = Schedule::new(&opts, false);
+        assert_eq!((fixed.samples_per_pixel, fixed.max_spp, fixed.pass_spp), (8, 8, 8));
+        /* A deadline lifts the limit and fills in the image one sample per pixel at a time */
+        let timed = Schedule::new(&opts, true);
+        assert_eq!((timed.samples_per_pixel, timed.max_spp, timed.pass_spp), (8, u32::MAX, 1));
+        let adaptive = Schedule::new(&Options { adaptive: true, pass_spp: Some(4), ..opts }, true);
+        assert_eq!((adaptive.samples_per_pixel, adaptive.max_spp, adaptive.pass_spp), (8, 32, 4));
+    }
 
-    save_image(img_w, img_h, &img);
+    #[test]
+    fn test_image_deadline() {
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        assert_eq!(image_deadline(10.0, 4 * second, 3, now), now + 2 * second);
+        assert_eq!(image_deadline(10.0, 12 * second, 1, now), now);
+        for budget in [f32::MAX, f32::INFINITY] {
+            assert!(image_deadline(budget, second, 2, now) > now + Duration::from_secs(50 * 365 * 24 * 3600));
+        }
+    }
+
+    /* A render out of time takes no samples at all, yet saves what it has */
+    #[test]
+    fn test_expired_deadline() {
+        let mat = Lambertian::new(Vec3::one() * 0.5);
+        let ball = Sphere::new(Vec3::zero(), 1.0, &mat);
+        let scene = test_scene(vec![&ball], Vec::new(), Vec3::one(), vec![&mat]);
+        let frame = Frame::look_at(Vec3::new(0.0, 0.0, 5.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0));
+        let camera = OrthographicCamera::new(frame, 1.0, 3.0, Shutter { open: 0.0, close: 0.0 });
+        let opts = Options { spp: 2, ..Options::default() };
+        let mut sampler = new_sampler(opts.sampler, opts.seed, opts.spp);
+
+        let path = std::env::temp_dir().join("rrt-test-deadline.bin");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let passes = Passes { checkpoint: Some(path), deadline: Some(Instant::now()) };
+        let (film, _) = render(&camera, &scene, &opts, sampler.as_mut(), 4, 4, passes).unwrap();
+        assert!(film.sample_counts().iter().all(|n| *n == 0.0));
+        assert!(std::path::Path::new(path).exists());
+
+        let passes = Passes { checkpoint: None, deadline: None };
+        let (film, _) = render(&camera, &scene, &opts, sampler.as_mut(), 4, 4, passes).unwrap();
+        assert_eq!(spp_summary(&film.sample_counts()), "2.0 samples per pixel, 2 to 2");
+        std::fs::remove_file(path).unwrap();
+    }
 }
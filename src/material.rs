@@ -1,10 +1,53 @@
 use crate::Vec3;
 use crate::Ray;
 use crate::hittable::HitRecord;
-use crate::rng::*;
+use crate::sampler::Sampler;
+use crate::warp::*;
 
+/* Kind of interaction a scattered ray came from, used by the depth limits */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Lobe {
+    Diffuse,
+    Specular,
+    Transmission,
+    /* Scattering inside a participating medium */
+    Volume,
+}
+
+pub struct Scatter {
+    pub attenuation: Vec3,
+    pub ray: Ray,
+    pub lobe: Lobe,
+    /* Solid angle density of the scattered direction, zero if it can't be evaluated */
+    pub pdf: f32,
+}
+
+/*
+ * In eval() and pdf() both directions point away from the surface, wo
+ * towards the viewer and wi towards the light.
+ */
 pub trait Material {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, rng: &mut RNG) -> Option<(Vec3, Ray)>;
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter>;
+
+    /* BSDF times the cosine term, zero for singular lobes */
+    fn eval(&self, _wo: Vec3, _wi: Vec3, _rec: &HitRecord) -> Vec3 {
+        Vec3::zero()
+    }
+
+    /* Density with which scatter() would pick wi */
+    fn pdf(&self, _wo: Vec3, _wi: Vec3, _rec: &HitRecord) -> f32 {
+        0.0
+    }
+
+    /* Radiance emitted from the surface towards the incoming ray */
+    fn emitted(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::zero()
+    }
+
+    /* Reflectance for the albedo output, white for clear and emissive surfaces */
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::one()
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -19,11 +62,29 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, _ray_in: &Ray, rec: &HitRecord, rng: &mut RNG) -> Option<(Vec3, Ray)> {
-        let scatter_dir = rec.n + random_unit_vector(rng);
-        let scattered = Ray::new(rec.p, scatter_dir);
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        /* Offsetting the normal by a uniform direction gives a cosine distribution */
+        let mut scatter_dir = rec.n + sample_unit_sphere(sampler.get_2d());
+        if scatter_dir.len2() < 1e-8 {
+            scatter_dir = rec.n;
+        }
+        let scattered = Ray::new(rec.p, scatter_dir, ray_in.time);
         let attenuation = self.albedo;
-        Some((attenuation, scattered))
+        let pdf = self.pdf(Vec3::zero(), scatter_dir, rec);
+        Some(Scatter { attenuation, ray: scattered, lobe: Lobe::Diffuse, pdf })
+    }
+
+    fn eval(&self, _wo: Vec3, wi: Vec3, rec: &HitRecord) -> Vec3 {
+        let cos = rec.n.dot(wi.normalized()).max(0.0);
+        self.albedo * (cos / std::f32::consts::PI)
+    }
+
+    fn pdf(&self, _wo: Vec3, wi: Vec3, rec: &HitRecord) -> f32 {
+        rec.n.dot(wi.normalized()).max(0.0) / std::f32::consts::PI
+    }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
     }
 }
 
@@ -40,17 +101,21 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, rng: &mut RNG) -> Option<(Vec3, Ray)> {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
         let reflected = Vec3::reflect(ray_in.dir.normalized(), rec.n);
-        let fuzz_dir = random_in_unit_sphere(rng) * self.fuzz;
-        let scattered = Ray::new(rec.p, reflected + fuzz_dir);
+        let fuzz_dir = sample_unit_ball(sampler.get_2d(), sampler.get_1d()) * self.fuzz;
+        let scattered = Ray::new(rec.p, reflected + fuzz_dir, ray_in.time);
         let attenuation = self.albedo;
         if scattered.dir.dot(rec.n) > 0.0 {
-            Some((attenuation, scattered))
+            Some(Scatter { attenuation, ray: scattered, lobe: Lobe::Specular, pdf: 0.0 })
         } else {
             None
         }
     }
+
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        self.albedo
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -71,7 +136,7 @@ impl Dielectric {
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, rng: &mut RNG) -> Option<(Vec3, Ray)> {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
         let attenuation = Vec3::one();
         let refraction_ratio = if rec.front_face {
             1.0 / self.ir
@@ -85,13 +150,44 @@ impl Material for Dielectric {
         let no_refract = refraction_ratio * sin_theta > 1.0;
         let reflectance = Self::reflectance(cos_theta, refraction_ratio);
 
-        let dir = if no_refract || reflectance > random_f32(rng) {
-            Vec3::reflect(unit_dir, rec.n)
+        let (dir, lobe) = if no_refract || reflectance > sampler.get_1d() {
+            (Vec3::reflect(unit_dir, rec.n), Lobe::Specular)
         } else {
-            Vec3::refract(unit_dir, rec.n, refraction_ratio)
+            (Vec3::refract(unit_dir, rec.n, refraction_ratio), Lobe::Transmission)
         };
 
-        let scattered = Ray::new(rec.p, dir);
-        Some((attenuation, scattered))
+        let scattered = Ray::new(rec.p, dir, ray_in.time);
+        Some(Scatter { attenuation, ray: scattered, lobe, pdf: 0.0 })
+    }
+}
+
+/*
+ * Emitter with uniform radiance. Surfaces using it must also be registered
+ * as area lights, the integrator only adds their emission on hits that
+ * next event estimation can't account for.
+ */
+#[derive(Copy, Clone)]
+pub struct DiffuseLight {
+    radiance: Vec3,
+    two_sided: bool,
+}
+
+impl DiffuseLight {
+    pub fn new(radiance: Vec3, two_sided: bool) -> DiffuseLight {
+        DiffuseLight { radiance, two_sided }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray_in: &Ray, _rec: &HitRecord, _sampler: &mut dyn Sampler) -> Option<Scatter> {
+        None
+    }
+
+    fn emitted(&self, rec: &HitRecord) -> Vec3 {
+        if rec.front_face || self.two_sided {
+            self.radiance
+        } else {
+            Vec3::zero()
+        }
     }
 }
@@ -0,0 +1,282 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::bvh::BVH;
+use crate::hittable::{Hittable, HitRecord, Shapes};
+use crate::material::{Material, Scatter, Lobe};
+use crate::mesh::Mesh;
+use crate::options::{parse_f32, parse_vec3};
+use crate::rng::RNG;
+use crate::sampler::Sampler;
+use crate::sphere::Sphere;
+
+/*
+ * Henyey-Greenstein phase function, g > 0 scatters forward and g < 0
+ * backward. It is used as the material of scattering events inside a
+ * medium, where eval() and pdf() are the same and there is no cosine term.
+ */
+#[derive(Copy, Clone)]
+pub struct HenyeyGreenstein {
+    g: f32,
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f32) -> HenyeyGreenstein {
+        HenyeyGreenstein { g: g.clamp(-0.99, 0.99) }
+    }
+
+    /* cos_theta is measured against the direction the light was travelling */
+    fn phase(&self, cos_theta: f32) -> f32 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+        (1.0 - g * g) / (4.0 * PI * denom * denom.max(1e-8).sqrt())
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(&self, ray_in: &Ray, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<Scatter> {
+        let u = sampler.get_2d();
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u.0
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.0);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.1;
+
+        let forward = ray_in.dir.normalized();
+        let (t, b) = forward.basis();
+        let dir = t * (sin_theta * phi.cos()) + b * (sin_theta * phi.sin()) + forward * cos_theta;
+
+        Some(Scatter {
+            attenuation: Vec3::one(),
+            ray: Ray::new(rec.p, dir, ray_in.time),
+            lobe: Lobe::Volume,
+            pdf: self.phase(cos_theta),
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, rec: &HitRecord) -> Vec3 {
+        Vec3::one() * self.pdf(wo, wi, rec)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, _rec: &HitRecord) -> f32 {
+        self.phase((-wo).normalized().dot(wi.normalized()))
+    }
+}
+
+/* A collision found along a ray in a medium */
+pub struct MediumSample {
+    pub t: f32,
+    /* Path weight if the collision turns out to be the nearest one */
+    pub weight: Vec3,
+}
+
+/*
+ * Participating medium. Each medium samples collisions independently of
+ * the others, the integrator keeps the nearest one and weighs the rest as
+ * having been passed through up to it. Ray parameters are in units of the
+ * ray direction, which doesn't need to be normalized.
+ */
+pub trait Medium {
+    /* Free flight sampling along the ray up to t_max, u is the first random number */
+    fn sample(&self, ray: &Ray, t_max: f32, u: f32, rng: &mut RNG) -> Option<MediumSample>;
+
+    /* Weight of passing through up to t, given that sample() found nothing before it */
+    fn pass_weight(&self, ray: &Ray, t: f32, rng: &mut RNG) -> Vec3;
+
+    /* Fraction of light surviving between the ray origin and t_max */
+    fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut RNG) -> Vec3;
+
+    fn phase(&self) -> &HenyeyGreenstein;
+}
+
+/*
+ * Homogeneous medium filling the inside of a closed boundary. Only the
+ * first entry and exit of a ray are considered, so the boundary should be
+ * convex or close to it. The boundary isn't visible by itself, it needs a
+ * surface of its own in the scene to e.g. be glass.
+ */
+pub struct ConstantMedium<'a> {
+    boundary: Box<dyn Hittable + 'a>,
+    sigma_a: Vec3,
+    sigma_s: Vec3,
+    phase: HenyeyGreenstein,
+}
+
+impl<'a> ConstantMedium<'a> {
+    /* The coefficients are per unit of length in scene units */
+    pub fn new(boundary: Box<dyn Hittable + 'a>, sigma_a: Vec3, sigma_s: Vec3,
+               g: f32) -> ConstantMedium<'a> {
+        ConstantMedium { boundary, sigma_a, sigma_s, phase: HenyeyGreenstein::new(g) }
+    }
+
+    fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    /* Parametric interval of the ray inside the medium, clipped to [0, t_max] */
+    fn span(&self, ray: &Ray, t_max: f32, rng: &mut RNG) -> Option<(f32, f32)> {
+        let enter = self.boundary.hit(ray, f32::NEG_INFINITY, f32::INFINITY, rng)?;
+        let exit = self.boundary.hit(ray, enter.t + 1e-4, f32::INFINITY, rng)?;
+        let t0 = enter.t.max(0.0);
+        let t1 = exit.t.min(t_max);
+        if t0 < t1 { Some((t0, t1)) } else { None }
+    }
+}
+
+/*
+ * The color channel whose extinction drives the sampling is picked at
+ * random and the weights use the density averaged over all channels,
+ * which keeps chromatic media unbiased (pbrt, Pharr et al.).
+ */
+impl Medium for ConstantMedium<'_> {
+    fn sample(&self, ray: &Ray, t_max: f32, u: f32, rng: &mut RNG) -> Option<MediumSample> {
+        let (t0, t1) = self.span(ray, t_max, rng)?;
+        let channel = ((u * 3.0) as usize).min(2);
+        let u = u * 3.0 - channel as f32;
+
+        let sigma_t = self.sigma_t();
+        if sigma_t[channel] <= 0.0 {
+            return None;
+        }
+        let dist = -(1.0 - u).ln() / sigma_t[channel];
+        let t = t0 + dist / ray.dir.len();
+        if t >= t1 {
+            return None;
+        }
+
+        let tr = (-sigma_t * dist).exp();
+        let pdf = (sigma_t * tr).average();
+        Some(MediumSample { t, weight: tr * self.sigma_s / pdf })
+    }
+
+    fn pass_weight(&self, ray: &Ray, t: f32, rng: &mut RNG) -> Vec3 {
+        let tr = self.transmittance(ray, t, rng);
+        tr / tr.average()
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut RNG) -> Vec3 {
+        match self.span(ray, t_max, rng) {
+            Some((t0, t1)) => (-self.sigma_t() * ((t1 - t0) * ray.dir.len())).exp(),
+            None => Vec3::one(),
+        }
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+/*
+ * Medium description as given on the command line, vectors separated by
+ * commas and coefficients given as r,g,b:
+ *   sphere:x,y,z:radius:sigma_a:sigma_s:g[:ior]
+ *   mesh:file.obj:x,y,z:sigma_a:sigma_s:g[:ior]
+ * With an ior the boundary is also added as a dielectric surface, which
+ * gives milky or subsurface looking objects instead of fog.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct MediumDesc {
+    pub shape: MediumShape,
+    pub sigma_a: Vec3,
+    pub sigma_s: Vec3,
+    pub g: f32,
+    pub ior: Option<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MediumShape {
+    Sphere { center: Vec3, radius: f32 },
+    Mesh { path: String, offset: Vec3 },
+}
+
+impl FromStr for MediumDesc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let (shape, rest) = match fields.as_slice() {
+            ["sphere", center, radius, rest @ ..] => (MediumShape::Sphere {
+                center: parse_vec3(center)?,
+                radius: parse_f32(radius)?,
+            }, rest),
+            ["mesh", path, offset, rest @ ..] => (MediumShape::Mesh {
+                path: path.to_string(),
+                offset: parse_vec3(offset)?,
+            }, rest),
+            _ => return Err(format!("Invalid medium: {}", s)),
+        };
+
+        match rest {
+            [sigma_a, sigma_s, g, ior @ ..] if ior.len() <= 1 => Ok(MediumDesc {
+                shape,
+                sigma_a: parse_vec3(sigma_a)?,
+                sigma_s: parse_vec3(sigma_s)?,
+                g: parse_f32(g)?,
+                ior: match ior.first() {
+                    Some(ior) => Some(parse_f32(ior)?),
+                    None => None,
+                },
+            }),
+            _ => Err(format!("Invalid medium: {}", s)),
+        }
+    }
+}
+
+impl MediumDesc {
+    /* Geometry of the boundary, mat is the surface used if it is visible */
+    pub fn shapes<'a>(&self, mat: &'a dyn Material) -> Result<Shapes<'a>, String> {
+        match &self.shape {
+            MediumShape::Sphere { center, radius } =>
+                Ok(Shapes { spheres: vec![Sphere::new(*center, *radius, mat)], tris: Vec::new() }),
+            MediumShape::Mesh { path, offset } => {
+                let mesh = Mesh::load_obj(path).map_err(|why| format!("Could not load {}: {}", path, why))?;
+                let mut tris = mesh.get_mesh(mat);
+                for tri in &mut tris {
+                    for v in &mut tri.verts {
+                        *v += *offset;
+                    }
+                }
+                Ok(Shapes { spheres: Vec::new(), tris })
+            }
+        }
+    }
+
+    pub fn build<'a>(&self, shapes: &'a Shapes<'a>) -> Box<dyn Medium + 'a> {
+        let boundary = BVH::new(shapes.hittables().collect());
+        Box::new(ConstantMedium::new(Box::new(boundary), self.sigma_a, self.sigma_s, self.g))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_media() {
+        let fog: MediumDesc = "sphere:0,1,0:2:0,0,0:0.5,0.5,0.5:0.3".parse().unwrap();
+        assert_eq!(fog.shape, MediumShape::Sphere { center: Vec3::new(0.0, 1.0, 0.0), radius: 2.0 });
+        assert_eq!(fog.ior, None);
+        let milk: MediumDesc = "mesh:blob.obj:0,0,0:0.01,0.02,0.05:4,4,4:0.8:1.35".parse().unwrap();
+        assert_eq!(milk.ior, Some(1.35));
+        assert!("sphere:0,1,0:2:0,0,0:0.5,0.5,0.5".parse::<MediumDesc>().is_err());
+    }
+
+    /* The phase function integrates to one over the sphere */
+    #[test]
+    fn test_henyey_greenstein() {
+        let hg = HenyeyGreenstein::new(0.6);
+        let n = 256;
+        let mut integral = 0.0;
+        for i in 0..n {
+            let cos_theta = -1.0 + 2.0 * (i as f32 + 0.5) / n as f32;
+            integral += hg.phase(cos_theta) * 2.0 * PI * 2.0 / n as f32;
+        }
+        assert!((integral - 1.0).abs() < 1e-2);
+        assert!(hg.phase(1.0) > hg.phase(-1.0));
+    }
+}
@@ -1,8 +1,9 @@
 use std::str::FromStr;
+use std::fmt;
 use core::num::ParseFloatError;
 use core::num::ParseIntError;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::BufReader;
 use std::io::prelude::*;
 
 use crate::Vec3;
@@ -35,9 +36,9 @@ pub struct Mesh {
 
 impl Face {
     fn from_tokens(tokens: &Vec<&str>,
-                   has_normals: bool,
-                   has_tex_coords: bool) -> Result<Face, ParseIntError> {
-        let sub_tokens = tokens.into_iter().map(|tok| tok.split("/").collect());
+                   _has_normals: bool,
+                   _has_tex_coords: bool) -> Result<Face, ParseIntError> {
+        let sub_tokens = tokens.iter().map(|tok| tok.split("/").collect());
         let sub_tokens: Vec<Vec<&str>> = sub_tokens.collect();
         /* TODO Handle has_normals and has_tex_coords */
         Ok(Face {
@@ -47,17 +48,28 @@ impl Face {
         })
     }
 
-    fn as_tri(&self, verts: &Vec<Vec3>) -> [Vec3; 3] {
+    fn as_tri(&self, verts: &[Vec3]) -> [Vec3; 3] {
         [verts[self.i], verts[self.j], verts[self.k]]
     }
 }
 
 #[derive(Debug)]
 pub enum MeshParseError {
-    string(String),
-    io(std::io::Error),
-    parse_int(ParseIntError),
-    parse_float(ParseFloatError),
+    String(String),
+    Io(std::io::Error),
+    ParseInt(ParseIntError),
+    ParseFloat(ParseFloatError),
+}
+
+impl fmt::Display for MeshParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MeshParseError::String(why) => write!(f, "{}", why),
+            MeshParseError::Io(why) => write!(f, "{}", why),
+            MeshParseError::ParseInt(why) => write!(f, "{}", why),
+            MeshParseError::ParseFloat(why) => write!(f, "{}", why),
+        }
+    }
 }
 
 impl Mesh {
@@ -66,7 +78,7 @@ impl Mesh {
         let mut faces = Vec::new();
         let file = match File::open(path) {
             Ok(file) => file,
-            Err(why) => return Err(MeshParseError::io(why)),
+            Err(why) => return Err(MeshParseError::Io(why)),
         };
         let file = BufReader::new(file);
         let lines = file.lines();
@@ -77,19 +89,19 @@ impl Mesh {
         for line in lines {
             let line = match line {
                 Ok(line) => line,
-                Err(why) => return Err(MeshParseError::io(why)),
+                Err(why) => return Err(MeshParseError::Io(why)),
             };
             let line = line.trim();
-            if line.len() == 0 {
+            if line.is_empty() {
                 continue;
             }
             let tokens = line.split_whitespace().collect::<Vec<&str>>();
             match tokens[0] {
                 "#" => continue,
                 "v" => verts.push(
-                    match Vec3::from_str(&line) {
+                    match Vec3::from_str(line) {
                         Ok(vert) => vert,
-                        Err(why) => return Err(MeshParseError::parse_float(why))
+                        Err(why) => return Err(MeshParseError::ParseFloat(why))
                     }),
                 "vn" => has_normals = true,
                 "vt" => has_tex_coords = true,
@@ -98,13 +110,13 @@ impl Mesh {
                 "f" => faces.push(
                     match Face::from_tokens(&tokens, has_normals, has_tex_coords) {
                         Ok(face) => face,
-                        Err(why) => return Err(MeshParseError::parse_int(why))
+                        Err(why) => return Err(MeshParseError::ParseInt(why))
                     }),
-                _ => return Err(MeshParseError::string(format!("Could not parse: {}", line))),
+                _ => return Err(MeshParseError::String(format!("Could not parse: {}", line))),
             };
         }
 
-        Ok(Mesh { verts: verts, faces: faces })
+        Ok(Mesh { verts, faces })
     }
 
     pub fn get_mesh<'a>(&self, mat: &'a dyn Material) -> Vec<Tri<'a>> {
@@ -0,0 +1,419 @@
+use std::str::FromStr;
+
+use crate::sampler::SamplerKind;
+use crate::integrator::{DepthPolicy, IntegratorKind};
+use crate::background::BackgroundKind;
+use crate::light::LightDesc;
+use crate::lightsampler::LightSamplerKind;
+use crate::medium::MediumDesc;
+use crate::volume::VolumeDesc;
+use crate::camera::{CameraKind, FisheyeMapping, ViewDesc, Stereo};
+use crate::aperture::ApertureShape;
+use crate::filter::FilterKind;
+use crate::film::CropWindow;
+use crate::tonemap::ToneMap;
+use crate::sphere::MovingSphereDesc;
+use crate::instance::InstanceDesc;
+use crate::aov::AovKind;
+use crate::denoise::DenoiserKind;
+use crate::stats::StatsFormat;
+use crate::debug::DebugMode;
+use crate::Vec3;
+
+pub struct Options {
+    /*
+     * Where the image goes, stdout if not given. A .pfm or .exr file keeps
+     * the linear radiance, an .exr file also gets the AOVs as layers.
+     */
+    pub output: Option<String>,
+    /* Auxiliary images, written next to the output unless that is an .exr file */
+    pub aovs: Vec<AovKind>,
+    /* Renders a heatmap or a geometric quantity instead of the radiance */
+    pub debug: Option<DebugMode>,
+    /* Filters the image guided by the albedo, normal and depth of the first hits */
+    pub denoiser: Option<DenoiserKind>,
+    /* Display transform of 8 bit output, the exposure in stops and the white balance in kelvin */
+    pub exposure: f32,
+    pub white_balance: Option<f32>,
+    pub tone_map: ToneMap,
+    pub dither: bool,
+    pub width: usize,
+    /* Derived from a 16:9 aspect ratio if not given */
+    pub height: Option<usize>,
+    pub camera: CameraKind,
+    /* Degrees, vertical for perspective cameras and across the image circle for fisheyes */
+    pub fov: Option<f32>,
+    pub fisheye_mapping: FisheyeMapping,
+    /* Vertical extent in scene units, by default what the perspective view frames at the target */
+    pub ortho_height: Option<f32>,
+    /* Cameras besides the scene's own one named main, and which to render, all by default */
+    pub views: Vec<ViewDesc>,
+    pub render_views: Option<Vec<String>>,
+    /* Renders a left and a right image of every view */
+    pub stereo: Option<Stereo>,
+    /* Lens diameter of the perspective camera in scene units, zero for a pinhole */
+    pub aperture: f32,
+    pub bokeh: ApertureShape,
+    /* Prescription of the realistic camera and the film diagonal in millimeters */
+    pub lens: Option<String>,
+    pub film_diagonal: f32,
+    /* Times objects are seen at, moving spheres travel between 0 and 1 */
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    pub filter: FilterKind,
+    /* In pixels, each filter has its own default */
+    pub filter_radius: Option<f32>,
+    /* Only this part of the image is rendered and written */
+    pub crop: CropWindow,
+    pub seed: u64,
+    /* Samples per pixel, or with photon mapping the number of iterations */
+    pub spp: u32,
+    pub sampler: SamplerKind,
+    pub adaptive: bool,
+    pub max_spp: u32,
+    pub target_error: f32,
+    /*
+     * Seconds for the whole run. Passes are added while the next one is
+     * expected to fit, without a sample limit unless sampling adaptively.
+     */
+    pub time_budget: Option<f32>,
+    /* Samples added to every pixel per pass over the image, spp by default and one with a time budget */
+    pub pass_spp: Option<u32>,
+    /* File the render state is saved to between passes, at most every interval seconds and at the end */
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: f32,
+    /* Continues the render saved in the checkpoint, up to the current sample counts */
+    pub resume: bool,
+    /* Counters of the render, printed to stderr unless written to stats_output */
+    pub stats: Option<StatsFormat>,
+    pub stats_output: Option<String>,
+    pub spp_map: Option<String>,
+    /* Sum of the filter weights of every pixel */
+    pub weight_map: Option<String>,
+    pub integrator: IntegratorKind,
+    /* Distance within which surfaces occlude and occlusion rays per camera ray of ambient occlusion */
+    pub ao_radius: f32,
+    pub ao_samples: u32,
+    /* Photons traced per iteration of photon mapping, by default as many as the image has pixels */
+    pub photons: Option<usize>,
+    /* Initial radius within which photons are gathered, shrinking with every iteration */
+    pub photon_radius: f32,
+    pub depth: DepthPolicy,
+    pub background: BackgroundKind,
+    pub background_color: Vec3,
+    pub envmap: Option<String>,
+    /* Degrees around the y axis */
+    pub env_rotation: f32,
+    pub env_intensity: f32,
+    /* Degrees, azimuth is measured from -z towards +x */
+    pub sun_elevation: f32,
+    pub sun_azimuth: f32,
+    pub turbidity: f32,
+    pub sky_intensity: f32,
+    pub lights: Vec<LightDesc>,
+    pub light_sampler: LightSamplerKind,
+    pub media: Vec<MediumDesc>,
+    pub volumes: Vec<VolumeDesc>,
+    pub moving_spheres: Vec<MovingSphereDesc>,
+    pub instances: Vec<InstanceDesc>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            output: None,
+            aovs: Vec::new(),
+            denoiser: None,
+            debug: None,
+            exposure: 0.0,
+            white_balance: None,
+            tone_map: ToneMap::Clamp,
+            dither: false,
+            width: 400,
+            height: None,
+            camera: CameraKind::Perspective,
+            fov: None,
+            fisheye_mapping: FisheyeMapping::Equidistant,
+            ortho_height: None,
+            views: Vec::new(),
+            render_views: None,
+            stereo: None,
+            aperture: 0.2,
+            bokeh: ApertureShape::Circle,
+            lens: None,
+            film_diagonal: 35.0,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            filter: FilterKind::Box,
+            filter_radius: None,
+            crop: CropWindow::default(),
+            seed: 0,
+            spp: 1,
+            sampler: SamplerKind::Independent,
+            adaptive: false,
+            max_spp: 256,
+            target_error: 0.02,
+            time_budget: None,
+            pass_spp: None,
+            checkpoint: None,
+            checkpoint_interval: 60.0,
+            resume: false,
+            stats: None,
+            stats_output: None,
+            spp_map: None,
+            weight_map: None,
+            integrator: IntegratorKind::Path,
+            ao_radius: 1.0,
+            ao_samples: 16,
+            photons: None,
+            photon_radius: 0.1,
+            depth: DepthPolicy::default(),
+            background: BackgroundKind::Gradient,
+            background_color: Vec3::one(),
+            envmap: None,
+            env_rotation: 0.0,
+            env_intensity: 1.0,
+            sun_elevation: 45.0,
+            sun_azimuth: 0.0,
+            turbidity: 3.0,
+            sky_intensity: 1.0,
+            lights: Vec::new(),
+            light_sampler: LightSamplerKind::BVH,
+            media: Vec::new(),
+            volumes: Vec::new(),
+            moving_spheres: Vec::new(),
+            instances: Vec::new(),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = match value {
+        Some(value) => value,
+        None => return Err(format!("Missing value for {}", flag)),
+    };
+    match value.parse::<T>() {
+        Ok(v) => Ok(v),
+        Err(_) => Err(format!("Invalid value for {}: {}", flag, value)),
+    }
+}
+
+/* Vectors are given as comma separated components, e.g. 1,0.5,0.25 */
+fn parse_vec3_value(flag: &str, value: Option<String>) -> Result<Vec3, String> {
+    let value: String = parse_value(flag, value)?;
+    parse_vec3(&value).map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+/* Fields within the value of an option, like the position of a --light */
+pub fn parse_vec3(s: &str) -> Result<Vec3, String> {
+    match Vec3::from_csv(s) {
+        Some(v) => Ok(v),
+        None => Err(format!("Invalid vector: {}", s)),
+    }
+}
+
+pub fn parse_f32(s: &str) -> Result<f32, String> {
+    s.parse::<f32>().map_err(|_| format!("Invalid number: {}", s))
+}
+
+impl Options {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut opts = Options::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" => opts.output = Some(parse_value(&arg, args.next())?),
+                "--aov" => {
+                    let names: String = parse_value(&arg, args.next())?;
+                    for name in names.split(',') {
+                        let kind = name.parse()?;
+                        if !opts.aovs.contains(&kind) {
+                            opts.aovs.push(kind);
+                        }
+                    }
+                }
+                "--debug" => opts.debug = Some(parse_value(&arg, args.next())?),
+                "--denoise" => opts.denoiser = Some(parse_value(&arg, args.next())?),
+                "--exposure" => opts.exposure = parse_value(&arg, args.next())?,
+                "--white-balance" => opts.white_balance = Some(parse_value(&arg, args.next())?),
+                "--tonemap" => opts.tone_map = parse_value(&arg, args.next())?,
+                "--dither" => opts.dither = true,
+                "--width" => opts.width = parse_value(&arg, args.next())?,
+                "--height" => opts.height = Some(parse_value(&arg, args.next())?),
+                "--camera" => opts.camera = parse_value(&arg, args.next())?,
+                "--fov" => opts.fov = Some(parse_value(&arg, args.next())?),
+                "--fisheye-mapping" => opts.fisheye_mapping = parse_value(&arg, args.next())?,
+                "--ortho-height" => opts.ortho_height = Some(parse_value(&arg, args.next())?),
+                "--view" => opts.views.push(parse_value(&arg, args.next())?),
+                "--views" => {
+                    let names: String = parse_value(&arg, args.next())?;
+                    opts.render_views = Some(names.split(',').map(|name| name.to_string()).collect());
+                }
+                "--stereo" => opts.stereo = Some(parse_value(&arg, args.next())?),
+                "--aperture" => opts.aperture = parse_value(&arg, args.next())?,
+                "--bokeh" => opts.bokeh = parse_value(&arg, args.next())?,
+                "--lens" => {
+                    opts.lens = Some(parse_value(&arg, args.next())?);
+                    opts.camera = CameraKind::Realistic;
+                }
+                "--film-diagonal" => opts.film_diagonal = parse_value(&arg, args.next())?,
+                "--shutter-open" => opts.shutter_open = parse_value(&arg, args.next())?,
+                "--shutter-close" => opts.shutter_close = parse_value(&arg, args.next())?,
+                "--filter" => opts.filter = parse_value(&arg, args.next())?,
+                "--filter-radius" => opts.filter_radius = Some(parse_value(&arg, args.next())?),
+                "--crop" => opts.crop = parse_value(&arg, args.next())?,
+                "--seed" => opts.seed = parse_value(&arg, args.next())?,
+                "--spp" => opts.spp = parse_value(&arg, args.next())?,
+                "--sampler" => opts.sampler = parse_value(&arg, args.next())?,
+                "--adaptive" => opts.adaptive = true,
+                "--max-spp" => opts.max_spp = parse_value(&arg, args.next())?,
+                "--target-error" => opts.target_error = parse_value(&arg, args.next())?,
+                "--time-budget" => opts.time_budget = Some(parse_value(&arg, args.next())?),
+                "--pass-spp" => opts.pass_spp = Some(parse_value(&arg, args.next())?),
+                "--checkpoint" => opts.checkpoint = Some(parse_value(&arg, args.next())?),
+                "--checkpoint-interval" => opts.checkpoint_interval = parse_value(&arg, args.next())?,
+                "--resume" => opts.resume = true,
+                "--stats" => opts.stats = Some(parse_value(&arg, args.next())?),
+                "--stats-output" => opts.stats_output = Some(parse_value(&arg, args.next())?),
+                "--spp-map" => opts.spp_map = Some(parse_value(&arg, args.next())?),
+                "--weight-map" => opts.weight_map = Some(parse_value(&arg, args.next())?),
+                "--integrator" => opts.integrator = parse_value(&arg, args.next())?,
+                "--ao-radius" => opts.ao_radius = parse_value(&arg, args.next())?,
+                "--ao-samples" => opts.ao_samples = parse_value(&arg, args.next())?,
+                "--photons" => opts.photons = Some(parse_value(&arg, args.next())?),
+                "--photon-radius" => opts.photon_radius = parse_value(&arg, args.next())?,
+                "--max-depth" => opts.depth.max_depth = parse_value(&arg, args.next())?,
+                "--max-diffuse" => opts.depth.max_diffuse = parse_value(&arg, args.next())?,
+                "--max-specular" => opts.depth.max_specular = parse_value(&arg, args.next())?,
+                "--max-transmission" => opts.depth.max_transmission = parse_value(&arg, args.next())?,
+                "--max-volume" => opts.depth.max_volume = parse_value(&arg, args.next())?,
+                "--rr-depth" => opts.depth.rr_depth = parse_value(&arg, args.next())?,
+                "--background" => opts.background = parse_value(&arg, args.next())?,
+                "--background-color" => opts.background_color = parse_vec3_value(&arg, args.next())?,
+                "--envmap" => {
+                    opts.envmap = Some(parse_value(&arg, args.next())?);
+                    opts.background = BackgroundKind::EnvMap;
+                }
+                "--env-rotation" => opts.env_rotation = parse_value(&arg, args.next())?,
+                "--env-intensity" => opts.env_intensity = parse_value(&arg, args.next())?,
+                "--sun-elevation" => opts.sun_elevation = parse_value(&arg, args.next())?,
+                "--sun-azimuth" => opts.sun_azimuth = parse_value(&arg, args.next())?,
+                "--turbidity" => opts.turbidity = parse_value(&arg, args.next())?,
+                "--sky-intensity" => opts.sky_intensity = parse_value(&arg, args.next())?,
+                "--light" => opts.lights.push(parse_value(&arg, args.next())?),
+                "--light-sampler" => opts.light_sampler = parse_value(&arg, args.next())?,
+                "--medium" => opts.media.push(parse_value(&arg, args.next())?),
+                "--volume" => opts.volumes.push(parse_value(&arg, args.next())?),
+                "--moving-sphere" => opts.moving_spheres.push(parse_value(&arg, args.next())?),
+                "--instance" => opts.instances.push(parse_value(&arg, args.next())?),
+                /* Animates the instance given last */
+                "--keyframe" => {
+                    let key = parse_value(&arg, args.next())?;
+                    match opts.instances.last_mut() {
+                        Some(instance) => instance.keyframes.push(key),
+                        None => return Err("--keyframe needs an --instance before it".to_string()),
+                    }
+                }
+                _ => return Err(format!("Unknown option: {}", arg)),
+            }
+        }
+
+        if opts.width == 0 || opts.height == Some(0) {
+            return Err("The image size must not be zero".to_string());
+        }
+        if !opts.aovs.is_empty() && opts.output.is_none() {
+            return Err("AOVs need an --output path".to_string());
+        }
+        if opts.camera == CameraKind::Realistic && opts.lens.is_none() {
+            return Err("The realistic camera needs a --lens prescription".to_string());
+        }
+        if opts.time_budget.is_some_and(|t| !t.is_finite() || t <= 0.0) {
+            return Err("The time budget must be a positive number of seconds".to_string());
+        }
+        if opts.pass_spp == Some(0) {
+            return Err("Passes need at least one sample per pixel".to_string());
+        }
+        if opts.stats_output.is_some() && opts.stats.is_none() {
+            return Err("--stats-output needs a --stats format".to_string());
+        }
+        if opts.resume && opts.checkpoint.is_none() {
+            return Err("--resume needs the --checkpoint to continue from".to_string());
+        }
+        if opts.ao_radius <= 0.0 || opts.ao_samples == 0 {
+            return Err("Ambient occlusion needs a positive radius and sample count".to_string());
+        }
+        /* Integrators tracing paths from the lights */
+        let from_lights = match opts.integrator {
+            IntegratorKind::Bidirectional => Some("Bidirectional path tracing"),
+            IntegratorKind::PhotonMapping => Some("Photon mapping"),
+            _ => None,
+        };
+        if let Some(name) = from_lights {
+            if opts.lights.iter().any(|light| matches!(light, LightDesc::Directional { .. })) {
+                return Err(format!("{} does not support directional lights", name));
+            }
+            if !opts.media.is_empty() || !opts.volumes.is_empty() {
+                return Err(format!("{} does not support media", name));
+            }
+        }
+        if opts.photon_radius <= 0.0 || opts.photons == Some(0) {
+            return Err("Photon mapping needs a positive radius and photon count".to_string());
+        }
+        /* Debug images of photon mapping are rendered like those of the other integrators */
+        if opts.integrator == IntegratorKind::PhotonMapping && opts.debug.is_none() {
+            let unsupported = [(opts.adaptive, "--adaptive"), (opts.pass_spp.is_some(), "--pass-spp"),
+                               (opts.resume, "--resume"), (opts.checkpoint.is_some(), "--checkpoint"),
+                               (opts.time_budget.is_some(), "--time-budget")];
+            if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+                return Err(format!("Photon mapping renders --spp iterations over the whole image, {} is not supported",
+                                   flag));
+            }
+            if opts.aovs.contains(&AovKind::Direct) || opts.aovs.contains(&AovKind::Indirect) {
+                return Err("Photon mapping does not split direct and indirect light".to_string());
+            }
+        }
+        if opts.debug.is_some() && opts.denoiser.is_some() {
+            return Err("Debug images are not denoised".to_string());
+        }
+        if opts.filter_radius.is_some_and(|r| r <= 0.0) {
+            return Err("The filter radius must be positive".to_string());
+        }
+        if opts.shutter_close < opts.shutter_open {
+            return Err("The shutter must not close before it opens".to_string());
+        }
+
+        if opts.background == BackgroundKind::EnvMap && opts.envmap.is_none() {
+            return Err("The envmap background needs --envmap".to_string());
+        }
+
+        Ok(opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        Options::from_args(args.split_whitespace().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_photon_mapping_flags() {
+        for flag in ["--adaptive", "--pass-spp 2", "--checkpoint a.ckpt", "--resume --checkpoint a.ckpt",
+                     "--time-budget 10"] {
+            let err = parse(&format!("--integrator sppm {}", flag)).err().unwrap();
+            assert!(err.contains(flag.split_whitespace().next().unwrap()), "{}", err);
+            assert!(parse(&format!("--integrator path {}", flag)).is_ok());
+        }
+        assert_eq!(parse("--integrator sppm --spp 3").unwrap().spp, 3);
+    }
+
+    #[test]
+    fn test_time_budget() {
+        for budget in ["NaN", "inf", "-inf", "0", "-1"] {
+            assert!(parse(&format!("--time-budget {}", budget)).is_err(), "{}", budget);
+        }
+        assert_eq!(parse("--time-budget 2.5").unwrap().time_budget, Some(2.5));
+    }
+}
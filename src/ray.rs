@@ -1,13 +1,16 @@
 use crate::Vec3;
 
+#[derive(Copy, Clone, Debug)]
 pub struct Ray {
     pub orig: Vec3,
     pub dir: Vec3,
+    /* Instant within the shutter interval the ray samples */
+    pub time: f32,
 }
 
 impl Ray {
-    pub fn new(orig: Vec3, dir: Vec3) -> Ray {
-        Ray { orig, dir }
+    pub fn new(orig: Vec3, dir: Vec3, time: f32) -> Ray {
+        Ray { orig, dir, time }
     }
 
     pub fn at(&self, t: f32) -> Vec3 {
@@ -1,56 +1,92 @@
-use rand::Rng;
-use rand::rngs::ThreadRng;
-use rand::distributions::Uniform;
-
-use crate::Vec3;
+const PCG_MULT: u64 = 6364136223846793005;
 
+/* PCG32 (XSH RR 64/32), see https://www.pcg-random.org */
+#[derive(Copy, Clone, Debug)]
 pub struct RNG {
-    side_11: Uniform<f32>,
-    side_01: Uniform<f32>,
-    rng: ThreadRng,
+    state: u64,
+    inc: u64,
+}
+
+/* SplitMix64 finalizer, used to turn structured inputs into well mixed seeds */
+pub fn mix64(v: u64) -> u64 {
+    let mut z = v.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
 impl RNG {
-    pub fn new() -> RNG {
-        RNG {
-            side_11: Uniform::new(-1.0, 1.0),
-            side_01: Uniform::new(0.0, 1.0),
-            rng: ThreadRng::default(),
-        }
+    pub fn with_seed(seed: u64) -> RNG {
+        RNG::with_stream(seed, mix64(seed))
+    }
+
+    pub fn with_stream(seed: u64, stream: u64) -> RNG {
+        let mut rng = RNG { state: 0, inc: (stream << 1) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    /*
+     * Every sample of every pixel gets its own stream derived only from the
+     * seed and its coordinates, so the image does not depend on the order in
+     * which pixels and samples are rendered.
+     */
+    pub fn for_sample(seed: u64, pixel: usize, sample: u32) -> RNG {
+        RNG::with_seed(mix64(seed ^ mix64(pixel as u64 ^ mix64(sample as u64))))
     }
 
-    pub fn sample_11(&mut self) -> f32 {
-        self.rng.sample(self.side_11)
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG_MULT).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
     }
 
+    /* Uniform in [0, 1), uses the top 24 bits so that 1.0 is never returned */
     pub fn sample_01(&mut self) -> f32 {
-        self.rng.sample(self.side_01)
+        (self.next_u32() >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
     }
 }
 
-pub fn random_f32(rng: &mut RNG) -> f32 {
-    rng.sample_01()
-}
+#[cfg(test)]
+mod tests {
+    use super::RNG;
 
-pub fn random_in_unit_sphere(rng: &mut RNG) -> Vec3 {
-    loop {
-        let p = Vec3::new(rng.sample_11(), rng.sample_11(), rng.sample_11());
-        if p.len2() < 1.0 {
-            return p;
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut a = RNG::with_seed(42);
+        let mut b = RNG::with_seed(42);
+        for _ in 0..1000 {
+            assert_eq!(a.next_u32(), b.next_u32());
         }
     }
-}
 
-pub fn random_in_unit_disk(rng: &mut RNG) -> Vec3 {
-    loop {
-        let p = Vec3::new(rng.sample_11(), rng.sample_11(), 0.0);
-        if p.len2() < 1.0 {
-            return p;
-        }
+    #[test]
+    fn test_sample_streams() {
+        let mut a = RNG::for_sample(7, 10, 0);
+        let mut b = RNG::for_sample(7, 10, 1);
+        let mut c = RNG::for_sample(7, 11, 0);
+        let mut a2 = RNG::for_sample(7, 10, 0);
+
+        let sa: Vec<u32> = (0..16).map(|_| a.next_u32()).collect();
+        let sb: Vec<u32> = (0..16).map(|_| b.next_u32()).collect();
+        let sc: Vec<u32> = (0..16).map(|_| c.next_u32()).collect();
+        let sa2: Vec<u32> = (0..16).map(|_| a2.next_u32()).collect();
+
+        assert_eq!(sa, sa2);
+        assert_ne!(sa, sb);
+        assert_ne!(sa, sc);
     }
-}
 
-pub fn random_unit_vector(rng: &mut RNG) -> Vec3 {
-    /* XXX */
-    random_in_unit_sphere(rng)
+    #[test]
+    fn test_sample_range() {
+        let mut rng = RNG::with_seed(1);
+        for _ in 0..10000 {
+            let x = rng.sample_01();
+            assert!((0.0..1.0).contains(&x));
+        }
+    }
 }
@@ -0,0 +1,481 @@
+use std::str::FromStr;
+
+use crate::rng::{RNG, mix64};
+
+/*
+ * Sample dimensions are laid out in fixed blocks: the camera owns the first
+ * CAMERA_DIMS dimensions and every bounce gets BOUNCE_DIMS of its own, so the
+ * same dimension always feeds the same decision no matter how many values
+ * the previous bounces consumed.
+ */
+pub const CAMERA_DIMS: u32 = 8;
+pub const BOUNCE_DIMS: u32 = 10;
+
+/* Camera dimensions: two for the position on the film, two for the lens, then the time */
+pub const CAMERA_TIME_DIM: u32 = 4;
+
+/*
+ * Offsets within the dimensions of a bounce: Russian roulette, the
+ * distance in participating media, then up to three for the BSDF, two for
+ * the background and three for picking and sampling a light.
+ */
+pub const BOUNCE_RR_DIM: u32 = 0;
+pub const BOUNCE_BSDF_DIM: u32 = 2;
+pub const BOUNCE_LIGHT_DIM: u32 = 5;
+
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, pixel: usize, index: u32);
+    fn set_dimension(&mut self, dim: u32);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> (f32, f32);
+
+    /* Generator for everything that does not need well distributed samples */
+    fn rng(&mut self) -> &mut RNG;
+
+    fn start_bounce(&mut self, bounce: u32) {
+        self.set_bounce_dimension(bounce, BOUNCE_RR_DIM);
+    }
+
+    fn set_bounce_dimension(&mut self, bounce: u32, offset: u32) {
+        self.set_dimension(CAMERA_DIMS + bounce * BOUNCE_DIMS + offset);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl FromStr for SamplerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!("Unknown sampler: {}", s)),
+        }
+    }
+}
+
+pub fn new_sampler(kind: SamplerKind, seed: u64, spp: u32) -> Box<dyn Sampler> {
+    match kind {
+        SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+        SamplerKind::Stratified => Box::new(StratifiedSampler::new(seed, spp)),
+        SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+        SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+fn to_f32(bits: u32) -> f32 {
+    (bits >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+/* State shared by all the samplers */
+struct SampleState {
+    seed: u64,
+    pixel: usize,
+    index: u32,
+    dim: u32,
+    rng: RNG,
+}
+
+impl SampleState {
+    fn new(seed: u64) -> SampleState {
+        SampleState { seed, pixel: 0, index: 0, dim: 0, rng: RNG::for_sample(seed, 0, 0) }
+    }
+
+    fn start(&mut self, pixel: usize, index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dim = 0;
+        self.rng = RNG::for_sample(self.seed, pixel, index);
+    }
+
+    /* Per pixel and dimension hash, independent of the sample index */
+    fn hash(&self, dim: u32) -> u32 {
+        let h = mix64(self.seed ^ mix64(self.pixel as u64 ^ mix64(0x5a3c_0000_0000 | dim as u64)));
+        (h >> 32) as u32
+    }
+
+    fn next_dims(&mut self, count: u32) -> u32 {
+        let dim = self.dim;
+        self.dim += count;
+        dim
+    }
+}
+
+pub struct IndependentSampler {
+    state: SampleState,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler { state: SampleState::new(seed) }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: usize, index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.state.rng.sample_01()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.state.rng.sample_01(), self.state.rng.sample_01())
+    }
+
+    fn rng(&mut self) -> &mut RNG {
+        &mut self.state.rng
+    }
+}
+
+/* Kensler, "Correlated Multi-Jittered Sampling", 2013 */
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    i.wrapping_add(p) % l
+}
+
+fn randfloat(mut i: u32, p: u32) -> f32 {
+    i ^= p;
+    i ^= i >> 17;
+    i ^= i >> 10;
+    i = i.wrapping_mul(0xb36534e5);
+    i ^= i >> 12;
+    i ^= i >> 21;
+    i = i.wrapping_mul(0x93fc4795);
+    i ^= 0xdf6e307f;
+    i ^= i >> 17;
+    i = i.wrapping_mul(1 | p >> 18);
+    to_f32(i)
+}
+
+/*
+ * Jittered strata over `spp` samples, with the strata of each dimension
+ * shuffled independently. Samples past `spp` start a new, differently
+ * shuffled, pattern.
+ */
+pub struct StratifiedSampler {
+    state: SampleState,
+    spp: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, spp: u32) -> StratifiedSampler {
+        StratifiedSampler { state: SampleState::new(seed), spp: spp.max(1) }
+    }
+
+    fn pattern(&self, dim: u32) -> (u32, u32) {
+        let s = self.state.index % self.spp;
+        let p = self.state.hash(dim) ^ (self.state.index / self.spp).wrapping_mul(0x9e3779b9);
+        (s, p)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: usize, index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dim = self.state.next_dims(1);
+        let (s, p) = self.pattern(dim);
+        let n = self.spp;
+        let stratum = permute(s, n, p.wrapping_mul(0x68bc21eb));
+        let jitter = randfloat(s, p.wrapping_mul(0x967a889b));
+        (stratum as f32 + jitter) / n as f32
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dim = self.state.next_dims(2);
+        let (s, p) = self.pattern(dim);
+        let n = self.spp;
+        let m = ((n as f32).sqrt() as u32).max(1);
+        let k = n.div_ceil(m);
+        let s = permute(s, n, p.wrapping_mul(0x51633e2d));
+        let sx = permute(s % m, m, p.wrapping_mul(0x68bc21eb));
+        let sy = permute(s / m, k, p.wrapping_mul(0x02e5be93));
+        let jx = randfloat(s, p.wrapping_mul(0x967a889b));
+        let jy = randfloat(s, p.wrapping_mul(0x368cc8b7));
+        let x = (sx as f32 + (sy as f32 + jx) / k as f32) / m as f32;
+        let y = (s as f32 + jy) / n as f32;
+        (x.min(ONE_MINUS_EPSILON), y.min(ONE_MINUS_EPSILON))
+    }
+
+    fn rng(&mut self) -> &mut RNG {
+        &mut self.state.rng
+    }
+}
+
+/* Beyond this the Halton bases get large enough to show visible correlation */
+const HALTON_MAX_DIMS: u32 = 64;
+
+fn radical_inverse(base: u32, mut i: u32) -> f32 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_n = 1.0;
+    let mut reversed: u64 = 0;
+    while i > 0 {
+        let next = i / base;
+        let digit = i - next * base;
+        reversed = reversed * base as u64 + digit as u64;
+        inv_base_n *= inv_base;
+        i = next;
+    }
+    ((reversed as f64 * inv_base_n) as f32).min(ONE_MINUS_EPSILON)
+}
+
+fn first_primes(count: usize) -> Vec<u32> {
+    let mut primes = Vec::with_capacity(count);
+    let mut n = 2;
+    while primes.len() < count {
+        if primes.iter().take_while(|&&p| p * p <= n).all(|&p| n % p != 0) {
+            primes.push(n);
+        }
+        n += 1;
+    }
+    primes
+}
+
+/*
+ * Halton sequence with a per-pixel Cranley-Patterson rotation, so that
+ * neighbouring pixels don't share the exact same sample positions.
+ */
+pub struct HaltonSampler {
+    state: SampleState,
+    primes: Vec<u32>,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            state: SampleState::new(seed),
+            primes: first_primes(HALTON_MAX_DIMS as usize),
+        }
+    }
+
+    fn sample(&mut self) -> f32 {
+        let dim = self.state.next_dims(1);
+        if dim >= HALTON_MAX_DIMS {
+            return self.state.rng.sample_01();
+        }
+        let x = radical_inverse(self.primes[dim as usize], self.state.index);
+        let offset = to_f32(self.state.hash(dim));
+        let x = x + offset;
+        if x >= 1.0 { (x - 1.0).min(ONE_MINUS_EPSILON) } else { x }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: usize, index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.sample()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let x = self.sample();
+        let y = self.sample();
+        (x, y)
+    }
+
+    fn rng(&mut self) -> &mut RNG {
+        &mut self.state.rng
+    }
+}
+
+fn sobol_dim0(i: u32) -> u32 {
+    i.reverse_bits()
+}
+
+fn sobol_dim1(mut i: u32) -> u32 {
+    let mut res = 0;
+    let mut v = 1u32 << 31;
+    while i != 0 {
+        if i & 1 != 0 {
+            res ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    res
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/*
+ * Owen scrambled Sobol sequence, padded from the first two Sobol dimensions
+ * with a shuffled sample order per dimension (Burley, "Practical Hash-based
+ * Owen Scrambling", 2020).
+ */
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler { state: SampleState::new(seed) }
+    }
+
+    fn shuffled_index(&mut self, count: u32) -> (u32, u32) {
+        let dim = self.state.next_dims(count);
+        let seed = self.state.hash(dim);
+        (nested_uniform_scramble(self.state.index, seed), seed)
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: usize, index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn set_dimension(&mut self, dim: u32) {
+        self.state.dim = dim;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let (i, seed) = self.shuffled_index(1);
+        to_f32(nested_uniform_scramble(sobol_dim0(i), mix64(seed as u64) as u32))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let (i, seed) = self.shuffled_index(2);
+        let h = mix64(seed as u64);
+        let x = nested_uniform_scramble(sobol_dim0(i), h as u32);
+        let y = nested_uniform_scramble(sobol_dim1(i), (h >> 32) as u32);
+        (to_f32(x), to_f32(y))
+    }
+
+    fn rng(&mut self) -> &mut RNG {
+        &mut self.state.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strata_2d(sampler: &mut dyn Sampler, n: u32, grid: u32) -> Vec<u32> {
+        let mut counts = vec![0; (grid * grid) as usize];
+        for i in 0..n {
+            sampler.start_pixel_sample(3, i);
+            let (x, y) = sampler.get_2d();
+            assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+            let cx = (x * grid as f32) as u32;
+            let cy = (y * grid as f32) as u32;
+            counts[(cx + cy * grid) as usize] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_sobol_stratification() {
+        let mut sampler = SobolSampler::new(1);
+        let counts = strata_2d(&mut sampler, 16, 4);
+        assert!(counts.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_stratified_stratification() {
+        let mut sampler = StratifiedSampler::new(1, 16);
+        let counts = strata_2d(&mut sampler, 16, 4);
+        assert!(counts.iter().all(|&c| c == 1));
+
+        let mut strata = [0; 16];
+        for i in 0..16 {
+            sampler.start_pixel_sample(3, i);
+            strata[(sampler.get_1d() * 16.0) as usize] += 1;
+        }
+        assert!(strata.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_halton_1d() {
+        let mut sampler = HaltonSampler::new(1);
+        let mut strata = [0; 8];
+        for i in 0..8 {
+            sampler.start_pixel_sample(3, i);
+            let x = sampler.get_1d();
+            assert!((0.0..1.0).contains(&x));
+            strata[(x * 8.0) as usize] += 1;
+        }
+        assert!(strata.iter().all(|&c| c == 1));
+    }
+
+    #[test]
+    fn test_dimensions_are_consistent() {
+        let mut sampler = SobolSampler::new(1);
+        sampler.start_pixel_sample(5, 2);
+        sampler.start_bounce(1);
+        let a = sampler.get_2d();
+
+        sampler.start_pixel_sample(5, 2);
+        sampler.get_2d();
+        sampler.get_1d();
+        sampler.start_bounce(1);
+        let b = sampler.get_2d();
+
+        assert_eq!(a, b);
+    }
+}
@@ -0,0 +1,43 @@
+use crate::background::Background;
+use crate::bvh::BVH;
+use crate::light::Light;
+use crate::lightsampler::LightSampler;
+use crate::material::Material;
+use crate::medium::Medium;
+
+pub struct Scene<'a> {
+    pub world: BVH<'a>,
+    pub background: Box<dyn Background>,
+    pub lights: Vec<Box<dyn Light>>,
+    pub light_sampler: Box<dyn LightSampler>,
+    pub media: Vec<Box<dyn Medium + 'a>>,
+    /* Materials in the order they are numbered for the material ID output */
+    pub materials: Vec<&'a dyn Material>,
+}
+
+impl Scene<'_> {
+    /* Number of a material starting at one, zero for materials not in the list */
+    pub fn material_id(&self, mat: &dyn Material) -> u32 {
+        match self.materials.iter().position(|m| std::ptr::addr_eq(*m, mat)) {
+            Some(i) => i as u32 + 1,
+            None => 0,
+        }
+    }
+}
+
+/* Scene of the given objects and lights under a constant background, shared by the tests */
+#[cfg(test)]
+pub fn test_scene<'a>(objects: Vec<&'a dyn crate::hittable::Hittable>, lights: Vec<Box<dyn Light>>,
+                      background: crate::Vec3, materials: Vec<&'a dyn Material>) -> Scene<'a> {
+    use crate::background::ConstantBackground;
+    use crate::lightsampler::{new_light_sampler, LightSamplerKind};
+
+    Scene {
+        world: BVH::new(objects),
+        background: Box::new(ConstantBackground::new(background)),
+        light_sampler: new_light_sampler(LightSamplerKind::Uniform, &lights, 1.0),
+        lights,
+        media: Vec::new(),
+        materials,
+    }
+}
@@ -0,0 +1,267 @@
+use std::f32::consts::PI;
+
+use crate::Vec3;
+use crate::background::{Background, BackgroundSample};
+use crate::warp::*;
+
+/*
+ * Radiance is expressed in kilonits (1000 cd/m^2) for both the sky and the
+ * sun, so a clear noon sky zenith is around 5-10 and the sun disk around
+ * 1.6e6, and the two stay balanced under any exposure.
+ */
+
+/* Angular radius of the sun disk seen from earth */
+const SUN_ANGULAR_RADIUS: f32 = 0.2665 * PI / 180.0;
+/* Luminance of the sun before atmospheric extinction */
+const SUN_LUMINANCE: f32 = 2.0e6;
+/* Directions along each axis of the grid the power of the sky dome is estimated over */
+const SKY_POWER_RES: usize = 16;
+
+/* Perez et al. all-weather distribution coefficients A to E */
+type Perez = [f32; 5];
+
+fn perez(c: &Perez, cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32 {
+    (1.0 + c[0] * (c[1] / cos_theta.max(0.01)).exp()) *
+    (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+/*
+ * Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight",
+ * 1999. The sky is black below the horizon, the ground is expected to be
+ * geometry.
+ *
+ * The sun disk is part of the background rather than a light of its own.
+ * Rays escaping the scene see it through eval and are weighted against pdf
+ * by the integrators, which would all have to leave the disk out if it
+ * were in scene.lights as well. Sampling picks the disk or the dome in
+ * proportion to the power each sends down.
+ */
+pub struct PhysicalSky {
+    sun_dir: Vec3,
+    sun_radiance: Vec3,
+    /* 1 - cos of the angular radius of the sun disk */
+    sun_one_minus_cos: f32,
+    coeffs: [Perez; 3],
+    /* Zenith luminance and chromaticity divided by the Perez function at the zenith */
+    zenith: [f32; 3],
+    intensity: f32,
+    /* Probability of sampling the sun disk instead of the sky dome */
+    sun_prob: f32,
+}
+
+impl PhysicalSky {
+    /* Angles in radians, azimuth is measured from -z towards +x */
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, intensity: f32) -> PhysicalSky {
+        let t = turbidity.clamp(1.7, 10.0);
+        let sun_dir = Vec3::new(elevation.cos() * azimuth.sin(),
+                                elevation.sin(),
+                                -elevation.cos() * azimuth.cos());
+
+        let coeffs = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251,
+             0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125,
+             -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102,
+             -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        /* The zenith formulas are only valid with the sun above the horizon */
+        let theta_s = (PI / 2.0 - elevation).clamp(0.0, PI / 2.0);
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+
+        let th = theta_s;
+        let th2 = th * th;
+        let th3 = th2 * th;
+        let t2 = t * t;
+        let zenith_x = t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) +
+                       t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) +
+                       (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+        let zenith_yc = t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) +
+                        t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) +
+                        (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+        let zenith_vals = [zenith_y, zenith_x, zenith_yc];
+        let mut zenith = [0.0; 3];
+        for i in 0..3 {
+            zenith[i] = zenith_vals[i] / perez(&coeffs[i], 1.0, theta_s, theta_s.cos());
+        }
+
+        let sun_radiance = if elevation > -SUN_ANGULAR_RADIUS {
+            Self::sun_transmittance(theta_s, t) * SUN_LUMINANCE
+        } else {
+            Vec3::zero()
+        };
+
+        let half = SUN_ANGULAR_RADIUS / 2.0;
+        let sun_one_minus_cos = 2.0 * half.sin() * half.sin();
+
+        let mut sky = PhysicalSky { sun_dir, sun_radiance, sun_one_minus_cos, coeffs, zenith, intensity,
+                                    sun_prob: 0.0 };
+        let sun_power = sky.sun_radiance.luminance() * 2.0 * PI * sky.sun_one_minus_cos;
+        let sky_power = sky.sky_power();
+        if sun_power > 0.0 {
+            sky.sun_prob = sun_power / (sun_power + sky_power);
+        }
+        sky
+    }
+
+    /* Luminance of the dome integrated over the upper hemisphere, by a grid of uniformly spread directions */
+    fn sky_power(&self) -> f32 {
+        let n = SKY_POWER_RES;
+        let mut sum = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let local = sample_hemisphere(((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32));
+                sum += self.sky_radiance(Vec3::new(local.x, local.z, local.y)).luminance();
+            }
+        }
+        sum / (n * n) as f32 * 2.0 * PI
+    }
+
+    /*
+     * Rayleigh and aerosol extinction along the path of the sunlight at
+     * wavelengths representative of the red, green and blue primaries.
+     */
+    fn sun_transmittance(theta_s: f32, turbidity: f32) -> Vec3 {
+        let theta_deg = theta_s.to_degrees();
+        let mass = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_deg).max(0.01).powf(-1.253));
+        let beta = 0.046_083_66 * turbidity - 0.045_860_26;
+        let alpha = 1.3;
+
+        let tau = |lambda_um: f32| {
+            let rayleigh = (-mass * 0.008735 * lambda_um.powf(-4.08)).exp();
+            let aerosol = (-mass * beta * lambda_um.powf(-alpha)).exp();
+            rayleigh * aerosol
+        };
+
+        Vec3::new(tau(0.65), tau(0.55), tau(0.45))
+    }
+
+    fn sky_radiance(&self, dir: Vec3) -> Vec3 {
+        if dir.y <= 0.0 {
+            return Vec3::zero();
+        }
+
+        let cos_gamma = dir.dot(self.sun_dir).clamp(-1.0, 1.0);
+        let gamma = cos_gamma.acos();
+
+        let lum = self.zenith[0] * perez(&self.coeffs[0], dir.y, gamma, cos_gamma);
+        let x = self.zenith[1] * perez(&self.coeffs[1], dir.y, gamma, cos_gamma);
+        let y = self.zenith[2] * perez(&self.coeffs[2], dir.y, gamma, cos_gamma);
+        if y <= 0.0 {
+            return Vec3::zero();
+        }
+
+        /* xyY to XYZ to linear Rec. 709 */
+        let cx = x / y * lum;
+        let cz = (1.0 - x - y) / y * lum;
+        let rgb = Vec3::new(3.2406 * cx - 1.5372 * lum - 0.4986 * cz,
+                            -0.9689 * cx + 1.8758 * lum + 0.0415 * cz,
+                            0.0557 * cx - 0.2040 * lum + 1.0570 * cz);
+        Vec3::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0))
+    }
+
+    fn in_sun_disk(&self, dir: Vec3) -> bool {
+        1.0 - dir.dot(self.sun_dir) < self.sun_one_minus_cos
+    }
+
+    fn has_sun(&self) -> bool {
+        self.sun_radiance != Vec3::zero()
+    }
+}
+
+impl Background for PhysicalSky {
+    fn eval(&self, dir: Vec3) -> Vec3 {
+        let dir = dir.normalized();
+        let mut radiance = self.sky_radiance(dir);
+        if self.has_sun() && self.in_sun_disk(dir) {
+            radiance += self.sun_radiance;
+        }
+        radiance * self.intensity
+    }
+
+    fn sample(&self, u: (f32, f32)) -> Option<BackgroundSample> {
+        let sun_prob = self.sun_prob;
+        let dir = if u.0 < sun_prob {
+            let u = (u.0 / sun_prob, u.1);
+            let local = sample_cone(u, self.sun_one_minus_cos);
+            let (t, b) = self.sun_dir.basis();
+            t * local.x + b * local.y + self.sun_dir * local.z
+        } else {
+            let u = ((u.0 - sun_prob) / (1.0 - sun_prob), u.1);
+            let local = sample_hemisphere(u);
+            Vec3::new(local.x, local.z, local.y)
+        };
+
+        let pdf = self.pdf(dir);
+        if pdf == 0.0 {
+            return None;
+        }
+        Some(BackgroundSample { dir, radiance: self.eval(dir), pdf })
+    }
+
+    fn pdf(&self, dir: Vec3) -> f32 {
+        let dir = dir.normalized();
+        let sun_prob = self.sun_prob;
+        let mut pdf = 0.0;
+        if sun_prob > 0.0 && self.in_sun_disk(dir) {
+            pdf += sun_prob * cone_pdf(self.sun_one_minus_cos);
+        }
+        if dir.y > 0.0 {
+            pdf += (1.0 - sun_prob) / (2.0 * PI);
+        }
+        pdf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sky_brightness() {
+        let sky = PhysicalSky::new(45f32.to_radians(), 0.0, 3.0, 1.0);
+        let zenith = sky.eval(Vec3::new(0.0, 1.0, 0.0)).luminance();
+        let sun = sky.eval(sky.sun_dir).luminance();
+
+        /* Clear sky zenith is a few kilonits, the sun around a million */
+        assert!(zenith > 1.0 && zenith < 20.0);
+        assert!(sun > 1.0e6 && sun < 2.0e6);
+
+        /* The sky is brighter around the sun than opposite to it */
+        let near_sun = sky.eval(Vec3::new(0.0, 0.8, -0.6)).luminance();
+        let away = sky.eval(Vec3::new(0.0, 0.8, 0.6)).luminance();
+        assert!(near_sun > away);
+    }
+
+    #[test]
+    fn test_sky_sample_pdf() {
+        let sky = PhysicalSky::new(30f32.to_radians(), 1.0, 4.0, 1.0);
+        let mut sun_samples = 0;
+        for i in 0..32 {
+            for j in 0..32 {
+                let u = ((i as f32 + 0.5) / 32.0, (j as f32 + 0.5) / 32.0);
+                let s = sky.sample(u).unwrap();
+                assert!((sky.pdf(s.dir) - s.pdf).abs() / s.pdf < 1e-3);
+                if sky.in_sun_disk(s.dir) {
+                    sun_samples += 1;
+                }
+            }
+        }
+        /* Every column of u.0 below the sun probability lands on the disk */
+        assert!((sun_samples as f32 / (32.0 * 32.0) - sky.sun_prob).abs() <= 1.0 / 32.0);
+    }
+
+    /* The sun gets the samples its power is worth, fewer when low and hazy and none below the horizon */
+    #[test]
+    fn test_sun_sampling_follows_power() {
+        let noon = PhysicalSky::new(60f32.to_radians(), 0.0, 2.0, 1.0);
+        let hazy_dusk = PhysicalSky::new(3f32.to_radians(), 0.0, 9.0, 1.0);
+        let night = PhysicalSky::new(-10f32.to_radians(), 0.0, 2.0, 1.0);
+        assert!(noon.sun_prob > hazy_dusk.sun_prob && hazy_dusk.sun_prob > 0.0);
+        assert!(noon.sun_prob < 1.0);
+        assert_eq!(night.sun_prob, 0.0);
+    }
+}
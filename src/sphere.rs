@@ -1,25 +1,42 @@
+use std::f32::consts::PI;
+use std::str::FromStr;
+
 use crate::Vec3;
 use crate::Ray;
 use crate::hittable::{Hittable, HitRecord};
 use crate::material::Material;
+use crate::options::parse_vec3;
 use crate::rng::RNG;
 use crate::aabb::AABB;
 
 pub struct Sphere<'a> {
+    /* Center at time 0 */
     pub c: Vec3,
     pub r: f32,
+    /* Displacement of the center between time 0 and 1 */
+    pub motion: Vec3,
     mat: &'a dyn Material,
 }
 
 impl Sphere<'_> {
-    pub fn new(center: Vec3, radius: f32, mat: &dyn Material) -> Sphere {
-        Sphere { c: center, r: radius, mat: mat }
+    pub fn new(center: Vec3, radius: f32, mat: &dyn Material) -> Sphere<'_> {
+        Sphere { c: center, r: radius, motion: Vec3::zero(), mat }
+    }
+
+    /* Sphere moving linearly from c0 at time 0 to c1 at time 1 and resting outside of that */
+    pub fn moving(c0: Vec3, c1: Vec3, radius: f32, mat: &dyn Material) -> Sphere<'_> {
+        Sphere { c: c0, r: radius, motion: c1 - c0, mat }
+    }
+
+    pub fn center(&self, time: f32) -> Vec3 {
+        self.c + self.motion * time.clamp(0.0, 1.0)
     }
 }
 
 impl Hittable for Sphere<'_> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, _rng: &mut RNG) -> Option<HitRecord> {
-        let oc = ray.orig - self.c;
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, _rng: &mut RNG) -> Option<HitRecord<'_>> {
+        let center = self.center(ray.time);
+        let oc = ray.orig - center;
         let a = ray.dir.len2();
         let half_b =  oc.dot(ray.dir);
         let c = oc.len2() - self.r * self.r;
@@ -39,12 +56,70 @@ impl Hittable for Sphere<'_> {
         }
 
         let p = ray.at(root);
+        let n = (p - center) / self.r;
+        /* Longitude around y starting at -x and latitude from the bottom */
+        let uv = ((-n.z).atan2(n.x) / (2.0 * PI) + 0.5, (-n.y).clamp(-1.0, 1.0).acos() / PI);
 
-        Some(HitRecord::new(p, (p - self.c) / self.r, root, ray, self.mat))
+        Some(HitRecord::new(p, n, root, ray, self.mat, uv))
     }
 
     fn get_aabb(&self) -> Option<AABB> {
         let r = Vec3::new(self.r, self.r, self.r);
-        Some(AABB::new(self.c - r, self.c + r))
+        let start = AABB::new(self.c - r, self.c + r);
+        let end = AABB::new(self.c + self.motion - r, self.c + self.motion + r);
+        Some(start.merge(&end))
+    }
+}
+
+/*
+ * Diffuse moving sphere as given on the command line:
+ *   x0,y0,z0:x1,y1,z1:radius:r,g,b
+ * with the centers at time 0 and 1 and the albedo last.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct MovingSphereDesc {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub radius: f32,
+    pub albedo: Vec3,
+}
+
+impl FromStr for MovingSphereDesc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        match fields.as_slice() {
+            [start, end, radius, albedo] => Ok(MovingSphereDesc {
+                start: parse_vec3(start)?,
+                end: parse_vec3(end)?,
+                radius: radius.parse().map_err(|_| format!("Invalid radius: {}", radius))?,
+                albedo: parse_vec3(albedo)?,
+            }),
+            _ => Err(format!("Invalid moving sphere: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+
+    #[test]
+    fn test_moving_sphere() {
+        let mat = Lambertian::new(Vec3::one());
+        let sphere = Sphere::moving(Vec3::zero(), Vec3::new(4.0, 0.0, 0.0), 1.0, &mat);
+        let mut rng = RNG::with_seed(0);
+
+        /* A ray down through x = 4 only finds the sphere at the end of its motion */
+        let down = |time| Ray::new(Vec3::new(4.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), time);
+        assert!(sphere.hit(&down(0.0), 0.0, f32::INFINITY, &mut rng).is_none());
+        let hit = sphere.hit(&down(1.0), 0.0, f32::INFINITY, &mut rng).unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-5);
+
+        let aabb = sphere.get_aabb().unwrap();
+        assert_eq!(aabb.min, Vec3::new(-1.0, -1.0, -1.0));
+        assert_eq!(aabb.max, Vec3::new(5.0, 1.0, 1.0));
+        assert!("0,0,0:1,0,0:0.5".parse::<MovingSphereDesc>().is_err());
     }
 }
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::aov::{AovFilm, AovSample};
+use crate::camera::{Camera, Shutter};
+use crate::distribution::Distribution1D;
+use crate::film::Film;
+use crate::hittable::Hittable;
+use crate::integrator::{trace_specular, BounceCounts, DepthPolicy, DiffuseHit, T_MAX, T_MIN};
+use crate::sampler::{Sampler, BOUNCE_BSDF_DIM};
+use crate::scene::Scene;
+use crate::stats::{count, Counter};
+use crate::warp::{sample_unit_disk, sample_unit_sphere};
+
+/* Fraction of the photons of an iteration that stay counted, sets how quickly the radius shrinks */
+const ALPHA: f32 = 2.0 / 3.0;
+
+/* Photon gathering state of a pixel, kept over all iterations */
+struct SppmPixel<'s> {
+    radius: f32,
+    /* Photons counted so far and their flux times the BSDF, both scaled down along with the radius */
+    n: f32,
+    tau: Vec3,
+    /* Where the camera path of this iteration gathers photons, and what it has gathered there */
+    visible: Option<DiffuseHit<'s>>,
+    phi: Vec3,
+    m: u32,
+}
+
+impl SppmPixel<'_> {
+    /* Folds the photons of an iteration into the totals, shrinking the radius to keep only ALPHA of them */
+    fn update(&mut self) {
+        if let (Some(visible), true) = (&self.visible, self.m > 0) {
+            let m = self.m as f32;
+            let n = self.n + ALPHA * m;
+            let radius = self.radius * (n / (self.n + m)).sqrt();
+            self.tau = (self.tau + visible.throughput * self.phi) * ((radius * radius) / (self.radius * self.radius));
+            self.n = n;
+            self.radius = radius;
+        }
+        self.visible = None;
+        self.phi = Vec3::zero();
+        self.m = 0;
+    }
+}
+
+/*
+ * Hash grid over the visible points, each listed in every cell its
+ * gathering sphere overlaps. Cells are as wide as the largest radius,
+ * which keeps that to at most eight, and a photon only needs to look at
+ * the points listed in the cell it lands in.
+ */
+struct PhotonGrid {
+    cell: f32,
+    cells: HashMap<(i32, i32, i32), Vec<usize>>,
+}
+
+impl PhotonGrid {
+    fn new(pixels: &[SppmPixel]) -> PhotonGrid {
+        let cell = pixels.iter().filter(|p| p.visible.is_some()).map(|p| p.radius).fold(0.0, f32::max);
+        let mut grid = PhotonGrid { cell, cells: HashMap::new() };
+        for (i, pixel) in pixels.iter().enumerate() {
+            if let Some(visible) = &pixel.visible {
+                let r = Vec3::one() * pixel.radius;
+                let (lo, hi) = (grid.cell_of(visible.rec.p - r), grid.cell_of(visible.rec.p + r));
+                for x in lo.0..=hi.0 {
+                    for y in lo.1..=hi.1 {
+                        for z in lo.2..=hi.2 {
+                            grid.cells.entry((x, y, z)).or_default().push(i);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+
+    fn cell_of(&self, p: Vec3) -> (i32, i32, i32) {
+        ((p.x / self.cell).floor() as i32, (p.y / self.cell).floor() as i32, (p.z / self.cell).floor() as i32)
+    }
+
+    /* Pixels whose visible points may be within their radius of p */
+    fn near(&self, p: Vec3) -> &[usize] {
+        if self.cells.is_empty() {
+            return &[];
+        }
+        self.cells.get(&self.cell_of(p)).map_or(&[], |pixels| pixels.as_slice())
+    }
+}
+
+/*
+ * Where photons start, the lights and the background picked by power.
+ * Photons from the background come in from a disk as wide as the scene,
+ * in uniformly distributed directions.
+ */
+struct Emitters {
+    distribution: Distribution1D,
+    center: Vec3,
+    radius: f32,
+}
+
+impl Emitters {
+    fn new(scene: &Scene) -> Option<Emitters> {
+        let aabb = scene.world.get_aabb()?;
+        let center = (aabb.min + aabb.max) / 2.0;
+        let radius = (aabb.max - aabb.min).len() / 2.0;
+        let mut powers: Vec<f32> = scene.lights.iter().map(|light| light.power(radius)).collect();
+        powers.push(Self::background_power(scene, radius));
+        if powers.iter().all(|power| *power <= 0.0) {
+            return None;
+        }
+        Some(Emitters { distribution: Distribution1D::new(&powers), center, radius })
+    }
+
+    /* Power the background sends through the disk, its radiance averaged over a grid of directions */
+    fn background_power(scene: &Scene, radius: f32) -> f32 {
+        let (nu, nv) = (32, 16);
+        let mut sum = 0.0;
+        for i in 0..nu {
+            for j in 0..nv {
+                let u = ((i as f32 + 0.5) / nu as f32, (j as f32 + 0.5) / nv as f32);
+                sum += scene.background.eval(sample_unit_sphere(u)).luminance();
+            }
+        }
+        sum / (nu * nv) as f32 * 4.0 * PI * PI * radius * radius
+    }
+
+    /* Ray and flux of a photon, with the light it left if it has a cutoff to apply at the first hit */
+    fn sample(&self, scene: &Scene, sampler: &mut dyn Sampler, time: f32) -> Option<(Ray, Vec3, Option<usize>)> {
+        let (index, pmf) = self.distribution.sample_discrete(sampler.get_1d());
+        let u_pos = sampler.get_2d();
+        let u_dir = sampler.get_2d();
+        if let Some(light) = scene.lights.get(index) {
+            let emission = light.sample_le(u_pos, u_dir, time)?;
+            if emission.pdf_pos == 0.0 || emission.pdf_dir == 0.0 {
+                return None;
+            }
+            let cos = if emission.n == Vec3::zero() { 1.0 } else { emission.n.dot(emission.ray.dir).abs() };
+            let flux = emission.radiance * (cos / (pmf * emission.pdf_pos * emission.pdf_dir));
+            return Some((emission.ray, flux, Some(index)));
+        }
+
+        let dir = sample_unit_sphere(u_dir);
+        let (t, b) = dir.basis();
+        let disk = sample_unit_disk(u_pos) * self.radius;
+        let orig = self.center + t * disk.x + b * disk.y - dir * self.radius;
+        let area = PI * self.radius * self.radius;
+        let flux = scene.background.eval(-dir) * (area * 4.0 * PI / pmf);
+        Some((Ray::new(orig, dir, time), flux, None))
+    }
+}
+
+/*
+ * Stochastic progressive photon mapping. Every iteration traces a camera
+ * ray per pixel through glass and mirrors to the first other surface,
+ * taking the light found on the way and the direct light there as an
+ * ordinary sample (see trace_specular). Photons are then traced from the
+ * lights and the background, and from their second hit on they add to
+ * the visible points they land within the radius of. Each pixel shrinks
+ * its radius as photons come in, so that the blur of the density estimate
+ * vanishes over the iterations while the noise keeps going down. Caustics
+ * seen directly or through glass converge where path tracing has to hit
+ * small lights by chance.
+ */
+pub struct PhotonMapper {
+    pub policy: DepthPolicy,
+    pub iterations: u32,
+    /* Photons traced per iteration */
+    pub photons: usize,
+    /* Radius every pixel starts out gathering photons within */
+    pub radius: f32,
+}
+
+impl PhotonMapper {
+    /* Renders all iterations onto the film, recording the AOVs of the camera rays */
+    pub fn render(&self, cam: &dyn Camera, scene: &Scene, sampler: &mut dyn Sampler, film: &mut Film,
+                  aovs: &mut AovFilm) {
+        let bounds = film.sample_bounds();
+        let mut pixels: Vec<SppmPixel> = (0..bounds.w() * bounds.h()).map(|_| SppmPixel {
+            radius: self.radius,
+            n: 0.0,
+            tau: Vec3::zero(),
+            visible: None,
+            phi: Vec3::zero(),
+            m: 0,
+        }).collect();
+        let emitters = Emitters::new(scene);
+        let (img_w, img_h) = (film.w, film.h);
+
+        for iteration in 0..self.iterations {
+            eprint!("\rIteration {} of {}", iteration + 1, self.iterations);
+            for y in bounds.y0..bounds.y1 {
+                for x in bounds.x0..bounds.x1 {
+                    sampler.start_pixel_sample(x + y * img_w, iteration);
+                    let jitter = sampler.get_2d();
+                    let u = (x as f32 + jitter.0) / img_w as f32;
+                    let v = 1.0 - (y as f32 + jitter.1) / img_h as f32;
+                    let mut aov = AovSample::default();
+                    let (radiance, visible) = match cam.get_ray(u, v, sampler) {
+                        Some(ray) => trace_specular(&ray, scene, sampler, &self.policy, Some(&mut aov)),
+                        None => (Vec3::zero(), None),
+                    };
+                    film.add_sample(x, y, jitter, radiance);
+                    aovs.add_sample(x, y, jitter, radiance, &aov);
+                    pixels[(x - bounds.x0) + (y - bounds.y0) * bounds.w()].visible = visible;
+                }
+            }
+
+            if let Some(emitters) = &emitters {
+                let grid = PhotonGrid::new(&pixels);
+                for i in 0..self.photons {
+                    /* Photons are numbered after the pixels for the sampler */
+                    sampler.start_pixel_sample(img_w * img_h + i, iteration);
+                    self.trace_photon(scene, emitters, &grid, &mut pixels, sampler, cam.shutter());
+                }
+            }
+            for pixel in &mut pixels {
+                pixel.update();
+            }
+        }
+
+        let photons = self.iterations as f32 * self.photons as f32;
+        for y in bounds.y0..bounds.y1 {
+            for x in bounds.x0..bounds.x1 {
+                let pixel = &pixels[(x - bounds.x0) + (y - bounds.y0) * bounds.w()];
+                film.add_estimate(x, y, pixel.tau / (photons * PI * pixel.radius * pixel.radius));
+            }
+        }
+    }
+
+    /*
+     * Follows a photon through the scene at a time of its own within the
+     * shutter interval. Its first hit is skipped as the direct light is
+     * sampled from the visible points. Russian roulette keeps the flux of
+     * the photons about constant.
+     */
+    fn trace_photon<'s>(&self, scene: &Scene, emitters: &Emitters, grid: &PhotonGrid, pixels: &mut [SppmPixel<'s>],
+                        sampler: &mut dyn Sampler, shutter: Shutter) {
+        let time = shutter.sample(sampler);
+        /* The emission takes the block after the bounces, clear of the time dimension of the camera */
+        sampler.set_bounce_dimension(self.policy.max_depth, 0);
+        let (mut ray, mut beta, light) = match emitters.sample(scene, sampler, time) {
+            Some(photon) => photon,
+            None => return,
+        };
+        let mut bounces = BounceCounts::default();
+        for depth in 0..self.policy.max_depth {
+            sampler.start_bounce(depth);
+            let rr_u = sampler.get_1d();
+            count(Counter::ScatterRays, 1);
+            let rec = match scene.world.hit(&ray, T_MIN, T_MAX, sampler.rng()) {
+                Some(rec) => rec,
+                None => break,
+            };
+            if depth == 0 {
+                if let Some(index) = light {
+                    beta *= scene.lights[index].cutoff((rec.p - ray.orig).len2());
+                }
+            } else {
+                let wi = -ray.dir.normalized();
+                for &i in grid.near(rec.p) {
+                    let pixel = &mut pixels[i];
+                    let visible = match &pixel.visible {
+                        Some(visible) => visible,
+                        None => continue,
+                    };
+                    if (visible.rec.p - rec.p).len2() > pixel.radius * pixel.radius {
+                        continue;
+                    }
+                    /* The density of the photons already accounts for the cosine at the visible point */
+                    let cos = visible.rec.n.dot(wi).abs();
+                    if cos > 0.0 {
+                        pixel.phi += beta * visible.rec.mat.eval(visible.wo, wi, &visible.rec) / cos;
+                    }
+                    pixel.m += 1;
+                }
+            }
+
+            sampler.set_bounce_dimension(depth, BOUNCE_BSDF_DIM);
+            let scatter = match rec.mat.scatter(&ray, &rec, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            if !bounces.add(scatter.lobe, &self.policy) {
+                break;
+            }
+            let next = beta * scatter.attenuation;
+            let survive = if beta.luminance() > 0.0 { (next.luminance() / beta.luminance()).min(1.0) } else { 0.0 };
+            if rr_u >= survive {
+                break;
+            }
+            beta = next / survive;
+            ray = scatter.ray;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{Frame, OrthographicCamera, Shutter};
+    use crate::film::CropWindow;
+    use crate::filter::{new_filter, FilterKind};
+    use crate::light::{Light, PointLight};
+    use crate::material::Lambertian;
+    use crate::sampler::IndependentSampler;
+    use crate::scene::test_scene;
+    use crate::sphere::Sphere;
+
+    /*
+     * Inside a sphere lit by a point light at its center every bounce
+     * scales the radiance by the albedo, so with an albedo of one half the
+     * photons gather as much light as comes directly.
+     */
+    #[test]
+    fn test_photons_in_sphere() {
+        let mat = Lambertian::new(Vec3::one() * 0.5);
+        let shell = Sphere::new(Vec3::zero(), 1.0, &mat);
+        let lights: Vec<Box<dyn Light>> = vec![Box::new(PointLight::new(Vec3::zero(), Vec3::one(), 0.0))];
+        let scene = test_scene(vec![&shell], lights, Vec3::zero(), vec![&mat]);
+        let frame = Frame::look_at(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, -1.0));
+        let camera = OrthographicCamera::new(frame, 1.0, 0.01, Shutter { open: 0.0, close: 0.0 });
+        let mut film = Film::new(1, 1, CropWindow::default(), new_filter(FilterKind::Box, None));
+        let mut aovs = AovFilm::new(Vec::new(), film.crop);
+        let mapper = PhotonMapper { policy: DepthPolicy::default(), iterations: 16, photons: 20000, radius: 0.2 };
+        mapper.render(&camera, &scene, &mut IndependentSampler::new(3), &mut film, &mut aovs);
+
+        let direct = 0.5 / PI;
+        let radiance = film.image()[0].x;
+        assert!((radiance - 2.0 * direct).abs() < 0.1 * direct, "{}", radiance);
+    }
+}
@@ -0,0 +1,250 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+/* Events counted while rendering */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Counter {
+    CameraRays,
+    /* Rays continuing a path after it scattered */
+    ScatterRays,
+    ShadowRays,
+    BvhNodes,
+    PrimitiveTests,
+    /* Surface and medium interactions along all paths */
+    PathVertices,
+}
+
+const COUNTERS: usize = 6;
+
+thread_local! {
+    static COUNTS: [Cell<u64>; COUNTERS] = const { [const { Cell::new(0) }; COUNTERS] };
+}
+
+/*
+ * Adds to one of the counters of this thread. They are plain cells rather
+ * than arguments of every intersection routine, cheap enough to stay on.
+ */
+pub fn count(counter: Counter, n: u64) {
+    COUNTS.with(|counts| {
+        let cell = &counts[counter as usize];
+        cell.set(cell.get() + n);
+    });
+}
+
+/* Current value of a counter, to count what a single ray takes */
+pub fn get(counter: Counter) -> u64 {
+    COUNTS.with(|counts| counts[counter as usize].get())
+}
+
+/* Counts since the last call, which starts them over */
+pub fn take() -> [u64; COUNTERS] {
+    COUNTS.with(|counts| {
+        let mut values = [0; COUNTERS];
+        for (value, cell) in values.iter_mut().zip(counts) {
+            *value = cell.replace(0);
+        }
+        values
+    })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(StatsFormat::Table),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(format!("Unknown statistics format: {}", s)),
+        }
+    }
+}
+
+/* Counters of rendering one image */
+pub struct ImageStats {
+    pub name: String,
+    pub time: Duration,
+    pub counts: [u64; COUNTERS],
+}
+
+impl ImageStats {
+    fn get(&self, counter: Counter) -> u64 {
+        self.counts[counter as usize]
+    }
+
+    fn rays(&self) -> u64 {
+        self.get(Counter::CameraRays) + self.get(Counter::ScatterRays) + self.get(Counter::ShadowRays)
+    }
+
+    /* Ratio that is zero rather than NaN if nothing was counted */
+    fn per(&self, a: u64, b: u64) -> f64 {
+        if b == 0 { 0.0 } else { a as f64 / b as f64 }
+    }
+
+    /* Name and value of every figure reported */
+    fn figures(&self) -> Vec<(&'static str, f64)> {
+        let rays = self.rays();
+        vec![
+            ("render_seconds", self.time.as_secs_f64()),
+            ("camera_rays", self.get(Counter::CameraRays) as f64),
+            ("scatter_rays", self.get(Counter::ScatterRays) as f64),
+            ("shadow_rays", self.get(Counter::ShadowRays) as f64),
+            ("rays_per_second", rays as f64 / self.time.as_secs_f64().max(1e-9)),
+            ("bvh_nodes_per_ray", self.per(self.get(Counter::BvhNodes), rays)),
+            ("primitive_tests_per_ray", self.per(self.get(Counter::PrimitiveTests), rays)),
+            ("average_path_length", self.per(self.get(Counter::PathVertices), self.get(Counter::CameraRays))),
+        ]
+    }
+}
+
+/* Size of the acceleration structure and how long it took to build */
+pub struct BvhStats {
+    pub build_time: Duration,
+    pub nodes: usize,
+    pub bytes: usize,
+}
+
+/* Quoted JSON string, control characters escaped as \u00XX */
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/* Everything counted in a run, as a table for people or JSON for tools */
+pub fn report(format: StatsFormat, bvh: &BvhStats, images: &[ImageStats]) -> String {
+    let mut out = String::new();
+    match format {
+        StatsFormat::Table => {
+            let _ = writeln!(out, "{:<26}{:>16.3}", "bvh_build_seconds", bvh.build_time.as_secs_f64());
+            let _ = writeln!(out, "{:<26}{:>16}", "bvh_nodes", bvh.nodes);
+            let _ = writeln!(out, "{:<26}{:>16}", "bvh_bytes", bvh.bytes);
+            for image in images {
+                let _ = writeln!(out, "{}", image.name);
+                for (name, value) in image.figures() {
+                    if value.fract() == 0.0 {
+                        let _ = writeln!(out, "  {:<24}{:>16}", name, value);
+                    } else {
+                        let _ = writeln!(out, "  {:<24}{:>16.3}", name, value);
+                    }
+                }
+            }
+        }
+        StatsFormat::Json => {
+            let _ = write!(out, "{{\"bvh\": {{\"build_seconds\": {}, \"nodes\": {}, \"bytes\": {}}}, \"images\": [",
+                           bvh.build_time.as_secs_f64(), bvh.nodes, bvh.bytes);
+            for (i, image) in images.iter().enumerate() {
+                let separator = if i == 0 { "" } else { ", " };
+                let _ = write!(out, "{}{{\"name\": {}", separator, json_string(&image.name));
+                for (name, value) in image.figures() {
+                    let _ = write!(out, ", \"{}\": {}", name, value);
+                }
+                out.push('}');
+            }
+            out.push_str("]}\n");
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        take();
+        count(Counter::CameraRays, 2);
+        count(Counter::ShadowRays, 2);
+        count(Counter::BvhNodes, 12);
+        count(Counter::PathVertices, 3);
+        let image = ImageStats { name: "main".to_string(), time: Duration::from_secs(2), counts: take() };
+        assert_eq!(take(), [0; COUNTERS]);
+
+        let bvh = BvhStats { build_time: Duration::from_millis(5), nodes: 3, bytes: 100 };
+        let json = report(StatsFormat::Json, &bvh, &[image]);
+        assert!(json.starts_with("{\"bvh\": {\"build_seconds\": 0.005, \"nodes\": 3"));
+        assert!(json.contains("\"rays_per_second\": 2, \"bvh_nodes_per_ray\": 3,"));
+        assert!(json.contains("\"average_path_length\": 1.5}]}"));
+    }
+
+    /* Minimal JSON reader, returns the strings of the value starting at s */
+    fn parse(s: &mut std::iter::Peekable<std::str::Chars>, strings: &mut Vec<String>) -> Option<()> {
+        let skip = |s: &mut std::iter::Peekable<std::str::Chars>| while s.next_if(|c| c.is_whitespace()).is_some() {};
+        skip(s);
+        match s.next()? {
+            '{' | '[' => {
+                loop {
+                    skip(s);
+                    if s.next_if(|&c| c == '}' || c == ']').is_some() {
+                        return Some(());
+                    }
+                    parse(s, strings)?;
+                    skip(s);
+                    match s.next()? {
+                        ':' | ',' => {}
+                        '}' | ']' => return Some(()),
+                        _ => return None,
+                    }
+                }
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match s.next()? {
+                        '"' => break,
+                        '\\' => match s.next()? {
+                            '"' => string.push('"'),
+                            '\\' => string.push('\\'),
+                            'u' => {
+                                let hex: String = s.take(4).collect();
+                                string.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                            }
+                            _ => return None,
+                        },
+                        c if (c as u32) < 0x20 => return None,
+                        c => string.push(c),
+                    }
+                }
+                strings.push(string);
+                Some(())
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                while s.next_if(|&c| c.is_ascii_digit() || ".eE+-".contains(c)).is_some() {}
+                Some(())
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_json_name() {
+        let name = "\"caf\u{e9}\"\\\n";
+        let image = ImageStats { name: name.to_string(), time: Duration::from_secs(1), counts: [0; COUNTERS] };
+        let bvh = BvhStats { build_time: Duration::from_millis(5), nodes: 3, bytes: 100 };
+        let json = report(StatsFormat::Json, &bvh, &[image]);
+
+        let mut strings = Vec::new();
+        let mut chars = json.chars().peekable();
+        assert!(parse(&mut chars, &mut strings).is_some());
+        assert!(chars.all(char::is_whitespace));
+        assert!(strings.iter().any(|s| s == name));
+    }
+}
@@ -0,0 +1,221 @@
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::rng::mix64;
+
+/* Curve compressing scene radiance into the displayable range */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMap {
+    /* Values above one are clipped */
+    Clamp,
+    /* L / (1 + L) on the luminance, which keeps hues */
+    Reinhard,
+    /* Hable's curve from Uncharted 2 */
+    Filmic,
+    /* Narkowicz's fit of the ACES reference rendering transform */
+    Aces,
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" | "none" => Ok(ToneMap::Clamp),
+            "reinhard" => Ok(ToneMap::Reinhard),
+            "filmic" => Ok(ToneMap::Filmic),
+            "aces" => Ok(ToneMap::Aces),
+            _ => Err(format!("Unknown tone mapping: {}", s)),
+        }
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+/* Linear white point of the filmic curve */
+const FILMIC_WHITE: f32 = 11.2;
+
+impl ToneMap {
+    pub fn apply(&self, c: Vec3) -> Vec3 {
+        let per_channel = |f: &dyn Fn(f32) -> f32| Vec3::new(f(c.x), f(c.y), f(c.z));
+        match self {
+            ToneMap::Clamp => c,
+            ToneMap::Reinhard => {
+                let l = c.luminance();
+                if l <= 0.0 { Vec3::zero() } else { c * (1.0 / (1.0 + l)) }
+            }
+            ToneMap::Filmic => per_channel(&|x| hable(2.0 * x) / hable(FILMIC_WHITE)),
+            ToneMap::Aces => per_channel(&|x| {
+                let x = x * 0.6;
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }),
+        }
+    }
+}
+
+/* sRGB opto-electronic transfer function, for a linear value in [0, 1] */
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/* Inverse of the sRGB encoding, the linear value of a displayed one */
+pub fn srgb_eotf(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+type Matrix3 = [[f32; 3]; 3];
+
+fn mul3(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut m = [[0.0; 3]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+    }
+    m
+}
+
+fn apply3(m: &Matrix3, v: Vec3) -> Vec3 {
+    Vec3::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+              m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+              m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+}
+
+const RGB_TO_XYZ: Matrix3 = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.119192, 0.9503041],
+];
+
+const XYZ_TO_RGB: Matrix3 = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.969266, 1.8760108, 0.041556],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+/* Bradford cone response, used for chromatic adaptation */
+const BRADFORD: Matrix3 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+const BRADFORD_INV: Matrix3 = [
+    [0.9869929, -0.1470543, 0.1599627],
+    [0.4323053, 0.5183603, 0.0492912],
+    [-0.0085287, 0.0400428, 0.9684867],
+];
+
+/* Chromaticity of the sRGB white point, D65 */
+const D65: (f32, f32) = (0.31271, 0.32902);
+
+/* Chromaticity of a black body, Kim et al.'s fit of the Planckian locus, 1667 to 25000 K */
+pub fn planckian_xy(kelvin: f32) -> (f32, f32) {
+    let t = kelvin.clamp(1667.0, 25000.0) as f64;
+    let x = if t <= 4000.0 {
+        -0.2661239e9 / (t * t * t) - 0.2343589e6 / (t * t) + 0.8776956e3 / t + 0.179910
+    } else {
+        -3.0258469e9 / (t * t * t) + 2.1070379e6 / (t * t) + 0.2226347e3 / t + 0.240390
+    };
+    let y = if t <= 2222.0 {
+        -1.1063814 * x * x * x - 1.34811020 * x * x + 2.18555832 * x - 0.20219683
+    } else if t <= 4000.0 {
+        -0.9549476 * x * x * x - 1.37418593 * x * x + 2.09137015 * x - 0.16748867
+    } else {
+        3.0817580 * x * x * x - 5.87338670 * x * x + 3.75112997 * x - 0.37001483
+    };
+    (x as f32, y as f32)
+}
+
+fn xy_to_xyz(xy: (f32, f32)) -> Vec3 {
+    Vec3::new(xy.0 / xy.1, 1.0, (1.0 - xy.0 - xy.1) / xy.1)
+}
+
+/*
+ * Linear sRGB transform that makes white lit by a black body at the given
+ * temperature come out neutral, a von Kries adaptation in Bradford space.
+ */
+pub fn white_balance(kelvin: f32) -> Matrix3 {
+    let src = apply3(&BRADFORD, xy_to_xyz(planckian_xy(kelvin)));
+    let dst = apply3(&BRADFORD, xy_to_xyz(D65));
+    let scale = [[dst.x / src.x, 0.0, 0.0], [0.0, dst.y / src.y, 0.0], [0.0, 0.0, dst.z / src.z]];
+    let adapt = mul3(&BRADFORD_INV, &mul3(&scale, &BRADFORD));
+    mul3(&XYZ_TO_RGB, &mul3(&adapt, &RGB_TO_XYZ))
+}
+
+/*
+ * Conversion of linear radiance to 8 bit sRGB for display: white balance,
+ * exposure in stops, the tone curve, the sRGB encoding and quantization,
+ * optionally dithered with triangular noise of one step to hide banding.
+ */
+pub struct OutputTransform {
+    pub exposure: f32,
+    pub white_balance: Option<f32>,
+    pub tone_map: ToneMap,
+    pub dither: bool,
+}
+
+impl OutputTransform {
+    pub fn apply(&self, pixels: &[Vec3]) -> Vec<[u8; 3]> {
+        let balance = self.white_balance.map(white_balance);
+        let scale = 2.0f32.powf(self.exposure);
+        pixels.iter().enumerate().map(|(i, p)| {
+            let c = match &balance {
+                Some(m) => apply3(m, *p),
+                None => *p,
+            };
+            let c = self.tone_map.apply(c * scale);
+            let mut out = [0; 3];
+            for (channel, v) in out.iter_mut().enumerate() {
+                let encoded = srgb_oetf(c[channel].clamp(0.0, 1.0)) * 255.0;
+                let noise = if self.dither { triangle_noise(i as u64 * 3 + channel as u64) } else { 0.0 };
+                *v = (encoded + noise).round().clamp(0.0, 255.0) as u8;
+            }
+            out
+        }).collect()
+    }
+}
+
+/* Deterministic noise in (-1, 1) with a triangular distribution, from a hash of the index */
+fn triangle_noise(index: u64) -> f32 {
+    let h = mix64(index.wrapping_add(0x9e3779b97f4a7c15));
+    let u0 = (h >> 40) as f32 / (1u64 << 24) as f32;
+    let u1 = ((h >> 16) & 0xff_ffff) as f32 / (1u64 << 24) as f32;
+    u0 - u1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_transform() {
+        assert!((srgb_oetf(0.5) - 0.7354).abs() < 1e-3);
+        for tone_map in [ToneMap::Reinhard, ToneMap::Filmic, ToneMap::Aces] {
+            let bright = tone_map.apply(Vec3::one() * 100.0);
+            assert!(bright.x > 0.8, "{:?} {:?}", tone_map, bright);
+            assert!(tone_map.apply(Vec3::one() * 0.1).x < tone_map.apply(Vec3::one() * 0.2).x);
+        }
+
+        /* Daylight balance barely changes anything, D65 being just off the Planckian locus */
+        let m = white_balance(6504.0);
+        let grey = apply3(&m, Vec3::one() * 0.5);
+        assert!((grey - Vec3::one() * 0.5).len() < 5e-2);
+        let warm = apply3(&XYZ_TO_RGB, xy_to_xyz(planckian_xy(3000.0)));
+        let neutral = apply3(&white_balance(3000.0), warm);
+        assert!((neutral.x - neutral.z).abs() < 1e-2 * neutral.y);
+
+        let transform = OutputTransform { exposure: 1.0, white_balance: None, tone_map: ToneMap::Clamp, dither: false };
+        assert_eq!(transform.apply(&[Vec3::new(0.25, 2.0, -1.0)]), vec![[188, 255, 0]]);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::Vec3;
+use crate::Ray;
+
+type Matrix = [[f32; 4]; 3];
+
+const IDENTITY: Matrix = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+];
+
+/* 3x4 matrix product, the implicit last row being 0 0 0 1 */
+fn mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut m = [[0.0; 4]; 3];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+        row[3] += a[i][3];
+    }
+    m
+}
+
+/* Unit quaternion, used where rotations need to be interpolated */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quat {
+    pub w: f32,
+    pub v: Vec3,
+}
+
+impl Default for Quat {
+    fn default() -> Quat {
+        Quat { w: 1.0, v: Vec3::zero() }
+    }
+}
+
+impl Quat {
+    /* Rotation by angle radians counterclockwise around axis */
+    pub fn from_axis_angle(axis: Vec3, angle: f32) -> Quat {
+        let (s, c) = (angle / 2.0).sin_cos();
+        Quat { w: c, v: axis.normalized() * s }
+    }
+
+    fn dot(&self, other: &Quat) -> f32 {
+        self.w * other.w + self.v.dot(other.v)
+    }
+
+    /* Angle of the rotation taking self to other, in radians */
+    pub fn angle_to(&self, other: &Quat) -> f32 {
+        2.0 * self.dot(other).abs().min(1.0).acos()
+    }
+
+    /* Spherical linear interpolation along the shorter arc */
+    pub fn slerp(&self, other: &Quat, t: f32) -> Quat {
+        let mut cos = self.dot(other);
+        let mut end = *other;
+        if cos < 0.0 {
+            cos = -cos;
+            end = Quat { w: -end.w, v: -end.v };
+        }
+
+        /* Nearly parallel, a normalized lerp avoids dividing by sin(theta) ~ 0 */
+        let (a, b) = if cos > 0.9995 {
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin_theta = theta.sin();
+            (((1.0 - t) * theta).sin() / sin_theta, (t * theta).sin() / sin_theta)
+        };
+        let w = self.w * a + end.w * b;
+        let v = self.v * a + end.v * b;
+        let len = (w * w + v.len2()).sqrt();
+        Quat { w: w / len, v: v / len }
+    }
+}
+
+/* Affine transform, kept along with its inverse */
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    m: Matrix,
+    inv: Matrix,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform { m: IDENTITY, inv: IDENTITY }
+    }
+}
+
+impl Transform {
+    pub fn translate(t: Vec3) -> Transform {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        for i in 0..3 {
+            m[i][3] = t[i];
+            inv[i][3] = -t[i];
+        }
+        Transform { m, inv }
+    }
+
+    /* Scale factors must not be zero */
+    pub fn scale(s: Vec3) -> Transform {
+        let mut m = IDENTITY;
+        let mut inv = IDENTITY;
+        for i in 0..3 {
+            m[i][i] = s[i];
+            inv[i][i] = 1.0 / s[i];
+        }
+        Transform { m, inv }
+    }
+
+    /* Rotation by angle radians counterclockwise around axis */
+    pub fn rotate(axis: Vec3, angle: f32) -> Transform {
+        Transform::from_quat(&Quat::from_axis_angle(axis, angle))
+    }
+
+    pub fn from_quat(q: &Quat) -> Transform {
+        let Quat { w, v: Vec3 { x, y, z } } = *q;
+        let mut m = IDENTITY;
+        m[0][0] = 1.0 - 2.0 * (y * y + z * z);
+        m[0][1] = 2.0 * (x * y - w * z);
+        m[0][2] = 2.0 * (x * z + w * y);
+        m[1][0] = 2.0 * (x * y + w * z);
+        m[1][1] = 1.0 - 2.0 * (x * x + z * z);
+        m[1][2] = 2.0 * (y * z - w * x);
+        m[2][0] = 2.0 * (x * z - w * y);
+        m[2][1] = 2.0 * (y * z + w * x);
+        m[2][2] = 1.0 - 2.0 * (x * x + y * y);
+
+        /* The inverse of a rotation is its transpose */
+        let mut inv = IDENTITY;
+        for i in 0..3 {
+            for j in 0..3 {
+                inv[i][j] = m[j][i];
+            }
+        }
+        Transform { m, inv }
+    }
+
+    /* Applies self first and then next */
+    pub fn then(&self, next: &Transform) -> Transform {
+        Transform { m: mul(&next.m, &self.m), inv: mul(&self.inv, &next.inv) }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform { m: self.inv, inv: self.m }
+    }
+
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+                  m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+                  m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3])
+    }
+
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+                  m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+                  m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z)
+    }
+
+    /* The direction is not normalized so parameters along the ray are preserved */
+    pub fn ray(&self, ray: &Ray) -> Ray {
+        Ray::new(self.point(ray.orig), self.vector(ray.dir), ray.time)
+    }
+
+    /* Normals go through the inverse transpose, the result is not normalized */
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        let inv = &self.inv;
+        Vec3::new(inv[0][0] * n.x + inv[1][0] * n.y + inv[2][0] * n.z,
+                  inv[0][1] * n.x + inv[1][1] * n.y + inv[2][1] * n.z,
+                  inv[0][2] * n.x + inv[1][2] * n.y + inv[2][2] * n.z)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).len() < 1e-5
+    }
+
+    #[test]
+    fn test_compose_and_invert() {
+        let t = Transform::scale(Vec3::new(2.0, 2.0, 2.0))
+            .then(&Transform::rotate(Vec3::new(0.0, 1.0, 0.0), std::f32::consts::FRAC_PI_2))
+            .then(&Transform::translate(Vec3::new(1.0, 0.0, 0.0)));
+
+        /* x is scaled, then turned towards -z and moved along x */
+        let p = t.point(Vec3::new(1.0, 0.0, 0.0));
+        assert!(close(p, Vec3::new(1.0, 0.0, -2.0)));
+        assert!(close(t.inverse().point(p), Vec3::new(1.0, 0.0, 0.0)));
+        assert!(close(t.vector(Vec3::new(0.0, 1.0, 0.0)), Vec3::new(0.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn test_slerp() {
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let a = Quat::default();
+        let b = Quat::from_axis_angle(y, 2.0);
+        let half = Transform::from_quat(&a.slerp(&b, 0.5));
+        let p = Vec3::new(1.0, 0.0, 0.0);
+        assert!(close(half.point(p), Transform::rotate(y, 1.0).point(p)));
+        assert!((a.angle_to(&b) - 2.0).abs() < 1e-4);
+
+        /* Normals stay perpendicular to surfaces under non-uniform scaling */
+        let t = Transform::scale(Vec3::new(2.0, 1.0, 1.0));
+        let n = t.normal(Vec3::new(1.0, 1.0, 0.0));
+        assert!(n.dot(t.vector(Vec3::new(1.0, -1.0, 0.0))).abs() < 1e-5);
+    }
+}
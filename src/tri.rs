@@ -11,13 +11,13 @@ pub struct Tri<'a> {
 }
 
 impl Tri<'_> {
-    pub fn new(verts: [Vec3; 3], mat: &dyn Material) -> Tri {
+    pub fn new(verts: [Vec3; 3], mat: &dyn Material) -> Tri<'_> {
         Tri { verts, mat }
     }
 }
 
 impl Hittable for Tri<'_> {
-    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, _rng: &mut RNG) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, t_min: f32, t_max: f32, _rng: &mut RNG) -> Option<HitRecord<'_>> {
         /* Möller–Trumbore intersection */
         let eps: f32 = 0.000001;
         let v0 = self.verts[0];
@@ -37,7 +37,7 @@ impl Hittable for Tri<'_> {
         let s = ray.orig - v0;
         let u = f * s.dot(h);
 
-        if u < 0.0f32 || u > 1.0f32 {
+        if !(0.0f32..=1.0f32).contains(&u) {
             return None;
         }
 
@@ -53,7 +53,7 @@ impl Hittable for Tri<'_> {
         if t > t_min && t < t_max {
             let p = ray.at(t);
             let n = (v1 - v0).cross(v2 - v0).normalized();
-            Some(HitRecord::new(p, n, t, ray, self.mat))
+            Some(HitRecord::new(p, n, t, ray, self.mat, (u, v)))
         } else {
             None
         }
@@ -66,11 +66,11 @@ impl Hittable for Tri<'_> {
 
         fn f32_ord(a: &&f32, b: &&f32) -> std::cmp::Ordering {
             if a == b {
-                return std::cmp::Ordering::Equal;
+                std::cmp::Ordering::Equal
             } else if a > b {
-                return std::cmp::Ordering::Greater;
+                std::cmp::Ordering::Greater
             } else {
-                return std::cmp::Ordering::Less;
+                std::cmp::Ordering::Less
             }
         }
 
@@ -11,7 +11,16 @@ pub struct Vec3 {
 
 impl Vec3 {
     pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
-        Vec3{x: x, y: y, z: z}
+        Vec3{x, y, z}
+    }
+
+    /* Parses comma separated components, e.g. "1,0.5,0.25" */
+    pub fn from_csv(s: &str) -> Option<Vec3> {
+        let fields: Vec<Result<f32, _>> = s.split(',').map(|f| f.trim().parse::<f32>()).collect();
+        match fields.as_slice() {
+            [Ok(x), Ok(y), Ok(z)] => Some(Vec3::new(*x, *y, *z)),
+            _ => None,
+        }
     }
 
     pub fn zero() -> Vec3 {
@@ -66,14 +75,36 @@ impl Vec3 {
         }
     }
 
-    pub fn sqrt(self) -> Self {
+    pub fn max_component(&self) -> f32 {
+        self.x.max(self.y).max(self.z)
+    }
+
+    /* Relative luminance of linear Rec. 709 primaries */
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
+    /* Two unit vectors completing an orthonormal basis with this unit vector (Duff et al. 2017) */
+    pub fn basis(&self) -> (Vec3, Vec3) {
+        let sign = 1.0f32.copysign(self.z);
+        let a = -1.0 / (sign + self.z);
+        let b = self.x * self.y * a;
+        (Vec3::new(1.0 + sign * self.x * self.x * a, sign * b, -sign * self.x),
+         Vec3::new(b, sign + self.y * self.y * a, -self.y))
+    }
+
+    pub fn exp(self) -> Self {
         Vec3 {
-            x: self.x.sqrt(),
-            y: self.y.sqrt(),
-            z: self.z.sqrt(),
+            x: self.x.exp(),
+            y: self.y.exp(),
+            z: self.z.exp(),
         }
     }
 
+    pub fn average(&self) -> f32 {
+        (self.x + self.y + self.z) / 3.0
+    }
+
     pub fn reflect(v: Self, n: Self) -> Self {
         v - n * 2.0 * v.dot(n)
     }
@@ -0,0 +1,441 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use crate::Vec3;
+use crate::Ray;
+use crate::medium::{Medium, MediumSample, HenyeyGreenstein};
+use crate::options::{parse_f32, parse_vec3};
+use crate::rng::RNG;
+use crate::transform::Transform;
+
+#[derive(Debug)]
+pub enum GridError {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for GridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GridError::Io(why) => write!(f, "{}", why),
+            GridError::Format(why) => write!(f, "{}", why),
+        }
+    }
+}
+
+impl From<io::Error> for GridError {
+    fn from(why: io::Error) -> GridError {
+        GridError::Io(why)
+    }
+}
+
+/*
+ * Dense scalar grid of densities covering the unit cube, with samples at
+ * the voxel centers. On disk it is a text header "GRID nx ny nz" on its own
+ * line followed by nx * ny * nz little endian f32, x varying fastest.
+ */
+pub struct DensityGrid {
+    pub nx: usize,
+    pub ny: usize,
+    pub nz: usize,
+    pub values: Vec<f32>,
+}
+
+impl DensityGrid {
+    pub fn load(path: &str) -> Result<DensityGrid, GridError> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        parse_grid(&data)
+    }
+
+    fn get(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[x + self.nx * (y + self.ny * z)]
+    }
+
+    /* Trilinear interpolation at a point of the unit cube */
+    pub fn density(&self, p: Vec3) -> f32 {
+        let dims = [self.nx, self.ny, self.nz];
+        let mut i0 = [0; 3];
+        let mut i1 = [0; 3];
+        let mut f = [0.0; 3];
+        for a in 0..3 {
+            let x = (p[a] * dims[a] as f32 - 0.5).clamp(0.0, (dims[a] - 1) as f32);
+            i0[a] = x as usize;
+            i1[a] = (i0[a] + 1).min(dims[a] - 1);
+            f[a] = x - i0[a] as f32;
+        }
+
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let row = |y: usize, z: usize| lerp(self.get(i0[0], y, z), self.get(i1[0], y, z), f[0]);
+        let plane = |z: usize| lerp(row(i0[1], z), row(i1[1], z), f[1]);
+        lerp(plane(i0[2]), plane(i1[2]), f[2])
+    }
+
+    /* Maximum over the voxels whose interpolation reaches into [lo, hi] */
+    fn max_in(&self, lo: Vec3, hi: Vec3) -> f32 {
+        let dims = [self.nx, self.ny, self.nz];
+        let mut range = [(0, 0); 3];
+        for a in 0..3 {
+            let n = dims[a] as f32;
+            let first = (lo[a] * n - 0.5).floor().clamp(0.0, n - 1.0) as usize;
+            let last = (hi[a] * n - 0.5).ceil().clamp(0.0, n - 1.0) as usize;
+            range[a] = (first, last);
+        }
+
+        let mut max: f32 = 0.0;
+        for z in range[2].0..=range[2].1 {
+            for y in range[1].0..=range[1].1 {
+                for x in range[0].0..=range[0].1 {
+                    max = max.max(self.get(x, y, z));
+                }
+            }
+        }
+        max
+    }
+}
+
+fn parse_grid(data: &[u8]) -> Result<DensityGrid, GridError> {
+    let end = match data.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None => return Err(GridError::Format("Missing grid header".to_string())),
+    };
+    let header = String::from_utf8_lossy(&data[..end]);
+    let fields: Vec<&str> = header.split_whitespace().collect();
+    let dims: Vec<usize> = match fields.as_slice() {
+        ["GRID", rest @ ..] if rest.len() == 3 => {
+            match rest.iter().map(|f| f.parse::<usize>()).collect() {
+                Ok(dims) => dims,
+                Err(_) => return Err(GridError::Format(format!("Invalid grid size: {}", header))),
+            }
+        }
+        _ => return Err(GridError::Format(format!("Invalid grid header: {}", header))),
+    };
+    if dims.contains(&0) {
+        return Err(GridError::Format(format!("Empty grid: {}", header)));
+    }
+
+    let count = match dims[0].checked_mul(dims[1]).and_then(|n| n.checked_mul(dims[2])) {
+        Some(count) if count.checked_mul(4).is_some() => count,
+        _ => return Err(GridError::Format(format!("Grid too large: {}", header))),
+    };
+    let body = &data[end + 1..];
+    if body.len() < count * 4 {
+        return Err(GridError::Format(format!("Expected {} values, got {}", count, body.len() / 4)));
+    }
+    let values = body.chunks_exact(4)
+                     .take(count)
+                     .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]).max(0.0))
+                     .collect();
+
+    Ok(DensityGrid { nx: dims[0], ny: dims[1], nz: dims[2], values })
+}
+
+/* Resolution of the majorant grid along each axis */
+const MAJORANT_RES: usize = 16;
+
+/* Coarse grid of upper bounds on the density, used to take long steps through empty space */
+struct MajorantGrid {
+    values: Vec<f32>,
+}
+
+impl MajorantGrid {
+    fn new(grid: &DensityGrid) -> MajorantGrid {
+        let n = MAJORANT_RES;
+        let mut values = vec![0.0; n * n * n];
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let lo = Vec3::new(x as f32, y as f32, z as f32) / n as f32;
+                    let hi = Vec3::new((x + 1) as f32, (y + 1) as f32, (z + 1) as f32) / n as f32;
+                    values[x + n * (y + n * z)] = grid.max_in(lo, hi);
+                }
+            }
+        }
+        MajorantGrid { values }
+    }
+
+    /*
+     * Walks the cells crossed by the ray between t0 and t1 (Amanatides and
+     * Woo), calling f with each segment and its majorant until it returns
+     * false. The ray is in the unit cube space of the grid.
+     */
+    fn traverse<F: FnMut(f32, f32, f32) -> bool>(&self, ray: &Ray, t0: f32, t1: f32, mut f: F) {
+        let n = MAJORANT_RES as i32;
+        let p0 = ray.at(t0);
+        let mut cell = [0; 3];
+        let mut next_t = [f32::INFINITY; 3];
+        let mut delta_t = [f32::INFINITY; 3];
+        let mut step = [0; 3];
+        for a in 0..3 {
+            cell[a] = ((p0[a] * n as f32) as i32).clamp(0, n - 1);
+            let d = ray.dir[a];
+            if d > 0.0 {
+                next_t[a] = t0 + ((cell[a] + 1) as f32 / n as f32 - p0[a]) / d;
+                delta_t[a] = 1.0 / (n as f32 * d);
+                step[a] = 1;
+            } else if d < 0.0 {
+                next_t[a] = t0 + (cell[a] as f32 / n as f32 - p0[a]) / d;
+                delta_t[a] = -1.0 / (n as f32 * d);
+                step[a] = -1;
+            }
+        }
+
+        let mut t = t0;
+        loop {
+            let axis = if next_t[0] < next_t[1] && next_t[0] < next_t[2] {
+                0
+            } else if next_t[1] < next_t[2] {
+                1
+            } else {
+                2
+            };
+            let t_end = next_t[axis].min(t1);
+            let index = (cell[0] + n * (cell[1] + n * cell[2])) as usize;
+            if !f(t, t_end, self.values[index]) || t_end >= t1 {
+                return;
+            }
+            t = t_end;
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= n {
+                return;
+            }
+            next_t[axis] += delta_t[axis];
+        }
+    }
+}
+
+/*
+ * Heterogeneous medium from a density grid placed in the scene by a
+ * transform of the unit cube. The extinction is gray, density times
+ * sigma_t, so delta tracking is analog and the albedo colors the
+ * scattered light. Woodcock tracking is used for free flights and ratio
+ * tracking for the transmittance of shadow rays.
+ */
+pub struct GridMedium {
+    grid: DensityGrid,
+    majorants: MajorantGrid,
+    /* World to unit cube */
+    to_local: Transform,
+    sigma_t: f32,
+    albedo: Vec3,
+    phase: HenyeyGreenstein,
+}
+
+impl GridMedium {
+    pub fn new(grid: DensityGrid, to_world: Transform, sigma_t: f32, albedo: Vec3,
+               g: f32) -> GridMedium {
+        let majorants = MajorantGrid::new(&grid);
+        GridMedium {
+            grid,
+            majorants,
+            to_local: to_world.inverse(),
+            sigma_t,
+            albedo,
+            phase: HenyeyGreenstein::new(g),
+        }
+    }
+
+    /* Parametric interval of the local ray inside the unit cube, clipped to [0, t_max] */
+    fn span(ray: &Ray, t_max: f32) -> Option<(f32, f32)> {
+        let mut t0: f32 = 0.0;
+        let mut t1 = t_max;
+        for a in 0..3 {
+            let inv = 1.0 / ray.dir[a];
+            let mut near = -ray.orig[a] * inv;
+            let mut far = (1.0 - ray.orig[a]) * inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            /* NaN from rays parallel to a slab through its boundary are ignored by max/min */
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 >= t1 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+}
+
+impl Medium for GridMedium {
+    fn sample(&self, ray: &Ray, t_max: f32, u: f32, rng: &mut RNG) -> Option<MediumSample> {
+        let local = self.to_local.ray(ray);
+        let (t0, t1) = Self::span(&local, t_max)?;
+        /* World distance per unit of the ray parameter, the same in both spaces */
+        let len = ray.dir.len();
+
+        let mut u = u;
+        let mut found = None;
+        self.majorants.traverse(&local, t0, t1, |ta, tb, majorant| {
+            let sigma_bar = majorant * self.sigma_t;
+            if sigma_bar <= 0.0 {
+                return true;
+            }
+            let mut t = ta;
+            loop {
+                t += -(1.0 - u).ln() / (sigma_bar * len);
+                u = rng.sample_01();
+                if t >= tb {
+                    return true;
+                }
+                let sigma = self.grid.density(local.at(t)) * self.sigma_t;
+                let accept = rng.sample_01() * sigma_bar < sigma;
+                if accept {
+                    found = Some(t);
+                    return false;
+                }
+            }
+        });
+
+        found.map(|t| MediumSample { t, weight: self.albedo })
+    }
+
+    fn pass_weight(&self, _ray: &Ray, _t: f32, _rng: &mut RNG) -> Vec3 {
+        Vec3::one()
+    }
+
+    fn transmittance(&self, ray: &Ray, t_max: f32, rng: &mut RNG) -> Vec3 {
+        let local = self.to_local.ray(ray);
+        let (t0, t1) = match Self::span(&local, t_max) {
+            Some(span) => span,
+            None => return Vec3::one(),
+        };
+        let len = ray.dir.len();
+
+        let mut tr = 1.0;
+        self.majorants.traverse(&local, t0, t1, |ta, tb, majorant| {
+            let sigma_bar = majorant * self.sigma_t;
+            if sigma_bar <= 0.0 {
+                return true;
+            }
+            let mut t = ta;
+            loop {
+                t += -(1.0 - rng.sample_01()).ln() / (sigma_bar * len);
+                if t >= tb {
+                    return true;
+                }
+                let sigma = self.grid.density(local.at(t)) * self.sigma_t;
+                tr *= 1.0 - sigma / sigma_bar;
+
+                /* Russian roulette on low transmittance keeps long rays cheap */
+                if tr < 0.1 {
+                    if rng.sample_01() < 0.5 {
+                        tr = 0.0;
+                        return false;
+                    }
+                    tr *= 2.0;
+                }
+            }
+        });
+
+        Vec3::one() * tr
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+/*
+ * Volume description as given on the command line:
+ *   file.grid:sigma_t:albedo_r,g,b:g:x,y,z[:sx,sy,sz[:rotation_y_deg]]
+ * The unit cube of the grid is scaled, rotated around y and then moved
+ * to x,y,z.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeDesc {
+    pub path: String,
+    pub sigma_t: f32,
+    pub albedo: Vec3,
+    pub g: f32,
+    pub transform: Transform,
+}
+
+impl FromStr for VolumeDesc {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(':').collect();
+        match fields.as_slice() {
+            [path, sigma_t, albedo, g, pos, rest @ ..] if rest.len() <= 2 => {
+                let scale = match rest.first() {
+                    Some(scale) => parse_vec3(scale)?,
+                    None => Vec3::one(),
+                };
+                if scale.x == 0.0 || scale.y == 0.0 || scale.z == 0.0 {
+                    return Err(format!("Invalid scale: {}", s));
+                }
+                let rotation = match rest.get(1) {
+                    Some(angle) => parse_f32(angle)?.to_radians(),
+                    None => 0.0,
+                };
+                let transform = Transform::scale(scale)
+                    .then(&Transform::rotate(Vec3::new(0.0, 1.0, 0.0), rotation))
+                    .then(&Transform::translate(parse_vec3(pos)?));
+                Ok(VolumeDesc {
+                    path: path.to_string(),
+                    sigma_t: parse_f32(sigma_t)?,
+                    albedo: parse_vec3(albedo)?,
+                    g: parse_f32(g)?,
+                    transform,
+                })
+            }
+            _ => Err(format!("Invalid volume: {}", s)),
+        }
+    }
+}
+
+impl VolumeDesc {
+    pub fn build(&self) -> Result<GridMedium, String> {
+        match DensityGrid::load(&self.path) {
+            Ok(grid) => Ok(GridMedium::new(grid, self.transform, self.sigma_t, self.albedo, self.g)),
+            Err(why) => Err(format!("Could not load {}: {}", self.path, why)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid_file(nx: usize, ny: usize, nz: usize, values: &[f32]) -> Vec<u8> {
+        let mut data = format!("GRID {} {} {}\n", nx, ny, nz).into_bytes();
+        for v in values {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_grid() {
+        let grid = parse_grid(&grid_file(2, 1, 1, &[1.0, 3.0])).unwrap();
+        assert_eq!((grid.nx, grid.ny, grid.nz), (2, 1, 1));
+        assert_eq!(grid.density(Vec3::new(0.5, 0.5, 0.5)), 2.0);
+        assert_eq!(grid.density(Vec3::new(0.0, 0.5, 0.5)), 1.0);
+        assert!(parse_grid(&grid_file(2, 2, 1, &[1.0, 3.0])).is_err());
+        assert!(parse_grid(b"VOL 1 1 1\n").is_err());
+        let huge = format!("GRID {} 3 2\n", usize::MAX / 2);
+        assert!(matches!(parse_grid(huge.as_bytes()), Err(GridError::Format(_))));
+        assert!(matches!(parse_grid(&grid_file(usize::MAX / 2, 1, 1, &[])), Err(GridError::Format(_))));
+    }
+
+    /* Ratio tracking through a constant grid matches Beer-Lambert */
+    #[test]
+    fn test_grid_transmittance() {
+        let grid = DensityGrid { nx: 4, ny: 4, nz: 4, values: vec![0.5; 64] };
+        let to_world = Transform::scale(Vec3::new(2.0, 2.0, 2.0));
+        let medium = GridMedium::new(grid, to_world, 1.0, Vec3::one(), 0.0);
+
+        /* Crosses the full 2 units of the cube along x */
+        let ray = Ray::new(Vec3::new(-1.0, 1.0, 1.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let mut rng = RNG::with_seed(7);
+        let n = 4000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += medium.transmittance(&ray, 10.0, &mut rng).x;
+        }
+        let expected = (-1.0f32).exp();
+        assert!((sum / n as f32 - expected).abs() < 0.02);
+    }
+}
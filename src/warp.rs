@@ -0,0 +1,55 @@
+use std::f32::consts::PI;
+
+use crate::Vec3;
+
+/* Concentric mapping of the unit square onto the unit disk (Shirley & Chiu) */
+pub fn sample_unit_disk(u: (f32, f32)) -> Vec3 {
+    let ox = 2.0 * u.0 - 1.0;
+    let oy = 2.0 * u.1 - 1.0;
+
+    if ox == 0.0 && oy == 0.0 {
+        return Vec3::zero();
+    }
+
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, (PI / 4.0) * (oy / ox))
+    } else {
+        (oy, (PI / 2.0) - (PI / 4.0) * (ox / oy))
+    };
+
+    Vec3::new(r * theta.cos(), r * theta.sin(), 0.0)
+}
+
+/* Uniformly distributed direction, i.e. a point on the surface of the unit sphere */
+pub fn sample_unit_sphere(u: (f32, f32)) -> Vec3 {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/* Uniformly distributed direction in a cone around +z, given 1 - cos of its half angle */
+pub fn sample_cone(u: (f32, f32), one_minus_cos_max: f32) -> Vec3 {
+    let one_minus_cos = u.0 * one_minus_cos_max;
+    let cos_theta = 1.0 - one_minus_cos;
+    let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn cone_pdf(one_minus_cos_max: f32) -> f32 {
+    1.0 / (2.0 * PI * one_minus_cos_max)
+}
+
+/* Uniformly distributed direction on the hemisphere around +z */
+pub fn sample_hemisphere(u: (f32, f32)) -> Vec3 {
+    let z = u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/* Uniformly distributed point inside the unit sphere */
+pub fn sample_unit_ball(u: (f32, f32), r: f32) -> Vec3 {
+    sample_unit_sphere(u) * r.cbrt()
+}